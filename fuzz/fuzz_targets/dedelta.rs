@@ -0,0 +1,22 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The main crate is a binary, so pull the module in by path
+#[allow(dead_code, unused_imports)]
+#[path = "../../src/dewey/mod.rs"]
+mod dewey;
+
+// The first byte picks where the base ends and the delta begins
+fuzz_target!(|data: &[u8]| {
+    let Some((&split, rest)) = data.split_first() else {
+        return;
+    };
+    let (base, delta) = rest.split_at((split as usize).min(rest.len()));
+    if let Ok(result) = dewey::dedelta_buf(delta, base) {
+        let mut header = delta;
+        dewey::delta_buf_length(&mut header).unwrap();
+        let (_, size) = dewey::delta_buf_length(&mut header).unwrap();
+        assert_eq!(result.len() as u64, size);
+    }
+});
@@ -0,0 +1,45 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The main crate is a binary, so pull the module in by path
+#[allow(dead_code, unused_imports)]
+#[path = "../../src/dewey/mod.rs"]
+mod dewey;
+
+// Whatever decodes must encode back to the bytes it was read from, except
+// for the redundant forms a decoder accepts but an encoder never writes
+fuzz_target!(|data: &[u8]| {
+    if let Ok((n, pack_type, size)) = dewey::pack_item(&mut &data[..]) {
+        let encoded = dewey::encode_pack_item(pack_type, size);
+        if encoded.len() == n {
+            assert_eq!(encoded, data[..n]);
+        }
+        assert_eq!(
+            dewey::pack_item(&mut &encoded[..]).unwrap(),
+            (encoded.len(), pack_type, size)
+        );
+    }
+
+    if let Ok((n, ofs)) = dewey::delta_offset(&mut &data[..]) {
+        assert_eq!(dewey::encode_delta_offset(ofs), data[..n]);
+    }
+
+    if let Ok((_, size)) = dewey::delta_buf_length(&mut &data[..]) {
+        let encoded = dewey::encode_delta_buf_length(size);
+        assert_eq!(
+            dewey::delta_buf_length(&mut &encoded[..]).unwrap(),
+            (encoded.len(), size)
+        );
+    }
+
+    if let Some((&opcode, rest)) = data.split_first() {
+        if opcode & 128 != 0 {
+            if let Ok((ofs, size)) = dewey::delta_copy_length(&mut &rest[..], opcode) {
+                let encoded = dewey::encode_delta_copy(ofs, size);
+                let decoded = dewey::delta_copy_length(&mut &encoded[1..], encoded[0]).unwrap();
+                assert_eq!(decoded, (ofs, size));
+            }
+        }
+    }
+});
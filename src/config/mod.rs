@@ -0,0 +1,199 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::object::invalid;
+
+/// Settings from a git config file, as `section.subsection.name` keys.
+/// Section and variable names are case insensitive and stored lowercased;
+/// subsections keep their case.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    entries: Vec<(String, String)>,
+}
+
+impl Config {
+    /// The settings in effect for a repository: `/etc/gitconfig`, then the
+    /// user's global config, then the repository's own `config`, with later
+    /// files overriding earlier ones. Missing files are skipped.
+    pub fn open(git_dir: impl AsRef<Path>) -> io::Result<Self> {
+        let mut config = Config::default();
+        if env::var_os("GIT_CONFIG_NOSYSTEM").is_none() {
+            config.read_file("/etc/gitconfig")?;
+        }
+        for path in global_paths() {
+            config.read_file(path)?;
+        }
+        config.read_file(git_dir.as_ref().join("config"))?;
+        Ok(config)
+    }
+
+    // Adds the settings in `path` after, and so taking precedence over, the
+    // ones already read
+    fn read_file(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        self.parse(&content)
+            .map_err(|e| invalid(format!("{}: {e}", path.as_ref().display())))
+    }
+
+    fn parse(&mut self, content: &str) -> io::Result<()> {
+        let mut section = None;
+        let mut lines = content.lines().enumerate();
+        while let Some((n, line)) = lines.next() {
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            let bad_line = || invalid(format!("bad config line {}", n + 1));
+            if let Some(header) = line.strip_prefix('[') {
+                let end = header.find(']').ok_or_else(bad_line)?;
+                section = Some(parse_section(&header[..end]).ok_or_else(bad_line)?);
+                continue;
+            }
+            let section = section.as_ref().ok_or_else(bad_line)?;
+
+            let (name, value) = match line.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value)),
+                None => (strip_comment(line).trim(), None),
+            };
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                return Err(bad_line());
+            }
+            let value = match value {
+                // A bare name is a boolean that is set
+                None => "true".to_string(),
+                Some(value) => {
+                    let mut raw = value.to_string();
+                    // A trailing backslash continues the value on the next line
+                    while raw.ends_with('\\') && !raw.ends_with("\\\\") {
+                        raw.pop();
+                        let (_, next) = lines.next().ok_or_else(bad_line)?;
+                        raw.push_str(next);
+                    }
+                    parse_value(&raw).ok_or_else(bad_line)?
+                }
+            };
+            self.entries
+                .push((format!("{section}.{}", name.to_ascii_lowercase()), value));
+        }
+        Ok(())
+    }
+
+    /// The last value set for `key`, like `git config --get`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let key = normalize_key(key);
+        self.entries
+            .iter()
+            .rev()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+// `$GIT_CONFIG_GLOBAL` if set, else the XDG config and then `~/.gitconfig`
+fn global_paths() -> Vec<PathBuf> {
+    if let Some(path) = env::var_os("GIT_CONFIG_GLOBAL") {
+        return vec![PathBuf::from(path)];
+    }
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let xdg = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home.as_ref().map(|home| home.join(".config")));
+    [
+        xdg.map(|xdg| xdg.join("git/config")),
+        home.map(|home| home.join(".gitconfig")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+// `[section "subsection"]`, or the older `[section.subsection]`
+fn parse_section(header: &str) -> Option<String> {
+    let header = header.trim();
+    match header.split_once(char::is_whitespace) {
+        Some((name, subsection)) => {
+            let quoted = subsection.trim().strip_prefix('"')?.strip_suffix('"')?;
+            let mut sub = String::new();
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => sub.push(chars.next()?),
+                    '"' => return None,
+                    c => sub.push(c),
+                }
+            }
+            Some(format!("{}.{sub}", name.to_ascii_lowercase()))
+        }
+        None => Some(match header.split_once('.') {
+            Some((name, sub)) => {
+                format!("{}.{}", name.to_ascii_lowercase(), sub.to_ascii_lowercase())
+            }
+            None => header.to_ascii_lowercase(),
+        }),
+    }
+}
+
+// Unquotes and unescapes a value, dropping any trailing comment
+fn parse_value(raw: &str) -> Option<String> {
+    let mut value = String::new();
+    let mut quoted = false;
+    // Whitespace is only kept once something follows it
+    let mut pending_space = String::new();
+    let mut chars = raw.trim_start().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' | ';' if !quoted => break,
+            '\\' => {
+                value.push_str(&pending_space);
+                pending_space.clear();
+                value.push(match chars.next()? {
+                    'n' => '\n',
+                    't' => '\t',
+                    'b' => '\u{8}',
+                    c @ ('\\' | '"') => c,
+                    _ => return None,
+                });
+                continue;
+            }
+            c if c.is_whitespace() && !quoted => {
+                pending_space.push(c);
+                continue;
+            }
+            c => {
+                value.push_str(&pending_space);
+                pending_space.clear();
+                value.push(c);
+            }
+        }
+    }
+    (!quoted).then_some(value)
+}
+
+fn strip_comment(line: &str) -> &str {
+    line.split(['#', ';']).next().unwrap_or_default()
+}
+
+// Lowercases the section and variable name but not the subsection
+fn normalize_key(key: &str) -> String {
+    let (section, rest) = key.split_once('.').unwrap_or((key, ""));
+    match rest.rsplit_once('.') {
+        Some((sub, name)) => format!(
+            "{}.{sub}.{}",
+            section.to_ascii_lowercase(),
+            name.to_ascii_lowercase()
+        ),
+        None => format!(
+            "{}.{}",
+            section.to_ascii_lowercase(),
+            rest.to_ascii_lowercase()
+        ),
+    }
+}
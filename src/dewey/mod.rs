@@ -1,88 +1,219 @@
-// Return type is (n bytes, pack type, size of item)
-pub fn pack_item(pack_data: &mut <Vec<u8> as IntoIterator>::IntoIter) -> (u64, u8, u128) {
-    let mut n = 1;
-    let mut size = 0;
-
-    // Init: len MSB + 3bit Type + 4bit Size
-    let init_byte = pack_data.next().unwrap();
-    let init_type = (init_byte >> 4) & 7;
-    let init_size = init_byte & 15;
-    size += init_size as u128;
-    let mut msb = (init_byte & 128) >> 7 == 1;
-    while msb {
-        let byte = pack_data.by_ref().next().unwrap() as u128;
-        let v_size = (byte & 127) << (4 + (n - 1) * 7);
-        size += v_size;
-        msb = (byte & 128) >> 7 == 1;
-        n += 1;
+mod varlen;
+
+pub use varlen::delta_buf_length;
+pub use varlen::delta_copy_length;
+pub use varlen::delta_offset;
+pub use varlen::encode_delta_buf_length;
+pub use varlen::encode_delta_copy;
+pub use varlen::encode_delta_offset;
+pub use varlen::encode_pack_item;
+pub use varlen::pack_item;
+pub use varlen::VarintError;
+
+// Why a delta could not be applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeltaError {
+    // The delta ends in the middle of a header or instruction
+    Truncated,
+    // A size in the header does not fit in a usize
+    SizeOverflow,
+    BaseSizeMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    CopyOutOfRange {
+        offset: usize,
+        size: usize,
+        base_len: usize,
+    },
+    // Opcode 0 is reserved by git and never written
+    ReservedOpcode,
+    ResultSizeMismatch {
+        expected: usize,
+        actual: usize,
+    },
+}
+
+impl std::fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeltaError::Truncated => write!(f, "delta is truncated"),
+            DeltaError::SizeOverflow => write!(f, "delta declares an impossibly large size"),
+            DeltaError::BaseSizeMismatch { expected, actual } => write!(
+                f,
+                "delta expects a base of {expected} bytes but the base has {actual}"
+            ),
+            DeltaError::CopyOutOfRange {
+                offset,
+                size,
+                base_len,
+            } => write!(
+                f,
+                "delta copies {size} bytes from offset {offset} of a {base_len} byte base"
+            ),
+            DeltaError::ReservedOpcode => write!(f, "delta uses reserved opcode 0"),
+            DeltaError::ResultSizeMismatch { expected, actual } => write!(
+                f,
+                "delta should produce {expected} bytes but produces {actual}"
+            ),
+        }
     }
-    return (n, init_type, size);
 }
 
-pub fn delta_offset(pack_data: &mut <Vec<u8> as IntoIterator>::IntoIter) -> (u64, u128) {
-    let mut c = pack_data.next().unwrap() as u128;
-    let mut n = 1;
-    let mut ofs = c & 127;
-    while c & 128 != 0 {
-        ofs += 1;
-        n += 1;
-        c = pack_data.by_ref().next().unwrap() as u128;
-        ofs = (ofs << 7) + (c & 127);
-    };
-
-    (n, ofs)
+impl std::error::Error for DeltaError {}
+
+impl From<VarintError> for DeltaError {
+    fn from(e: VarintError) -> Self {
+        match e {
+            VarintError::Overflow => DeltaError::SizeOverflow,
+            // Reading from a slice can only run out
+            VarintError::Truncated | VarintError::Io(_) => DeltaError::Truncated,
+        }
+    }
 }
-// Return is number of bytes read and value of size
-pub fn delta_buf_length(buf : &mut <Vec<u8> as IntoIterator>::IntoIter) -> (u128, u128) {
-    
-    let mut n = 0;
-    let mut msb = true;
-    let mut size: u128 = 0;
-    while msb  {
-        let byte = buf.next().unwrap();
-        msb = byte & 128 == 128;
-        size = ((byte as u128 & 127) << (7*n) ) | size;
-        n += 1;
-    };
-    (n, size)
+
+fn to_usize(size: u64) -> Result<usize, DeltaError> {
+    usize::try_from(size).map_err(|_| DeltaError::SizeOverflow)
 }
 
-pub fn delta_copy_length(buf : &mut <Vec<u8> as IntoIterator>::IntoIter, byte: u8) -> (u128, u128) {
-    // Offset
-    let mut ofs: u128 = 0;
-    let mut size: u128 = 0;
-    if byte & 0x01 == 0x01 {
-        let ofs1 = buf.next().unwrap() as u128;
-        ofs = ofs1;
+/// Rebuilds an object from `delta_buf` and the `base_buf` it was made against.
+pub fn dedelta_buf(delta_buf: &[u8], base_buf: &[u8]) -> Result<Vec<u8>, DeltaError> {
+    let mut delta_stream = delta_buf;
+
+    let base_size = to_usize(delta_buf_length(&mut delta_stream)?.1)?;
+    if base_size != base_buf.len() {
+        return Err(DeltaError::BaseSizeMismatch {
+            expected: base_size,
+            actual: base_buf.len(),
+        });
     }
-    if byte & 0x02 == 0x02{
-        let ofs2 = buf.next().unwrap() as u128;
-        ofs = (ofs2 << 8) | ofs;
+    let result_size = to_usize(delta_buf_length(&mut delta_stream)?.1)?;
+    // Don't trust the declared size with an allocation of that size
+    let mut new_buf = Vec::with_capacity(result_size.min(delta_buf.len() * 128));
+
+    while let Some((&instruction, rest)) = delta_stream.split_first() {
+        delta_stream = rest;
+        let bytes = match instruction {
+            0 => return Err(DeltaError::ReservedOpcode),
+            // Insert
+            1..=127 => {
+                let n_bytes = instruction as usize;
+                if delta_stream.len() < n_bytes {
+                    return Err(DeltaError::Truncated);
+                }
+                let (bytes, rest) = delta_stream.split_at(n_bytes);
+                delta_stream = rest;
+                bytes
+            }
+            // Copy
+            128..=255 => {
+                let (src_ofs, n_copy) = delta_copy_length(&mut delta_stream, instruction)?;
+                let (src_ofs, n_copy) = (src_ofs as usize, n_copy as usize);
+                src_ofs
+                    .checked_add(n_copy)
+                    .and_then(|end| base_buf.get(src_ofs..end))
+                    .ok_or(DeltaError::CopyOutOfRange {
+                        offset: src_ofs,
+                        size: n_copy,
+                        base_len: base_buf.len(),
+                    })?
+            }
+        };
+        if new_buf.len() + bytes.len() > result_size {
+            return Err(DeltaError::ResultSizeMismatch {
+                expected: result_size,
+                actual: new_buf.len() + bytes.len(),
+            });
+        }
+        new_buf.extend_from_slice(bytes);
     }
-    if byte & 0x04 == 0x04{
-        let ofs3 = buf.next().unwrap() as u128;
-        ofs = (ofs3 << 16) | ofs;
+
+    if new_buf.len() != result_size {
+        return Err(DeltaError::ResultSizeMismatch {
+            expected: result_size,
+            actual: new_buf.len(),
+        });
     }
-    if byte & 0x08 == 0x08{
-        let ofs4 = buf.next().unwrap() as u128;
-        ofs = (ofs4 << 24) | ofs;
+    Ok(new_buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &[u8] = b"abcd";
+
+    // A delta header for a base of `base_len` bytes and a result of
+    // `result_len`, followed by `ops`
+    fn delta(base_len: u64, result_len: u64, ops: &[u8]) -> Vec<u8> {
+        let mut delta = encode_delta_buf_length(base_len);
+        delta.extend(encode_delta_buf_length(result_len));
+        delta.extend_from_slice(ops);
+        delta
     }
 
+    #[test]
+    fn applies_copies_and_inserts() {
+        let mut ops = encode_delta_copy(1, 2);
+        ops.extend_from_slice(b"\x02xy");
+        assert_eq!(dedelta_buf(&delta(4, 4, &ops), BASE).unwrap(), b"bcxy");
+    }
 
-    if byte & 0x10 == 0x10{
-        let s1 = buf.next().unwrap() as u128;
-        size = s1;
+    #[test]
+    fn rejects_a_base_of_the_wrong_size() {
+        assert_eq!(
+            dedelta_buf(&delta(5, 0, &[]), BASE),
+            Err(DeltaError::BaseSizeMismatch {
+                expected: 5,
+                actual: 4
+            })
+        );
     }
-    if byte & 0x20 == 0x20{
-        let s2 = buf.next().unwrap() as u128;
-        size = (s2 << 8) | size;
+
+    #[test]
+    fn rejects_copies_past_the_end_of_the_base() {
+        assert_eq!(
+            dedelta_buf(&delta(4, 4, &encode_delta_copy(2, 4)), BASE),
+            Err(DeltaError::CopyOutOfRange {
+                offset: 2,
+                size: 4,
+                base_len: 4
+            })
+        );
     }
-    if byte & 0x40 == 0x40{
-        let s3 = buf.next().unwrap() as u128;
-        size = (s3 << 16) | size;
+
+    #[test]
+    fn rejects_the_reserved_opcode() {
+        assert_eq!(
+            dedelta_buf(&delta(4, 1, &[0, b'a']), BASE),
+            Err(DeltaError::ReservedOpcode)
+        );
     }
 
-    
+    #[test]
+    fn rejects_results_of_the_wrong_size() {
+        assert_eq!(
+            dedelta_buf(&delta(4, 10, b"\x03abc"), BASE),
+            Err(DeltaError::ResultSizeMismatch {
+                expected: 10,
+                actual: 3
+            })
+        );
+        // Stops as soon as the result would grow too large
+        assert_eq!(
+            dedelta_buf(&delta(4, 2, b"\x03abc"), BASE),
+            Err(DeltaError::ResultSizeMismatch {
+                expected: 2,
+                actual: 3
+            })
+        );
+    }
 
-    (ofs, size)
+    #[test]
+    fn rejects_a_truncated_insert() {
+        assert_eq!(
+            dedelta_buf(&delta(4, 5, b"\x05ab"), BASE),
+            Err(DeltaError::Truncated)
+        );
+    }
 }
@@ -1,3 +1,318 @@
-pub fn hello() {
-    println!("HELLO");
+// The variable length numbers used by packs and deltas. Each format has a
+// decoder over any `Read`, byte slices included, and the encoder that undoes
+// it. Decoders return the number of bytes they consumed alongside the value.
+use std::io;
+use std::io::Read;
+
+#[derive(Debug)]
+pub enum VarintError {
+    // The input ends before the number does
+    Truncated,
+    // The number does not fit in 64 bits
+    Overflow,
+    Io(io::Error),
+}
+
+impl std::fmt::Display for VarintError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VarintError::Truncated => write!(f, "input ends in the middle of a number"),
+            VarintError::Overflow => write!(f, "number does not fit in 64 bits"),
+            VarintError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for VarintError {}
+
+impl From<VarintError> for io::Error {
+    fn from(e: VarintError) -> Self {
+        match e {
+            VarintError::Io(e) => e,
+            VarintError::Truncated => io::Error::new(io::ErrorKind::UnexpectedEof, e.to_string()),
+            VarintError::Overflow => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+fn next_byte(reader: &mut impl Read) -> Result<u8, VarintError> {
+    let mut byte = [0];
+    match reader.read_exact(&mut byte) {
+        Ok(()) => Ok(byte[0]),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(VarintError::Truncated),
+        Err(e) => Err(VarintError::Io(e)),
+    }
+}
+
+// Puts `bits` above the `shift` bits already in `value`, unless some would be lost
+fn place(value: u64, bits: u64, shift: u32) -> Result<u64, VarintError> {
+    if bits == 0 {
+        return Ok(value);
+    }
+    if shift >= u64::BITS || (bits << shift) >> shift != bits {
+        return Err(VarintError::Overflow);
+    }
+    Ok(value | bits << shift)
+}
+
+// Return type is (n bytes, pack type, size of item)
+//
+// An entry header in a pack: MSB continuation, 3 bits of type and the low 4
+// bits of the size, then 7 more bits of size per byte, least significant first.
+pub fn pack_item(reader: &mut impl Read) -> Result<(usize, u8, u64), VarintError> {
+    let init_byte = next_byte(reader)?;
+    let init_type = (init_byte >> 4) & 7;
+    let mut size = (init_byte & 15) as u64;
+    let mut n = 1;
+    let mut msb = init_byte & 128 != 0;
+    while msb {
+        let byte = next_byte(reader)?;
+        size = place(size, (byte & 127) as u64, 4 + 7 * (n as u32 - 1))?;
+        msb = byte & 128 != 0;
+        n += 1;
+    }
+    Ok((n, init_type, size))
+}
+
+pub fn encode_pack_item(pack_type: u8, size: u64) -> Vec<u8> {
+    let mut out = vec![(pack_type << 4) | (size & 15) as u8];
+    let mut size = size >> 4;
+    while size != 0 {
+        *out.last_mut().unwrap() |= 128;
+        out.push((size & 127) as u8);
+        size >>= 7;
+    }
+    out
+}
+
+// Return type is (n bytes, distance back to the base)
+//
+// An OFS_DELTA base: 7 bits per byte, most significant first, with one added
+// before each shift so that every value has a single encoding.
+pub fn delta_offset(reader: &mut impl Read) -> Result<(usize, u64), VarintError> {
+    let mut c = next_byte(reader)?;
+    let mut n = 1;
+    let mut ofs = (c & 127) as u64;
+    while c & 128 != 0 {
+        c = next_byte(reader)?;
+        n += 1;
+        ofs = ofs
+            .checked_add(1)
+            .and_then(|ofs| ofs.checked_mul(128))
+            .ok_or(VarintError::Overflow)?
+            | (c & 127) as u64;
+    }
+    Ok((n, ofs))
+}
+
+pub fn encode_delta_offset(ofs: u64) -> Vec<u8> {
+    let mut ofs = ofs;
+    let mut out = vec![(ofs & 127) as u8];
+    ofs >>= 7;
+    while ofs != 0 {
+        ofs -= 1;
+        out.push(128 | (ofs & 127) as u8);
+        ofs >>= 7;
+    }
+    out.reverse();
+    out
+}
+
+// Return is number of bytes read and value of size
+//
+// The base and result sizes at the start of a delta: 7 bits per byte, least
+// significant first.
+pub fn delta_buf_length(reader: &mut impl Read) -> Result<(usize, u64), VarintError> {
+    let mut n = 0;
+    let mut size = 0;
+    loop {
+        let byte = next_byte(reader)?;
+        size = place(size, (byte & 127) as u64, 7 * n as u32)?;
+        n += 1;
+        if byte & 128 == 0 {
+            return Ok((n, size));
+        }
+    }
+}
+
+pub fn encode_delta_buf_length(size: u64) -> Vec<u8> {
+    let mut size = size;
+    let mut out = vec![];
+    loop {
+        let byte = (size & 127) as u8;
+        size >>= 7;
+        if size == 0 {
+            out.push(byte);
+            return out;
+        }
+        out.push(byte | 128);
+    }
+}
+
+// Return is offset and size of a copy instruction
+//
+// `byte` is the opcode, whose low 4 bits say which offset bytes follow and
+// the next 3 which size bytes, least significant first. A size of zero can't
+// be written, so it stands for 0x10000.
+pub fn delta_copy_length(reader: &mut impl Read, byte: u8) -> Result<(u64, u64), VarintError> {
+    let mut ofs = 0;
+    for n in 0..4 {
+        if byte & (1 << n) != 0 {
+            ofs |= (next_byte(reader)? as u64) << (8 * n);
+        }
+    }
+    let mut size = 0;
+    for n in 0..3 {
+        if byte & (0x10 << n) != 0 {
+            size |= (next_byte(reader)? as u64) << (8 * n);
+        }
+    }
+    if size == 0 {
+        size = 0x10000;
+    }
+    Ok((ofs, size))
+}
+
+// The largest copy a single instruction can describe
+const MAX_COPY_SIZE: u64 = 0xff_ffff;
+
+// Opcode and operands of a copy. Panics unless `ofs` fits in 32 bits and
+// `size` is between 1 and `MAX_COPY_SIZE`.
+pub fn encode_delta_copy(ofs: u64, size: u64) -> Vec<u8> {
+    assert!(ofs <= u32::MAX as u64, "copy offset {ofs} is too large");
+    assert!(
+        (1..=MAX_COPY_SIZE).contains(&size),
+        "copy size {size} is out of range"
+    );
+    let mut out = vec![128];
+    for (n, byte) in (ofs as u32).to_le_bytes().into_iter().enumerate() {
+        if byte != 0 {
+            out[0] |= 1 << n;
+            out.push(byte);
+        }
+    }
+    // Written as zero like git does, which saves a byte
+    if size != 0x10000 {
+        for (n, byte) in (size as u32).to_le_bytes().into_iter().take(3).enumerate() {
+            if byte != 0 {
+                out[0] |= 0x10 << n;
+                out.push(byte);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZES: [u64; 9] = [0, 1, 15, 16, 127, 128, 0x10000, u32::MAX as u64, u64::MAX];
+
+    #[test]
+    fn pack_item_round_trips() {
+        for pack_type in 1..=7 {
+            for size in SIZES {
+                let bytes = encode_pack_item(pack_type, size);
+                let decoded = pack_item(&mut &bytes[..]).unwrap();
+                assert_eq!(decoded, (bytes.len(), pack_type, size));
+            }
+        }
+        // A blob of 10 bytes, and one of 100 that needs a second byte
+        assert_eq!(encode_pack_item(3, 10), [0x3a]);
+        assert_eq!(encode_pack_item(3, 100), [0xb4, 0x06]);
+    }
+
+    #[test]
+    fn delta_offset_round_trips() {
+        for ofs in SIZES.into_iter().chain([16511, 16512]) {
+            let bytes = encode_delta_offset(ofs);
+            assert_eq!(delta_offset(&mut &bytes[..]).unwrap(), (bytes.len(), ofs));
+        }
+        // Every length of encoding starts where the shorter ones end
+        assert_eq!(encode_delta_offset(127), [0x7f]);
+        assert_eq!(encode_delta_offset(128), [0x80, 0x00]);
+        assert_eq!(encode_delta_offset(16511), [0xff, 0x7f]);
+        assert_eq!(encode_delta_offset(16512), [0x80, 0x80, 0x00]);
+    }
+
+    #[test]
+    fn delta_buf_length_round_trips() {
+        for size in SIZES {
+            let bytes = encode_delta_buf_length(size);
+            assert_eq!(
+                delta_buf_length(&mut &bytes[..]).unwrap(),
+                (bytes.len(), size)
+            );
+        }
+        assert_eq!(encode_delta_buf_length(300), [0xac, 0x02]);
+    }
+
+    #[test]
+    fn delta_copy_round_trips() {
+        for (ofs, size) in [
+            (0, 1),
+            (0, 0x10000),
+            (0x1234, 0x56),
+            (u32::MAX as u64, MAX_COPY_SIZE),
+            (0x0100_0000, 0x01_0001),
+        ] {
+            let bytes = encode_delta_copy(ofs, size);
+            let mut rest = &bytes[1..];
+            assert_eq!(delta_copy_length(&mut rest, bytes[0]).unwrap(), (ofs, size));
+            assert!(rest.is_empty());
+        }
+        // Zero bytes are left out, and a size of 0x10000 altogether
+        assert_eq!(encode_delta_copy(0, 0x10000), [0x80]);
+        assert_eq!(encode_delta_copy(0x1234, 0x56), [0x93, 0x34, 0x12, 0x56]);
+    }
+
+    #[test]
+    fn decoders_stop_at_the_end_of_the_number() {
+        let mut input: &[u8] = &[0xac, 0x02, 0xff];
+        assert_eq!(delta_buf_length(&mut input).unwrap(), (2, 300));
+        assert_eq!(input, [0xff]);
+    }
+
+    #[test]
+    fn decoders_report_truncated_input() {
+        assert!(matches!(
+            pack_item(&mut &[][..]),
+            Err(VarintError::Truncated)
+        ));
+        assert!(matches!(
+            pack_item(&mut &[0xb4][..]),
+            Err(VarintError::Truncated)
+        ));
+        assert!(matches!(
+            delta_offset(&mut &[0x80][..]),
+            Err(VarintError::Truncated)
+        ));
+        assert!(matches!(
+            delta_buf_length(&mut &[0xff][..]),
+            Err(VarintError::Truncated)
+        ));
+        assert!(matches!(
+            delta_copy_length(&mut &[0x12][..], 0x93),
+            Err(VarintError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn decoders_report_overflow() {
+        let mut too_long = vec![0xff; 10];
+        too_long.push(0x7f);
+        assert!(matches!(
+            pack_item(&mut &too_long[..]),
+            Err(VarintError::Overflow)
+        ));
+        assert!(matches!(
+            delta_offset(&mut &too_long[..]),
+            Err(VarintError::Overflow)
+        ));
+        assert!(matches!(
+            delta_buf_length(&mut &too_long[..]),
+            Err(VarintError::Overflow)
+        ));
+    }
 }
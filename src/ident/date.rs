@@ -0,0 +1,164 @@
+// Dates as people and git write them: raw `<seconds> <offset>` stamps, ISO
+// 8601, and relative forms like `yesterday` or `2.weeks.ago`.
+
+use chrono::NaiveDate;
+use chrono::NaiveTime;
+
+const MINUTE: i64 = 60;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
+
+/// Parses an exact date into seconds since the epoch and an offset from UTC
+/// in minutes. Dates without an offset are in the local time zone.
+pub fn parse_date(s: &str) -> Option<(i64, i32)> {
+    let s = s.trim();
+    // `<seconds> <offset>` as stored in objects, optionally marked with `@`
+    let raw = s.strip_prefix('@').unwrap_or(s);
+    if let Some((time, offset)) = raw.split_once(' ') {
+        if let (Ok(time), Some(offset)) = (time.parse(), parse_offset(offset)) {
+            return Some((time, offset));
+        }
+    }
+    if let Some(raw) = s.strip_prefix('@') {
+        return Some((raw.parse().ok()?, 0));
+    }
+    parse_iso(s)
+}
+
+/// Parses a date that may also be relative to `now`, such as `now`,
+/// `yesterday`, `3.days.ago` or `2 weeks ago`.
+pub fn approxidate(s: &str, now: i64) -> Option<i64> {
+    let lower = s.trim().to_ascii_lowercase();
+    match lower.as_str() {
+        "now" => return Some(now),
+        "yesterday" => return now.checked_sub(DAY),
+        _ => {}
+    }
+    let words: Vec<&str> = lower.split(['.', ' ']).filter(|w| !w.is_empty()).collect();
+    if let [count, unit, "ago"] = words[..] {
+        let unit = unit.strip_suffix('s').unwrap_or(unit);
+        let seconds = match unit {
+            "second" => 1,
+            "minute" => MINUTE,
+            "hour" => HOUR,
+            "day" => DAY,
+            "week" => 7 * DAY,
+            "month" => 30 * DAY,
+            "year" => 365 * DAY,
+            _ => return None,
+        };
+        // Absurd counts are no date at all rather than an overflow
+        let ago = count.parse::<i64>().ok()?.checked_mul(seconds)?;
+        return now.checked_sub(ago);
+    }
+    parse_date(s).map(|(time, _)| time)
+}
+
+/// `+0100` style offsets, in minutes east of UTC.
+pub fn parse_offset(s: &str) -> Option<i32> {
+    let (sign, digits) = match s.as_bytes().first()? {
+        b'+' => (1, &s[1..]),
+        b'-' => (-1, &s[1..]),
+        _ => return None,
+    };
+    let digits = digits.replace(':', "");
+    if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i32 = digits[..2].parse().ok()?;
+    let minutes: i32 = digits[2..].parse().ok()?;
+    Some(sign * (hours * 60 + minutes))
+}
+
+pub fn format_offset(offset: i32) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    let offset = offset.abs();
+    format!("{sign}{:02}{:02}", offset / 60, offset % 60)
+}
+
+// `YYYY-MM-DD`, optionally followed by `[T ]HH:MM[:SS]` and an offset or `Z`
+fn parse_iso(s: &str) -> Option<(i64, i32)> {
+    let (date, rest) = s.split_at(s.find(['T', ' ']).unwrap_or(s.len()));
+    let mut ymd = date.split('-');
+    let year: i32 = ymd.next()?.parse().ok()?;
+    let month: u32 = ymd.next()?.parse().ok()?;
+    let day: u32 = ymd.next()?.parse().ok()?;
+    if ymd.next().is_some() {
+        return None;
+    }
+    let date = NaiveDate::from_ymd_opt(year, month, day)?;
+
+    let rest = rest.get(1..).unwrap_or_default().trim();
+    let (clock, zone) = match rest.find(['+', '-', 'Z', ' ']) {
+        Some(at) => (&rest[..at], rest[at..].trim()),
+        None => (rest, ""),
+    };
+    let mut seconds = 0;
+    if !clock.is_empty() {
+        let mut hms = clock.split(':');
+        let hours: i64 = hms.next()?.parse().ok()?;
+        let minutes: i64 = hms.next()?.parse().ok()?;
+        let secs: i64 = hms.next().map_or(Some(0), |s| s.parse().ok())?;
+        if hms.next().is_some() || hours > 23 || minutes > 59 || secs > 60 {
+            return None;
+        }
+        seconds = hours * HOUR + minutes * MINUTE + secs;
+    }
+    let wall_clock = date.and_time(NaiveTime::MIN).and_utc().timestamp() + seconds;
+    let offset = match zone {
+        "" => super::local_offset(wall_clock),
+        "Z" => 0,
+        zone => parse_offset(zone)?,
+    };
+    Some((wall_clock - offset as i64 * MINUTE, offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_raw_stamps() {
+        assert_eq!(parse_date("1112911993 +0100"), Some((1112911993, 60)));
+        assert_eq!(parse_date("@1112911993 -0530"), Some((1112911993, -330)));
+        assert_eq!(parse_date("@1112911993"), Some((1112911993, 0)));
+        assert_eq!(parse_date("1112911993 0100"), None);
+    }
+
+    #[test]
+    fn parses_iso_dates() {
+        assert_eq!(
+            parse_date("2005-04-07T22:13:13+0200"),
+            Some((1112904793, 120))
+        );
+        assert_eq!(
+            parse_date("2005-04-07 22:13:13 +02:00"),
+            Some((1112904793, 120))
+        );
+        assert_eq!(parse_date("2005-04-07 20:13Z"), Some((1112904780, 0)));
+        assert_eq!(parse_date("1969-12-31T23:00:00Z"), Some((-3600, 0)));
+        assert_eq!(parse_date("2000-02-29T00:00:00Z"), Some((951782400, 0)));
+        for bad in ["2001-02-29", "2005-13-01", "2005-04-07T24:00", "2005-04"] {
+            assert_eq!(parse_date(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn parses_relative_dates() {
+        let now = 1_700_000_000;
+        assert_eq!(approxidate("now", now), Some(now));
+        assert_eq!(approxidate("Yesterday", now), Some(now - DAY));
+        assert_eq!(approxidate("3.days.ago", now), Some(now - 3 * DAY));
+        assert_eq!(approxidate("2 weeks ago", now), Some(now - 14 * DAY));
+        assert_eq!(approxidate("1.hour.ago", now), Some(now - HOUR));
+        assert_eq!(approxidate("5.fortnights.ago", now), None);
+        assert_eq!(approxidate("@1112911993 +0100", now), Some(1112911993));
+    }
+
+    #[test]
+    fn huge_relative_dates_are_rejected() {
+        assert_eq!(approxidate("99999999999999999.years.ago", 0), None);
+        assert_eq!(approxidate("9223372036854775807.seconds.ago", -10), None);
+        assert_eq!(approxidate("yesterday", i64::MIN), None);
+    }
+}
@@ -0,0 +1,208 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::time::SystemTime;
+
+use chrono::TimeZone;
+
+use crate::config::Config;
+use crate::object::invalid;
+
+mod date;
+
+pub use date::approxidate;
+
+/// Who did something and when, as commits, tags and reflogs record it:
+/// `Name <email> <seconds since the epoch> <offset>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    pub time: i64,
+    // Minutes east of UTC
+    pub offset: i32,
+}
+
+impl Signature {
+    pub fn parse(s: &str) -> io::Result<Self> {
+        let bad = || invalid(format!("bad signature: {s}"));
+        let (name, rest) = s.split_once('<').ok_or_else(bad)?;
+        let (email, rest) = rest.split_once('>').ok_or_else(bad)?;
+        let (time, offset) = rest.trim().split_once(' ').ok_or_else(bad)?;
+        Ok(Signature {
+            name: name.trim_end().to_string(),
+            email: email.to_string(),
+            time: time.parse().map_err(|_| bad())?,
+            offset: date::parse_offset(offset).ok_or_else(bad)?,
+        })
+    }
+
+    /// The author of a commit made now, per `GIT_AUTHOR_*`, then `author.*`
+    /// and `user.*` settings.
+    pub fn author(config: &Config) -> io::Result<Self> {
+        Signature::for_role(config, "author")
+    }
+
+    /// The committer of whatever happens now, per `GIT_COMMITTER_*`, then
+    /// `committer.*` and `user.*` settings.
+    pub fn committer(config: &Config) -> io::Result<Self> {
+        Signature::for_role(config, "committer")
+    }
+
+    // Name and email from `GIT_<ROLE>_NAME` and `GIT_<ROLE>_EMAIL`, then
+    // `<role>.name` and `<role>.email`, then `user.name` and `user.email`,
+    // then the login and host names. The time is `GIT_<ROLE>_DATE` or now.
+    fn for_role(config: &Config, role: &str) -> io::Result<Self> {
+        let upper = role.to_ascii_uppercase();
+        let setting = |field: &str| {
+            env::var(format!("GIT_{upper}_{}", field.to_ascii_uppercase()))
+                .ok()
+                .or_else(|| config.get(&format!("{role}.{field}")).map(str::to_string))
+                .or_else(|| config.get(&format!("user.{field}")).map(str::to_string))
+        };
+        let mut signature = Signature {
+            name: setting("name").unwrap_or_else(login_name),
+            email: setting("email").unwrap_or_else(|| format!("{}@{}", login_name(), host_name())),
+            time: now(),
+            offset: 0,
+        };
+        signature.offset = local_offset(signature.time);
+        if let Ok(date) = env::var(format!("GIT_{upper}_DATE")) {
+            signature.set_date(&date)?;
+        }
+        Ok(signature)
+    }
+
+    /// Takes name and email from `Name <email>`, as `--author` gives them.
+    pub fn set_identity(&mut self, identity: &str) -> io::Result<()> {
+        let bad = || invalid(format!("bad identity, expected 'Name <email>': {identity}"));
+        let (name, rest) = identity.split_once('<').ok_or_else(bad)?;
+        let email = rest.strip_suffix('>').ok_or_else(bad)?;
+        if name.trim().is_empty() || email.contains(['<', '>']) {
+            return Err(bad());
+        }
+        self.name = name.trim().to_string();
+        self.email = email.to_string();
+        Ok(())
+    }
+
+    /// Sets the time from an exact date, or one relative to now such as
+    /// `yesterday`, as `--date` gives it.
+    pub fn set_date(&mut self, date: &str) -> io::Result<()> {
+        let (time, offset) = date::parse_date(date)
+            .or_else(|| approxidate(date, now()).map(|time| (time, local_offset(time))))
+            .ok_or_else(|| invalid(format!("invalid date format: {date}")))?;
+        self.time = time;
+        self.offset = offset;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} <{}> {} {}",
+            self.name,
+            self.email,
+            self.time,
+            date::format_offset(self.offset)
+        )
+    }
+}
+
+/// Minutes east of UTC of the local time zone at `time`.
+pub fn local_offset(time: i64) -> i32 {
+    chrono::Local
+        .timestamp_opt(time, 0)
+        .single()
+        .map_or(0, |local| local.offset().local_minus_utc() / 60)
+}
+
+/// Seconds since the epoch.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64)
+}
+
+fn login_name() -> String {
+    env::var("USER")
+        .or_else(|_| env::var("LOGNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+fn host_name() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn environment_then_role_then_user_settings() {
+        let dir = TempDir::new();
+        fs::write(
+            dir.path().join("config"),
+            "[user]\n\tname = User\n\temail = user@example.com\n\
+             [author]\n\tname = Author\n",
+        )
+        .unwrap();
+        let config = Config::open(dir.path()).unwrap();
+        // Nothing else reads the author variables, so changing them here
+        // cannot upset tests running alongside
+        for var in ["GIT_AUTHOR_NAME", "GIT_AUTHOR_EMAIL", "GIT_AUTHOR_DATE"] {
+            env::remove_var(var);
+        }
+
+        let author = Signature::author(&config).unwrap();
+        assert_eq!(author.name, "Author");
+        assert_eq!(author.email, "user@example.com");
+
+        env::set_var("GIT_AUTHOR_NAME", "Env Author");
+        env::set_var("GIT_AUTHOR_DATE", "1112911993 +0100");
+        let author = Signature::author(&config);
+        env::remove_var("GIT_AUTHOR_NAME");
+        env::remove_var("GIT_AUTHOR_DATE");
+        let author = author.unwrap();
+        assert_eq!(author.name, "Env Author");
+        assert_eq!(author.email, "user@example.com");
+        assert_eq!((author.time, author.offset), (1112911993, 60));
+    }
+
+    #[test]
+    fn parses_author_options() {
+        let mut signature = Signature::parse("A <a@b> 0 +0000").unwrap();
+        signature
+            .set_identity("  A U Thor <author@example.com>")
+            .unwrap();
+        assert_eq!(signature.name, "A U Thor");
+        assert_eq!(signature.email, "author@example.com");
+        assert_eq!(
+            signature.to_string(),
+            "A U Thor <author@example.com> 0 +0000"
+        );
+        for bad in ["A U Thor", "<a@b>", "A <a@b", "A <a<b>"] {
+            assert!(signature.set_identity(bad).is_err(), "{bad}");
+        }
+        assert_eq!(signature.name, "A U Thor");
+    }
+
+    #[test]
+    fn sets_dates_like_date_options() {
+        let mut signature = Signature::parse("A <a@b> 0 +0000").unwrap();
+        signature.set_date("2005-04-07T22:13:13+0200").unwrap();
+        assert_eq!((signature.time, signature.offset), (1112904793, 120));
+        let before = now();
+        signature.set_date("2.days.ago").unwrap();
+        assert!((before - 2 * 86400..=now() - 2 * 86400).contains(&signature.time));
+        assert!(signature.set_date("someday").is_err());
+    }
+}
@@ -0,0 +1,206 @@
+mod wildmatch;
+
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::config::Config;
+use wildmatch::wildmatch;
+
+/// The untracked paths git leaves alone, as listed by `.gitignore` files in
+/// the work tree, `info/exclude` and the file named by `core.excludesFile`.
+pub struct Ignore {
+    work_tree: PathBuf,
+    // core.excludesFile and then info/exclude, so the latter wins
+    global: Vec<Pattern>,
+    // Each directory's .gitignore, read the first time it is needed
+    per_dir: HashMap<Vec<u8>, Vec<Pattern>>,
+}
+
+impl Ignore {
+    pub fn new(git_dir: &Path, work_tree: &Path) -> io::Result<Self> {
+        let config = Config::open(git_dir)?;
+        let excludes_file = match config.get("core.excludesfile") {
+            Some(path) => match path.strip_prefix("~/") {
+                Some(rest) => env::var_os("HOME").map(|home| Path::new(&home).join(rest)),
+                None => Some(PathBuf::from(path)),
+            },
+            None => env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+                .map(|xdg| xdg.join("git/ignore")),
+        };
+        let mut global = match excludes_file {
+            Some(path) => read_patterns(&path)?,
+            None => vec![],
+        };
+        global.extend(read_patterns(&git_dir.join("info/exclude"))?);
+        Ok(Ignore {
+            work_tree: work_tree.to_path_buf(),
+            global,
+            per_dir: HashMap::new(),
+        })
+    }
+
+    /// Whether `path`, relative to the top of the work tree, is ignored.
+    pub fn is_ignored(&mut self, path: &[u8], is_dir: bool) -> io::Result<bool> {
+        Ok(self.excluded_part(path, is_dir)?.is_some())
+    }
+
+    // Whether the patterns exclude `path` itself, whatever the directories
+    // it is in
+    fn is_excluded(&mut self, path: &[u8], is_dir: bool) -> io::Result<bool> {
+        let mut dirs: Vec<usize> = path
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| **b == b'/')
+            .map(|(at, _)| at)
+            .collect();
+        dirs.insert(0, 0);
+        // A deeper .gitignore overrides the ones above it
+        for &end in dirs.iter().rev() {
+            let dir = &path[..end];
+            let rel = if end == 0 { path } else { &path[end + 1..] };
+            let found = self
+                .patterns_in(dir)?
+                .iter()
+                .rev()
+                .find(|pattern| pattern.matches(rel, is_dir))
+                .map(|pattern| !pattern.negated);
+            if let Some(ignored) = found {
+                return Ok(ignored);
+            }
+        }
+        Ok(self
+            .global
+            .iter()
+            .rev()
+            .find(|pattern| pattern.matches(path, is_dir))
+            .is_some_and(|pattern| !pattern.negated))
+    }
+
+    /// The part of `path` that is ignored: `path` itself, or the outermost
+    /// excluded directory it is in, since nothing in one can be re-included.
+    pub fn excluded_part<'a>(
+        &mut self,
+        path: &'a [u8],
+        is_dir: bool,
+    ) -> io::Result<Option<&'a [u8]>> {
+        for (at, _) in path.iter().enumerate().filter(|(_, b)| **b == b'/') {
+            if self.is_excluded(&path[..at], true)? {
+                return Ok(Some(&path[..at]));
+            }
+        }
+        Ok(self.is_excluded(path, is_dir)?.then_some(path))
+    }
+
+    fn patterns_in(&mut self, dir: &[u8]) -> io::Result<&[Pattern]> {
+        if !self.per_dir.contains_key(dir) {
+            let file = self
+                .work_tree
+                .join(OsStr::from_bytes(dir))
+                .join(".gitignore");
+            self.per_dir.insert(dir.to_vec(), read_patterns(&file)?);
+        }
+        Ok(&self.per_dir[dir])
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    glob: Vec<u8>,
+    // `!pattern`, which re-includes what an earlier pattern excluded
+    negated: bool,
+    // `pattern/`, which only matches directories
+    dir_only: bool,
+    // A pattern with a `/` before its end matches from the directory of the
+    // file it is in; any other matches a name at any depth
+    anchored: bool,
+}
+
+impl Pattern {
+    fn parse(line: &[u8]) -> Option<Pattern> {
+        let mut line = line.strip_suffix(b"\r").unwrap_or(line);
+        // Trailing spaces are dropped unless escaped with a backslash
+        while let [rest @ .., last, b' '] = line {
+            if *last == b'\\' {
+                break;
+            }
+            line = &line[..rest.len() + 1];
+        }
+        if line == b" " {
+            line = b"";
+        }
+        if line.is_empty() || line[0] == b'#' {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix(b"!") {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix(b"/") {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains(&b'/');
+        let glob = line.strip_prefix(b"/").unwrap_or(line).to_vec();
+        if glob.is_empty() {
+            return None;
+        }
+        Some(Pattern {
+            glob,
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    // `path` is relative to the directory the pattern came from
+    fn matches(&self, path: &[u8], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            // Like git, the part before the first wildcard is compared as is
+            // and only the rest globbed, so a `**` straight after it counts
+            // as starting a path component
+            let literal = self
+                .glob
+                .iter()
+                .position(|b| b"*?[\\".contains(b))
+                .unwrap_or(self.glob.len());
+            return path
+                .strip_prefix(&self.glob[..literal])
+                .is_some_and(|rest| wildmatch(&self.glob[literal..], rest));
+        }
+        let name = match path.iter().rposition(|b| *b == b'/') {
+            Some(slash) => &path[slash + 1..],
+            None => path,
+        };
+        wildmatch(&self.glob, name)
+    }
+}
+
+// The patterns in an ignore file, or none if it does not exist
+fn read_patterns(path: &Path) -> io::Result<Vec<Pattern>> {
+    match fs::read(path) {
+        Ok(content) => Ok(content
+            .split(|b| *b == b'\n')
+            .filter_map(Pattern::parse)
+            .collect()),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+            ) =>
+        {
+            Ok(vec![])
+        }
+        Err(e) => Err(e),
+    }
+}
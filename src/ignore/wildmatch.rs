@@ -0,0 +1,212 @@
+// Git's glob matching for paths: `*` and `?` never match a `/`, `**` between
+// slashes matches any number of directories, `[...]` matches a set of bytes
+// and a backslash makes the next byte literal.
+
+pub fn wildmatch(pattern: &[u8], text: &[u8]) -> bool {
+    dowild(pattern, 0, text) == Outcome::Matched
+}
+
+// Like git, a failed match says how far back it is worth retrying, so that a
+// pattern with many stars takes time in proportion to its length times the
+// text's rather than exponential time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Matched,
+    NoMatch,
+    // The text ran out, so no earlier star taking more of it can help
+    AbortAll,
+    // A `*` reached a `/`, which only an earlier `**` can get past
+    AbortToDoubleStar,
+}
+
+// Matches `text` against the pattern from byte `p` on. Taking the whole
+// pattern lets a `**` see whether it starts a path component.
+fn dowild(pattern: &[u8], p: usize, text: &[u8]) -> Outcome {
+    let Some(&first) = pattern.get(p) else {
+        return if text.is_empty() {
+            Outcome::Matched
+        } else {
+            Outcome::NoMatch
+        };
+    };
+    if text.is_empty() && first != b'*' {
+        return Outcome::AbortAll;
+    }
+    match first {
+        b'*' => {
+            let mut rest = p + 1;
+            while pattern.get(rest) == Some(&b'*') {
+                rest += 1;
+            }
+            // `**` is only special as a whole path component; anywhere else
+            // it is just a `*`
+            let double = rest - p > 1
+                && (p == 0 || pattern[p - 1] == b'/')
+                && matches!(pattern.get(rest), None | Some(b'/'));
+            if double {
+                match pattern.get(rest) {
+                    // A trailing `**` matches everything left
+                    None => return Outcome::Matched,
+                    // `**/` matches no directories, or any number of them.
+                    // If what follows cannot match before the text runs
+                    // out, skipping directories will not help either.
+                    _ => match dowild(pattern, rest + 1, text) {
+                        result @ (Outcome::Matched | Outcome::AbortAll) => return result,
+                        Outcome::NoMatch | Outcome::AbortToDoubleStar => {}
+                    },
+                }
+            }
+            star(pattern, rest, text, double)
+        }
+        b'?' => match text[0] {
+            b'/' => Outcome::NoMatch,
+            _ => dowild(pattern, p + 1, &text[1..]),
+        },
+        b'[' => match class(&pattern[p + 1..]) {
+            Some((negated, set, after)) => {
+                let b = text[0];
+                if b != b'/' && in_set(set, b) != negated {
+                    dowild(pattern, pattern.len() - after.len(), &text[1..])
+                } else {
+                    Outcome::NoMatch
+                }
+            }
+            // An unterminated class is matched literally
+            None => literal(pattern, p, b'[', text),
+        },
+        b'\\' if p + 1 < pattern.len() => literal(pattern, p + 1, pattern[p + 1], text),
+        _ => literal(pattern, p, first, text),
+    }
+}
+
+// The pattern byte at `p` is `b`, to be matched as is
+fn literal(pattern: &[u8], p: usize, b: u8, text: &[u8]) -> Outcome {
+    if text[0] == b {
+        dowild(pattern, p + 1, &text[1..])
+    } else {
+        Outcome::NoMatch
+    }
+}
+
+// A `*`, or with `match_slash` a `**/`, followed by the pattern from `rest`:
+// any run of bytes, up to the next `/` unless it may cross them
+fn star(pattern: &[u8], rest: usize, text: &[u8], match_slash: bool) -> Outcome {
+    for at in 0..=text.len() {
+        match dowild(pattern, rest, &text[at..]) {
+            Outcome::NoMatch => {}
+            Outcome::AbortToDoubleStar if match_slash => {}
+            result => return result,
+        }
+        if !match_slash && text.get(at) == Some(&b'/') {
+            return Outcome::AbortToDoubleStar;
+        }
+    }
+    Outcome::AbortAll
+}
+
+// Splits what follows a `[` into whether the class is negated, the set it
+// lists and the pattern after the closing `]`
+fn class(pattern: &[u8]) -> Option<(bool, &[u8], &[u8])> {
+    let (negated, body) = match pattern.first() {
+        Some(b'!' | b'^') => (true, &pattern[1..]),
+        _ => (false, pattern),
+    };
+    // A `]` straight after the `[` is part of the set
+    let mut at = usize::from(body.first() == Some(&b']'));
+    while at < body.len() {
+        match body[at] {
+            b'\\' => at += 2,
+            b']' => return Some((negated, &body[..at], &body[at + 1..])),
+            _ => at += 1,
+        }
+    }
+    None
+}
+
+// Whether `b` is one of the bytes or `a-z` ranges in `set`
+fn in_set(set: &[u8], b: u8) -> bool {
+    let mut at = 0;
+    while at < set.len() {
+        if set[at] == b'\\' && at + 1 < set.len() {
+            at += 1;
+        }
+        let low = set[at];
+        if set.get(at + 1) == Some(&b'-') && at + 2 < set.len() {
+            if (low..=set[at + 2]).contains(&b) {
+                return true;
+            }
+            at += 3;
+        } else if low == b {
+            return true;
+        } else {
+            at += 1;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::wildmatch;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        wildmatch(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn star_stays_within_a_directory() {
+        assert!(matches("*.o", "main.o"));
+        assert!(matches("a*c", "abbc"));
+        assert!(!matches("*.o", "src/main.o"));
+        assert!(matches("src/*.o", "src/main.o"));
+    }
+
+    #[test]
+    fn double_star_crosses_directories() {
+        assert!(matches("**/foo", "foo"));
+        assert!(matches("**/foo", "a/b/foo"));
+        assert!(matches("a/**/b", "a/b"));
+        assert!(matches("a/**/b", "a/x/y/b"));
+        assert!(matches("abc/**", "abc/x/y"));
+        assert!(!matches("a/**/b", "ab"));
+    }
+
+    #[test]
+    fn question_mark_and_classes() {
+        assert!(matches("?.txt", "a.txt"));
+        assert!(!matches("?", "/"));
+        assert!(matches("[abc].c", "b.c"));
+        assert!(!matches("[!abc].c", "b.c"));
+        assert!(matches("[a-z]1", "q1"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[", "["));
+    }
+
+    #[test]
+    fn backslash_escapes() {
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+        assert!(matches("\\#x", "#x"));
+    }
+
+    #[test]
+    fn double_star_only_counts_as_a_whole_component() {
+        assert!(matches("a**/b", "ax/b"));
+        assert!(!matches("a**/b", "ax/y/b"));
+        assert!(matches("x/**y/z", "x/qy/z"));
+        assert!(!matches("x/**y/z", "x/q/y/z"));
+        assert!(matches("**", "a/b"));
+        assert!(matches("a/***/b", "a/x/y/b"));
+    }
+
+    #[test]
+    fn many_stars_do_not_backtrack_exponentially() {
+        // Each of these takes many lifetimes with naive backtracking
+        let text = "a".repeat(100);
+        assert!(!matches(&format!("{}b", "*a".repeat(30)), &text));
+        assert!(matches(&"*a".repeat(30), &text));
+        let dirs = "a/".repeat(50);
+        assert!(!matches(&format!("{}b", "**/a/".repeat(20)), &dirs));
+        assert!(!matches(&format!("{}/b", "*/".repeat(20)), &dirs));
+    }
+}
@@ -0,0 +1,75 @@
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+
+use crate::object::ObjectId;
+
+/// What the filesystem said about a file when it was staged, so a later
+/// look can tell it is unchanged without reading it. Git keeps only the low
+/// 32 bits of each field.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stat {
+    pub ctime: u32,
+    pub ctime_nsec: u32,
+    pub mtime: u32,
+    pub mtime_nsec: u32,
+    pub dev: u32,
+    pub ino: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+}
+
+impl Stat {
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        Stat {
+            ctime: metadata.ctime() as u32,
+            ctime_nsec: metadata.ctime_nsec() as u32,
+            mtime: metadata.mtime() as u32,
+            mtime_nsec: metadata.mtime_nsec() as u32,
+            dev: metadata.dev() as u32,
+            ino: metadata.ino() as u32,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size() as u32,
+        }
+    }
+}
+
+/// One staged path. Paths are relative to the top of the working tree,
+/// separated by `/`, and appear once per stage: 0 normally, or 1 to 3 for
+/// the base, ours and theirs of a conflict.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub stat: Stat,
+    // 0o100644, 0o100755, 0o120000 for symlinks or 0o160000 for submodules
+    pub mode: u32,
+    pub oid: ObjectId,
+    pub stage: u8,
+    // Set by `update-index --assume-unchanged`: trust the entry over the file
+    pub assume_valid: bool,
+    // Outside a sparse checkout, so not expected in the working tree
+    pub skip_worktree: bool,
+    // Set by `add -N`: the path is tracked but its content is not staged yet
+    pub intent_to_add: bool,
+    pub path: Vec<u8>,
+}
+
+impl IndexEntry {
+    pub fn new(path: Vec<u8>, mode: u32, oid: ObjectId, stat: Stat) -> Self {
+        IndexEntry {
+            stat,
+            mode,
+            oid,
+            stage: 0,
+            assume_valid: false,
+            skip_worktree: false,
+            intent_to_add: false,
+            path,
+        }
+    }
+
+    // Only versions 3 and later can store these
+    pub(super) fn has_extended_flags(&self) -> bool {
+        self.skip_worktree || self.intent_to_add
+    }
+}
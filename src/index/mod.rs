@@ -0,0 +1,752 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use sha1::Digest;
+use sha1::Sha1;
+
+use crate::config::Config;
+use crate::dewey;
+use crate::lock::Lock;
+use crate::object::invalid;
+use crate::object::ObjectId;
+use crate::odb::ObjectDatabase;
+
+mod entry;
+mod tree;
+
+pub use entry::IndexEntry;
+pub use entry::Stat;
+pub use tree::CacheTree;
+
+const SIGNATURE: &[u8; 4] = b"DIRC";
+const HEADER_LEN: usize = 12;
+// Stat data, mode, id and flags, before any extended flags and the path
+const ENTRY_FIXED_LEN: usize = 62;
+// Paths this long or longer store 0xfff as their length
+const NAME_MASK: u16 = 0x0fff;
+const ASSUME_VALID: u16 = 0x8000;
+const EXTENDED: u16 = 0x4000;
+const SKIP_WORKTREE: u16 = 0x4000;
+const INTENT_TO_ADD: u16 = 0x2000;
+
+/// The staging area in `.git/index`: what the next commit will hold, one
+/// entry per path and stage, sorted by path then stage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Index {
+    // 2, 3 when entries need extended flags, or 4 to compress paths
+    pub version: u32,
+    entries: Vec<IndexEntry>,
+    pub cache_tree: Option<CacheTree>,
+    // Modification time of the file this was read from, as seconds and
+    // nanoseconds, telling which entries' stat data can be trusted
+    mtime: Option<(u32, u32)>,
+}
+
+impl Index {
+    pub fn new(version: u32) -> Self {
+        Index {
+            version,
+            entries: vec![],
+            cache_tree: None,
+            mtime: None,
+        }
+    }
+
+    /// Reads the index of `git_dir`; a repository without one has nothing
+    /// staged.
+    pub fn open(git_dir: impl AsRef<Path>) -> io::Result<Self> {
+        let git_dir = git_dir.as_ref();
+        let path = git_dir.join("index");
+        match fs::read(&path) {
+            Ok(data) => {
+                let mut index = Index::parse(&data)?;
+                let stat = Stat::from_metadata(&fs::metadata(&path)?);
+                index.mtime = Some((stat.mtime, stat.mtime_nsec));
+                Ok(index)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Index::new(new_version(git_dir))),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Locks the index of `git_dir` and reads it, for changes that must not
+    /// race with anyone else's. The lock goes to `commit`.
+    pub fn lock(git_dir: impl AsRef<Path>) -> io::Result<(Self, Lock)> {
+        let git_dir = git_dir.as_ref();
+        let lock = Lock::acquire(git_dir.join("index"))?;
+        Ok((Index::open(git_dir)?, lock))
+    }
+
+    /// Writes the index in place of the one `lock` was taken on.
+    pub fn commit(&self, lock: Lock) -> io::Result<()> {
+        lock.commit(&self.serialize())
+    }
+
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    pub fn entry(&self, path: &[u8], stage: u8) -> Option<&IndexEntry> {
+        self.position(path, stage).ok().map(|at| &self.entries[at])
+    }
+
+    /// Every stage staged at `path`: none if it is untracked, one normally,
+    /// or several while it is in conflict.
+    pub fn stages(&self, path: &[u8]) -> &[IndexEntry] {
+        let start = self.position(path, 0).unwrap_or_else(|at| at);
+        let len = self.entries[start..]
+            .iter()
+            .take_while(|entry| entry.path == path)
+            .count();
+        &self.entries[start..start + len]
+    }
+
+    /// Whether anything is staged under the directory `dir`.
+    pub fn contains_dir(&self, dir: &[u8]) -> bool {
+        let prefix = [dir, b"/"].concat();
+        let start = self.position(&prefix, 0).unwrap_or_else(|at| at);
+        self.entries
+            .get(start)
+            .is_some_and(|entry| entry.path.starts_with(&prefix))
+    }
+
+    /// Whether a file with `stat` certainly still holds what `entry` staged.
+    /// A file changed in the same instant as the index was written can look
+    /// the same as when it was staged, so such entries are never trusted.
+    pub fn is_fresh(&self, entry: &IndexEntry, stat: &Stat) -> bool {
+        let written_before = self
+            .mtime
+            .is_some_and(|mtime| (entry.stat.mtime, entry.stat.mtime_nsec) < mtime);
+        entry.stat == *stat && written_before
+    }
+
+    /// Stages `entry`, replacing whatever was staged at its path and stage,
+    /// along with any file staged where it needs a directory or anything
+    /// staged under the directory it replaces.
+    pub fn add(&mut self, entry: IndexEntry) {
+        let unchanged = self
+            .entry(&entry.path, entry.stage)
+            .is_some_and(|old| old.oid == entry.oid && old.mode == entry.mode);
+        if !unchanged {
+            self.invalidate(&entry.path);
+        }
+        for (at, _) in entry.path.iter().enumerate().filter(|(_, b)| **b == b'/') {
+            self.remove(&entry.path[..at]);
+        }
+        let dir = [&entry.path[..], b"/"].concat();
+        self.entries.retain(|staged| !staged.path.starts_with(&dir));
+
+        match self.position(&entry.path, entry.stage) {
+            Ok(at) => self.entries[at] = entry,
+            Err(at) => self.entries.insert(at, entry),
+        }
+    }
+
+    /// Unstages every stage of `path`, saying whether anything was staged.
+    pub fn remove(&mut self, path: &[u8]) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.path != path);
+        let removed = self.entries.len() != before;
+        if removed {
+            self.invalidate(path);
+        }
+        removed
+    }
+
+    /// Writes the trees of the staged entries, reusing the cached ones that
+    /// are still valid, and returns the id of the tree of the directory
+    /// `prefix`, or of the root for an empty prefix.
+    pub fn write_tree(
+        &mut self,
+        odb: &dyn ObjectDatabase,
+        prefix: &[u8],
+        missing_ok: bool,
+    ) -> io::Result<ObjectId> {
+        let unmerged: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|entry| entry.stage != 0)
+            .map(|entry| {
+                format!(
+                    "{}: unmerged ({})",
+                    String::from_utf8_lossy(&entry.path),
+                    entry.oid
+                )
+            })
+            .collect();
+        if !unmerged.is_empty() {
+            return Err(invalid(format!(
+                "{}\nerror building trees",
+                unmerged.join("\n")
+            )));
+        }
+
+        let tree = self.cache_tree.get_or_insert_with(CacheTree::default);
+        tree.update(odb, &self.entries, b"", missing_ok)?;
+        let prefix = prefix.strip_suffix(b"/").unwrap_or(prefix);
+        tree.find(prefix).and_then(|tree| tree.oid).ok_or_else(|| {
+            invalid(format!(
+                "prefix {}/ not found",
+                String::from_utf8_lossy(prefix)
+            ))
+        })
+    }
+
+    // The cached trees of the directories holding `path` are out of date
+    fn invalidate(&mut self, path: &[u8]) {
+        if let Some(tree) = &mut self.cache_tree {
+            tree.invalidate(path);
+        }
+    }
+
+    fn position(&self, path: &[u8], stage: u8) -> Result<usize, usize> {
+        self.entries
+            .binary_search_by(|entry| (&entry.path[..], entry.stage).cmp(&(path, stage)))
+    }
+
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        if data.len() < HEADER_LEN + 20 || &data[..4] != SIGNATURE {
+            return Err(invalid("index file is not a dircache"));
+        }
+        let version = read_u32(data, 4);
+        if !(2..=4).contains(&version) {
+            return Err(invalid(format!("unsupported index version {version}")));
+        }
+        let (body, checksum) = data.split_at(data.len() - 20);
+        // index.skipHash leaves the checksum zeroed
+        if checksum.iter().any(|b| *b != 0) && Sha1::digest(body)[..] != *checksum {
+            return Err(invalid("index file checksum does not match"));
+        }
+
+        let count = read_u32(data, 8) as usize;
+        let mut index = Index::new(version);
+        let mut at = HEADER_LEN;
+        for _ in 0..count {
+            let previous = index
+                .entries
+                .last()
+                .map_or(&[][..], |entry| &entry.path[..]);
+            let entry = parse_entry(body, &mut at, version, previous)?;
+            if let Some(last) = index.entries.last() {
+                if (&last.path, last.stage) >= (&entry.path, entry.stage) {
+                    return Err(invalid("index entries are out of order"));
+                }
+            }
+            index.entries.push(entry);
+        }
+
+        while at < body.len() {
+            let header = body
+                .get(at..at + 8)
+                .ok_or_else(|| invalid("index extension is truncated"))?;
+            let signature = &header[..4];
+            let size = read_u32(header, 4) as usize;
+            let content = body
+                .get(at + 8..at + 8 + size)
+                .ok_or_else(|| invalid("index extension is truncated"))?;
+            match signature {
+                b"TREE" => index.cache_tree = Some(CacheTree::parse(content)?),
+                // Extensions named in upper case are caches that are safe
+                // to drop; others change what the entries mean
+                _ if signature[0].is_ascii_uppercase() => {}
+                _ => {
+                    return Err(invalid(format!(
+                        "index uses {} extension, which is not supported",
+                        String::from_utf8_lossy(signature)
+                    )))
+                }
+            }
+            at += 8 + size;
+        }
+        Ok(index)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let extended = self.entries.iter().any(IndexEntry::has_extended_flags);
+        let version: u32 = match self.version {
+            4 => 4,
+            _ if extended => 3,
+            _ => 2,
+        };
+
+        let mut out = SIGNATURE.to_vec();
+        out.extend_from_slice(&version.to_be_bytes());
+        out.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        let mut previous: &[u8] = &[];
+        for entry in &self.entries {
+            write_entry(&mut out, entry, version, previous);
+            previous = &entry.path;
+        }
+
+        if let Some(tree) = &self.cache_tree {
+            let mut content = vec![];
+            tree.serialize(&mut content);
+            out.extend_from_slice(b"TREE");
+            out.extend_from_slice(&(content.len() as u32).to_be_bytes());
+            out.extend_from_slice(&content);
+        }
+
+        let checksum = Sha1::digest(&out);
+        out.extend_from_slice(&checksum);
+        out
+    }
+}
+
+// Version for a repository's first index: GIT_INDEX_VERSION, then
+// index.version, as git picks it
+fn new_version(git_dir: &Path) -> u32 {
+    let valid = |version: u32| (2..=4).contains(&version);
+    if let Some(version) = env::var("GIT_INDEX_VERSION")
+        .ok()
+        .and_then(|version| version.parse().ok())
+        .filter(|version| valid(*version))
+    {
+        return version;
+    }
+    Config::open(git_dir)
+        .ok()
+        .and_then(|config| config.get("index.version")?.parse().ok())
+        .filter(|version| valid(*version))
+        .unwrap_or(2)
+}
+
+fn parse_entry(
+    data: &[u8],
+    at: &mut usize,
+    version: u32,
+    previous: &[u8],
+) -> io::Result<IndexEntry> {
+    let start = *at;
+    let fixed = data
+        .get(start..start + ENTRY_FIXED_LEN)
+        .ok_or_else(|| invalid("index entry is truncated"))?;
+    let field = |n: usize| read_u32(fixed, n * 4);
+    let stat = Stat {
+        ctime: field(0),
+        ctime_nsec: field(1),
+        mtime: field(2),
+        mtime_nsec: field(3),
+        dev: field(4),
+        ino: field(5),
+        uid: field(7),
+        gid: field(8),
+        size: field(9),
+    };
+    let mode = field(6);
+    let oid = ObjectId::from_slice(&fixed[40..60])?;
+    let flags = u16::from_be_bytes([fixed[60], fixed[61]]);
+    *at += ENTRY_FIXED_LEN;
+
+    let mut extended_flags = 0;
+    if flags & EXTENDED != 0 {
+        if version < 3 {
+            return Err(invalid(
+                "index entry has extended flags in a version 2 index",
+            ));
+        }
+        let bytes = data
+            .get(*at..*at + 2)
+            .ok_or_else(|| invalid("index entry is truncated"))?;
+        extended_flags = u16::from_be_bytes([bytes[0], bytes[1]]);
+        *at += 2;
+    }
+
+    let path = if version == 4 {
+        // How much of the previous path to drop, then the rest of this one
+        let mut rest = &data[*at..];
+        let (len, strip) = dewey::delta_offset(&mut rest)?;
+        let strip = usize::try_from(strip)
+            .ok()
+            .filter(|strip| *strip <= previous.len())
+            .ok_or_else(|| invalid("index entry path prefix is too long"))?;
+        *at += len;
+        let suffix = take_path(data, at)?;
+        let mut path = previous[..previous.len() - strip].to_vec();
+        path.extend_from_slice(suffix);
+        path
+    } else {
+        let path = take_path(data, at)?.to_vec();
+        let name_len = (flags & NAME_MASK) as usize;
+        if name_len < NAME_MASK as usize && name_len != path.len() {
+            return Err(invalid("index entry path length does not match"));
+        }
+        // NULs pad each entry to a multiple of eight bytes
+        *at = start + (*at - 1 - start + 8) / 8 * 8;
+        path
+    };
+
+    Ok(IndexEntry {
+        stat,
+        mode,
+        oid,
+        stage: ((flags >> 12) & 3) as u8,
+        assume_valid: flags & ASSUME_VALID != 0,
+        skip_worktree: extended_flags & SKIP_WORKTREE != 0,
+        intent_to_add: extended_flags & INTENT_TO_ADD != 0,
+        path,
+    })
+}
+
+// A NUL-terminated path, moving `at` past the NUL
+fn take_path<'a>(data: &'a [u8], at: &mut usize) -> io::Result<&'a [u8]> {
+    let len = data[*at..]
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or_else(|| invalid("index entry path is not terminated"))?;
+    let path = &data[*at..*at + len];
+    *at += len + 1;
+    Ok(path)
+}
+
+fn write_entry(out: &mut Vec<u8>, entry: &IndexEntry, version: u32, previous: &[u8]) {
+    let start = out.len();
+    let stat = &entry.stat;
+    for field in [
+        stat.ctime,
+        stat.ctime_nsec,
+        stat.mtime,
+        stat.mtime_nsec,
+        stat.dev,
+        stat.ino,
+        entry.mode,
+        stat.uid,
+        stat.gid,
+        stat.size,
+    ] {
+        out.extend_from_slice(&field.to_be_bytes());
+    }
+    out.extend_from_slice(entry.oid.as_bytes());
+
+    let mut flags =
+        entry.path.len().min(NAME_MASK as usize) as u16 | (entry.stage as u16 & 3) << 12;
+    if entry.assume_valid {
+        flags |= ASSUME_VALID;
+    }
+    if entry.has_extended_flags() {
+        flags |= EXTENDED;
+    }
+    out.extend_from_slice(&flags.to_be_bytes());
+    if entry.has_extended_flags() {
+        let mut extended_flags = 0;
+        if entry.skip_worktree {
+            extended_flags |= SKIP_WORKTREE;
+        }
+        if entry.intent_to_add {
+            extended_flags |= INTENT_TO_ADD;
+        }
+        out.extend_from_slice(&extended_flags.to_be_bytes());
+    }
+
+    if version == 4 {
+        let common = previous
+            .iter()
+            .zip(&entry.path)
+            .take_while(|(a, b)| a == b)
+            .count();
+        out.extend_from_slice(&dewey::encode_delta_offset(
+            (previous.len() - common) as u64,
+        ));
+        out.extend_from_slice(&entry.path[common..]);
+        out.push(0);
+    } else {
+        out.extend_from_slice(&entry.path);
+        let padded = start + (out.len() - start + 8) / 8 * 8;
+        out.resize(padded, 0);
+    }
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::ObjectType;
+    use crate::object::RawObject;
+    use crate::odb::LooseObjectDb;
+    use crate::test_util::TempDir;
+
+    const EMPTY_BLOB: &str = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391";
+
+    fn id(hex: &str) -> ObjectId {
+        hex.parse().unwrap()
+    }
+
+    // What `git update-index --index-info` stages for these paths, which
+    // leaves the stat data zeroed
+    fn staged() -> Index {
+        let mut index = Index::new(2);
+        for (path, mode) in [
+            ("src/main.rs", 0o100755),
+            ("README", 0o100644),
+            ("src/lib.rs", 0o100644),
+            ("link", 0o120000),
+            ("src/a/deep.txt", 0o100644),
+        ] {
+            let path = path.as_bytes().to_vec();
+            index.add(IndexEntry::new(path, mode, id(EMPTY_BLOB), Stat::default()));
+        }
+        index
+    }
+
+    // The cache tree `git write-tree` leaves behind for `staged`
+    fn written_tree() -> CacheTree {
+        let deep = CacheTree {
+            oid: Some(id("9593e31a56191d9375527636da7b97e60a1a5f6e")),
+            entry_count: 1,
+            children: vec![],
+        };
+        let src = CacheTree {
+            oid: Some(id("d289d272fd21d136dba227a41c687aef8a4e27ae")),
+            entry_count: 3,
+            children: vec![(b"a".to_vec(), deep)],
+        };
+        CacheTree {
+            oid: Some(id("cce38cb2337ae7cc0de34365af7f824cfd3fcef5")),
+            entry_count: 5,
+            children: vec![(b"src".to_vec(), src)],
+        }
+    }
+
+    // The trailing checksum covers everything before it, so matching git's
+    // means the whole file matches
+    fn checksum(data: &[u8]) -> String {
+        data[data.len() - 20..]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    fn round_trip(index: &Index) -> Vec<u8> {
+        let data = index.serialize();
+        let parsed = Index::parse(&data).unwrap();
+        assert_eq!(parsed.entries, index.entries);
+        assert_eq!(parsed.cache_tree, index.cache_tree);
+        data
+    }
+
+    #[test]
+    fn writes_version_2_like_git() {
+        let mut index = staged();
+        let data = round_trip(&index);
+        assert_eq!(checksum(&data), "db71091d93ac642a93070a2db3ecb14d5e661c88");
+
+        index.cache_tree = Some(written_tree());
+        let data = round_trip(&index);
+        assert_eq!(checksum(&data), "8f889f3bc47ea8bc419c01d9d9fdbe551637bc7a");
+    }
+
+    #[test]
+    fn writes_extended_flags_as_version_3_like_git() {
+        let mut index = staged();
+        index.cache_tree = Some(written_tree());
+        index.entries[3].skip_worktree = true;
+        assert_eq!(index.entries[3].path, b"src/lib.rs");
+        let data = round_trip(&index);
+        assert_eq!(read_u32(&data, 4), 3);
+        assert_eq!(checksum(&data), "68703739982d1d846325ae8a715ab25d894045de");
+
+        index.entries[3].skip_worktree = false;
+        index.entries[0].intent_to_add = true;
+        let parsed = Index::parse(&round_trip(&index)).unwrap();
+        assert!(parsed.entries[0].intent_to_add);
+    }
+
+    #[test]
+    fn writes_version_4_like_git() {
+        let mut index = staged();
+        index.version = 4;
+        index.cache_tree = Some(written_tree());
+        let data = round_trip(&index);
+        assert_eq!(read_u32(&data, 4), 4);
+        assert_eq!(checksum(&data), "1d4a1facc9f062440b216256dabf43d646d5b2ea");
+        assert_eq!(Index::parse(&data).unwrap().version, 4);
+    }
+
+    #[test]
+    fn round_trips_conflicts_stat_data_and_long_paths() {
+        for version in [2, 3, 4] {
+            let mut index = Index::new(version);
+            let stat = Stat {
+                ctime: 1,
+                ctime_nsec: 2,
+                mtime: 3,
+                mtime_nsec: 4,
+                dev: 5,
+                ino: 6,
+                uid: 7,
+                gid: 8,
+                size: 9,
+            };
+            for stage in 1..=3 {
+                let mut entry = IndexEntry::new(b"c.txt".to_vec(), 0o100644, id(EMPTY_BLOB), stat);
+                entry.stage = stage;
+                index.add(entry);
+            }
+            let mut long = IndexEntry::new(vec![b'x'; 5000], 0o100644, id(EMPTY_BLOB), stat);
+            long.assume_valid = true;
+            index.add(long);
+            let mut gitlink = IndexEntry::new(b"sub".to_vec(), 0o160000, id(EMPTY_BLOB), stat);
+            gitlink.skip_worktree = true;
+            index.add(gitlink);
+            index.cache_tree = Some(CacheTree::default());
+            round_trip(&index);
+        }
+    }
+
+    #[test]
+    fn checks_the_checksum() {
+        let mut data = staged().serialize();
+        data[HEADER_LEN] ^= 1;
+        assert!(Index::parse(&data).is_err());
+
+        // Unless index.skipHash zeroed it
+        let mut data = staged().serialize();
+        let len = data.len();
+        data[len - 20..].fill(0);
+        assert_eq!(Index::parse(&data).unwrap().entries, staged().entries);
+    }
+
+    #[test]
+    fn rejects_malformed_indexes() {
+        let data = staged().serialize();
+        assert!(Index::parse(&data[..data.len() - 1]).is_err());
+        assert!(Index::parse(b"DIRC").is_err());
+
+        let mut unsupported = data.clone();
+        unsupported[7] = 5;
+        assert!(Index::parse(&unsupported).is_err());
+
+        // An extension git would need to understand to read the entries
+        let mut with_link = data[..data.len() - 20].to_vec();
+        with_link.extend_from_slice(b"link\0\0\0\0");
+        with_link.extend_from_slice(&Sha1::digest(&with_link));
+        assert!(Index::parse(&with_link).is_err());
+        // Whereas unknown caches are skipped
+        let mut with_cache = data[..data.len() - 20].to_vec();
+        with_cache.extend_from_slice(b"UNTR\0\0\0\x01x");
+        with_cache.extend_from_slice(&Sha1::digest(&with_cache));
+        assert_eq!(Index::parse(&with_cache).unwrap().entries, staged().entries);
+    }
+
+    // An object store holding the empty blob every staged entry points at
+    fn odb(dir: &TempDir) -> LooseObjectDb {
+        let odb = LooseObjectDb::new(dir.path());
+        odb.write(&RawObject::new(ObjectType::Blob, vec![]))
+            .unwrap();
+        odb
+    }
+
+    #[test]
+    fn writes_the_trees_git_writes() {
+        let dir = TempDir::new();
+        let odb = odb(&dir);
+        let mut index = staged();
+        let root = index.write_tree(&odb, b"", false).unwrap();
+        assert_eq!(root, id("cce38cb2337ae7cc0de34365af7f824cfd3fcef5"));
+        assert_eq!(index.cache_tree, Some(written_tree()));
+        assert!(odb.contains(&id("9593e31a56191d9375527636da7b97e60a1a5f6e")));
+
+        // What `git write-tree --prefix` prints for each directory
+        let src = index.write_tree(&odb, b"src/", false).unwrap();
+        assert_eq!(src, id("d289d272fd21d136dba227a41c687aef8a4e27ae"));
+        let deep = index.write_tree(&odb, b"src/a", false).unwrap();
+        assert_eq!(deep, id("9593e31a56191d9375527636da7b97e60a1a5f6e"));
+        let e = index.write_tree(&odb, b"nope/", false).unwrap_err();
+        assert_eq!(e.to_string(), "prefix nope/ not found");
+    }
+
+    #[test]
+    fn refuses_missing_objects_unless_told_otherwise() {
+        let dir = TempDir::new();
+        let odb = LooseObjectDb::new(dir.path());
+        assert!(staged().write_tree(&odb, b"", false).is_err());
+        let root = staged().write_tree(&odb, b"", true).unwrap();
+        assert_eq!(root, id("cce38cb2337ae7cc0de34365af7f824cfd3fcef5"));
+    }
+
+    #[test]
+    fn leaves_out_directories_of_intent_to_add_entries() {
+        let dir = TempDir::new();
+        let odb = odb(&dir);
+        let mut index = staged();
+        for path in ["new/deeper/x", "new/ita.txt", "src/ita"] {
+            let mut entry = IndexEntry::new(
+                path.as_bytes().to_vec(),
+                0o100644,
+                id(EMPTY_BLOB),
+                Stat::default(),
+            );
+            entry.intent_to_add = true;
+            index.add(entry);
+        }
+        // Like `git add -N new src/ita && git write-tree`, the same tree as
+        // without them
+        let root = index.write_tree(&odb, b"", false).unwrap();
+        assert_eq!(root, id("cce38cb2337ae7cc0de34365af7f824cfd3fcef5"));
+        let tree = index.cache_tree.as_ref().unwrap();
+        assert_eq!(tree.entry_count, 8);
+        assert!(tree.find(b"new").is_none());
+        assert_eq!(
+            tree.find(b"src").and_then(|src| src.oid),
+            Some(id("d289d272fd21d136dba227a41c687aef8a4e27ae"))
+        );
+    }
+
+    #[test]
+    fn finds_cached_directories() {
+        let tree = written_tree();
+        assert_eq!(tree.find(b""), Some(&tree));
+        assert_eq!(tree.find(b"src"), Some(&tree.children[0].1));
+        assert_eq!(
+            tree.find(b"src/a").and_then(|deep| deep.oid),
+            Some(id("9593e31a56191d9375527636da7b97e60a1a5f6e"))
+        );
+        assert_eq!(tree.find(b"src/b"), None);
+        assert_eq!(tree.find(b"README"), None);
+    }
+
+    #[test]
+    fn invalidates_only_the_directories_above_a_path() {
+        let mut tree = written_tree();
+        tree.invalidate(b"src/lib.rs");
+        assert_eq!(tree.oid, None);
+        assert_eq!(tree.find(b"src").unwrap().oid, None);
+        assert!(tree.find(b"src/a").unwrap().oid.is_some());
+
+        let mut tree = written_tree();
+        tree.invalidate(b"README");
+        assert_eq!(tree.oid, None);
+        assert!(tree.find(b"src").unwrap().oid.is_some());
+
+        // Writing again hashes only what was invalidated, to the same trees
+        let dir = TempDir::new();
+        let odb = odb(&dir);
+        let mut index = staged();
+        index.cache_tree = Some(written_tree());
+        index.invalidate(b"src/a/deep.txt");
+        let root = index.write_tree(&odb, b"", false).unwrap();
+        assert_eq!(root, id("cce38cb2337ae7cc0de34365af7f824cfd3fcef5"));
+        assert_eq!(index.cache_tree, Some(written_tree()));
+    }
+
+    #[test]
+    fn reuses_valid_cached_trees() {
+        // A cached id for src that is not what its entries hash to shows
+        // it was used as is
+        let dir = TempDir::new();
+        let odb = odb(&dir);
+        let mut index = staged();
+        let mut cached = written_tree();
+        cached.oid = None;
+        cached.children[0].1.oid = Some(id(EMPTY_BLOB));
+        index.cache_tree = Some(cached);
+        index.write_tree(&odb, b"", true).unwrap();
+        let tree = index.cache_tree.as_ref().unwrap();
+        assert_eq!(tree.find(b"src").unwrap().oid, Some(id(EMPTY_BLOB)));
+        assert_ne!(tree.oid, written_tree().oid);
+    }
+}
@@ -0,0 +1,187 @@
+use std::io;
+
+use super::IndexEntry;
+use crate::object::invalid;
+use crate::object::Object;
+use crate::object::ObjectId;
+use crate::object::ObjectType;
+use crate::object::RawObject;
+use crate::object::TreeBuilder;
+use crate::object::TreeEntry;
+use crate::odb::ObjectDatabase;
+
+/// The `TREE` extension: tree ids already computed for directories of the
+/// index, so writing a tree only hashes the directories that changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheTree {
+    // None when something under the directory changed since it was written
+    pub oid: Option<ObjectId>,
+    // Index entries under the directory, counted only while `oid` is set
+    pub entry_count: usize,
+    // Subdirectories by name, kept in the order git reads and writes them
+    pub children: Vec<(Vec<u8>, CacheTree)>,
+}
+
+impl CacheTree {
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        let mut rest = data;
+        let (name, tree) = CacheTree::parse_node(&mut rest)?;
+        if !name.is_empty() || !rest.is_empty() {
+            return Err(invalid("cache tree has trailing data"));
+        }
+        Ok(tree)
+    }
+
+    // `<name> NUL <entry count> SP <subtree count> LF [<id>]`, followed by
+    // the subtrees; an entry count of -1 marks the tree out of date
+    fn parse_node(rest: &mut &[u8]) -> io::Result<(Vec<u8>, CacheTree)> {
+        let name = take_until(rest, 0)?.to_vec();
+        let entry_count: i64 = parse_number(take_until(rest, b' ')?)?;
+        let subtree_count: i64 = parse_number(take_until(rest, b'\n')?)?;
+        let mut tree = CacheTree::default();
+        if entry_count >= 0 {
+            if rest.len() < ObjectId::LEN {
+                return Err(invalid("cache tree is truncated"));
+            }
+            tree.oid = Some(ObjectId::from_slice(&rest[..ObjectId::LEN])?);
+            tree.entry_count = entry_count as usize;
+            *rest = &rest[ObjectId::LEN..];
+        }
+        for _ in 0..subtree_count.max(0) {
+            tree.children.push(CacheTree::parse_node(rest)?);
+        }
+        Ok((name, tree))
+    }
+
+    /// Writes the tree of the directory `prefix` (empty, or ending in `/`)
+    /// whose entries start `entries`, along with any of its subtrees that
+    /// are out of date, and returns how many entries it covers. Entries must
+    /// all be merged.
+    pub fn update(
+        &mut self,
+        odb: &dyn ObjectDatabase,
+        entries: &[IndexEntry],
+        prefix: &[u8],
+        missing_ok: bool,
+    ) -> io::Result<usize> {
+        if self.oid.is_some() {
+            return Ok(self.entry_count);
+        }
+        let empty_tree = RawObject::new(ObjectType::Tree, vec![]).hash();
+        let mut tree = TreeBuilder::new();
+        let mut children = vec![];
+        let mut at = 0;
+        while let Some(entry) = entries.get(at) {
+            let Some(rest) = entry.path.strip_prefix(prefix) else {
+                break;
+            };
+            if let Some(slash) = rest.iter().position(|b| *b == b'/') {
+                let name = rest[..slash].to_vec();
+                let mut child = match self.children.iter().position(|(child, _)| *child == name) {
+                    Some(found) => self.children.swap_remove(found).1,
+                    None => CacheTree::default(),
+                };
+                let child_prefix = [prefix, &name, b"/"].concat();
+                at += child.update(odb, &entries[at..], &child_prefix, missing_ok)?;
+                // Left empty by entries that are all `add -N`, which like git
+                // we leave out rather than commit an empty directory
+                if child.oid == Some(empty_tree) {
+                    continue;
+                }
+                tree.push(TreeEntry {
+                    mode: "40000".to_string(),
+                    name: name.clone(),
+                    oid: child.oid.unwrap(),
+                });
+                children.push((name, child));
+                continue;
+            }
+            at += 1;
+            // Added with `add -N`, so there is nothing to commit yet
+            if entry.intent_to_add {
+                continue;
+            }
+            if !missing_ok && entry.mode != 0o160000 && !odb.contains(&entry.oid) {
+                return Err(invalid(format!(
+                    "invalid object {:o} {} for '{}'",
+                    entry.mode,
+                    entry.oid,
+                    String::from_utf8_lossy(&entry.path)
+                )));
+            }
+            tree.push(TreeEntry {
+                mode: format!("{:o}", entry.mode),
+                name: rest.to_vec(),
+                oid: entry.oid,
+            });
+        }
+
+        self.oid = Some(odb.write(&Object::Tree(tree.build()?).to_raw())?);
+        self.entry_count = at;
+        // Subtrees no entry lives in any more are dropped
+        children.sort_by(|(a, _), (b, _)| (a.len(), a).cmp(&(b.len(), b)));
+        self.children = children;
+        Ok(at)
+    }
+
+    /// The cached tree of the directory `path`, if there is one.
+    pub fn find(&self, path: &[u8]) -> Option<&CacheTree> {
+        if path.is_empty() {
+            return Some(self);
+        }
+        let (dir, rest) = match path.iter().position(|b| *b == b'/') {
+            Some(slash) => (&path[..slash], &path[slash + 1..]),
+            None => (path, &[][..]),
+        };
+        let (_, child) = self.children.iter().find(|(name, _)| name == dir)?;
+        child.find(rest)
+    }
+
+    /// Marks this tree and the subtrees leading to `path` out of date.
+    pub fn invalidate(&mut self, path: &[u8]) {
+        self.oid = None;
+        if let Some(slash) = path.iter().position(|b| *b == b'/') {
+            let (dir, rest) = (&path[..slash], &path[slash + 1..]);
+            if let Some((_, child)) = self.children.iter_mut().find(|(name, _)| name == dir) {
+                child.invalidate(rest);
+            }
+        }
+    }
+
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        self.write_node(out, b"");
+    }
+
+    fn write_node(&self, out: &mut Vec<u8>, name: &[u8]) {
+        out.extend_from_slice(name);
+        out.push(0);
+        let entry_count = match self.oid {
+            Some(_) => self.entry_count as i64,
+            None => -1,
+        };
+        out.extend_from_slice(format!("{entry_count} {}\n", self.children.len()).as_bytes());
+        if let Some(oid) = self.oid {
+            out.extend_from_slice(oid.as_bytes());
+        }
+        for (name, child) in &self.children {
+            child.write_node(out, name);
+        }
+    }
+}
+
+fn take_until<'a>(rest: &mut &'a [u8], end: u8) -> io::Result<&'a [u8]> {
+    let at = rest
+        .iter()
+        .position(|b| *b == end)
+        .ok_or_else(|| invalid("cache tree is truncated"))?;
+    let field = &rest[..at];
+    *rest = &rest[at + 1..];
+    Ok(field)
+}
+
+fn parse_number(field: &[u8]) -> io::Result<i64> {
+    std::str::from_utf8(field)
+        .ok()
+        .and_then(|field| field.parse().ok())
+        .ok_or_else(|| invalid("cache tree has a bad count"))
+}
@@ -0,0 +1,92 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Exclusive claim on a file, held by creating `<path>.lock` next to it.
+/// Committing renames the lock over the file; dropping an uncommitted lock
+/// removes it and leaves the file untouched.
+pub struct Lock {
+    path: PathBuf,
+    lock_path: PathBuf,
+    file: Option<fs::File>,
+}
+
+impl Lock {
+    pub fn acquire(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "unable to lock {}: {} exists; another process may be running",
+                        path.display(),
+                        lock_path.display()
+                    ),
+                ))
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Lock {
+            path,
+            lock_path,
+            file: Some(file),
+        })
+    }
+
+    /// Replaces the locked file with `content`.
+    pub fn commit(mut self, content: &[u8]) -> io::Result<()> {
+        self.write(content)?;
+        self.finish()
+    }
+
+    /// Writes `content` to the lock without touching the locked file yet,
+    /// so several locks can be filled before any of them is committed.
+    pub fn write(&mut self, content: &[u8]) -> io::Result<()> {
+        let file = self.file.as_mut().unwrap();
+        file.write_all(content)?;
+        file.sync_all()
+    }
+
+    /// Replaces the locked file with what was written to the lock.
+    pub fn finish(mut self) -> io::Result<()> {
+        drop(self.file.take());
+        let renamed = fs::rename(&self.lock_path, &self.path);
+        if renamed.is_err() {
+            let _ = fs::remove_file(&self.lock_path);
+        }
+        renamed
+    }
+
+    /// Removes the locked file, keeping it locked until it is gone.
+    pub fn delete(mut self) -> io::Result<()> {
+        drop(self.file.take());
+        let removed = match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+        fs::remove_file(&self.lock_path)?;
+        removed
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.lock_path);
+        }
+    }
+}
@@ -1,21 +1,58 @@
 use clap::Parser;
 use clap::Subcommand;
-use sha1::Digest;
-use sha1::Sha1;
-use std::collections::HashMap;
 #[allow(unused_imports)]
 use std::env;
 #[allow(unused_imports)]
 use std::fs;
+use std::ffi::OsStr;
 use std::fs::File;
 use std::io;
-use std::io::BufRead;
+use std::io::BufReader;
 use std::io::Read;
 use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::OsStringExt;
 use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 use std::path::PathBuf;
 
+use config::Config;
+use ident::Signature;
+use ignore::Ignore;
+use index::CacheTree;
+use index::Index;
+use index::IndexEntry;
+use index::Stat;
+use object::invalid;
+use object::Blob;
+use object::Commit;
+use object::Object;
+use object::ObjectId;
+use object::ObjectType;
+use object::Tree;
+use object::TreeBuilder;
+use object::TreeEntry;
+use odb::ObjectDatabase;
+use odb::ObjectStore;
+use refs::Expected;
+use refs::RefStore;
+use refs::RefValue;
+use rev_parse::rev_parse;
+use rev_parse::ObjectIds;
+
+mod config;
 mod dewey;
+mod ident;
+mod ignore;
+mod index;
+mod lock;
+mod object;
+mod odb;
+mod pack;
+mod refs;
+mod rev_parse;
+#[cfg(test)]
+mod test_util;
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
@@ -34,32 +71,255 @@ enum Command {
     Init {},
     Debug {},
     LsTree {
-        #[clap(long, short)]
+        /// Recurse into subtrees
+        #[clap(short = 'r')]
+        recursive: bool,
+        /// Show tree entries even when recursing into them
+        #[clap(short = 't')]
+        show_trees: bool,
+        /// Show the size of blobs
+        #[clap(long, short = 'l')]
+        long: bool,
+        #[clap(long)]
         name_only: bool,
-        object_sha: String,
+        /// List from the root of the tree; commands always run there anyway
+        #[clap(long)]
+        full_tree: bool,
+        /// Print each entry with %(objectmode), %(objecttype), %(objectname),
+        /// %(objectsize), %(objectsize:padded) and %(path)
+        #[clap(long, conflicts_with_all = ["long", "name_only"])]
+        format: Option<String>,
+        /// A tree, or a commit or tag that leads to one
+        tree_ish: String,
+        /// Only list these paths, or what is inside them
+        paths: Vec<PathBuf>,
     },
-    CatFile {
+    /// List the paths staged in the index
+    LsFiles {
+        /// Show the mode, object id and stage of each path
         #[clap(long, short)]
+        stage: bool,
+    },
+    CatFile {
+        /// Show the object's type
+        #[clap(short = 't', group = "mode")]
+        show_type: bool,
+        /// Show the object's size
+        #[clap(short = 's', group = "mode")]
+        show_size: bool,
+        /// Exit with zero status if the object exists
+        #[clap(short = 'e', group = "mode")]
+        exists: bool,
+        /// Pretty-print the object's content
+        #[clap(long, short, group = "mode")]
         pretty_print: bool,
-        object_sha: String,
+        /// Print id, type, size and content of each object named on stdin
+        #[clap(long, group = "mode")]
+        batch: bool,
+        /// Print id, type and size of each object named on stdin
+        #[clap(long, group = "mode")]
+        batch_check: bool,
+        #[clap(required_unless_present_any = ["batch", "batch_check"])]
+        object: Option<String>,
     },
     HashObject {
         #[clap(long, short)]
         write: bool,
         file: String,
     },
-    WriteTree {},
+    /// Write the staged content as trees, printing the root tree's id
+    WriteTree {
+        /// Print the id of the tree of this directory instead
+        #[clap(long)]
+        prefix: Option<PathBuf>,
+        /// Allow entries whose objects are not in the repository
+        #[clap(long)]
+        missing_ok: bool,
+        /// Hash the working directory as it is, ignoring the index
+        #[clap(long, conflicts_with_all = ["prefix", "missing_ok"])]
+        worktree: bool,
+    },
+    /// Show or expire the logs of where refs have pointed
+    Reflog {
+        #[command(subcommand)]
+        action: Option<ReflogAction>,
+    },
+    /// Point a ref at an object, optionally only if it holds an expected one
+    UpdateRef {
+        /// Reason recorded in the reflog
+        #[clap(short)]
+        message: Option<String>,
+        /// Delete the ref; the value given is then the one it must hold
+        #[clap(short)]
+        delete: bool,
+        /// Update a symbolic ref itself rather than the ref it points at
+        #[clap(long)]
+        no_deref: bool,
+        /// Apply update, create, delete and verify commands from stdin as
+        /// transactions
+        #[clap(long, conflicts_with_all = ["delete", "reference"])]
+        stdin: bool,
+        #[clap(required_unless_present = "stdin")]
+        reference: Option<String>,
+        /// The new value, then the old one it must replace; the all-zero id
+        /// as the old value means the ref must not exist
+        #[clap(num_args = 0..=2)]
+        values: Vec<String>,
+    },
+    /// Read, point or delete a symbolic ref such as HEAD
+    SymbolicRef {
+        /// Exit quietly with status 1 if the ref is not symbolic
+        #[clap(short)]
+        quiet: bool,
+        /// Delete the symbolic ref
+        #[clap(short, conflicts_with = "target")]
+        delete: bool,
+        /// Print the target without its refs/heads/ or similar prefix
+        #[clap(long)]
+        short: bool,
+        /// Reason recorded in the reflog
+        #[clap(short)]
+        message: Option<String>,
+        name: String,
+        target: Option<String>,
+    },
+    /// Print the object id each revision names
+    RevParse {
+        /// Fail unless given exactly one revision that names an object
+        #[clap(long)]
+        verify: bool,
+        /// Abbreviate ids to the shortest unique prefix of at least this length
+        #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "7")]
+        short: Option<usize>,
+        revisions: Vec<String>,
+    },
+    /// Stage files' current content in the index
+    Add(AddArgs),
+    /// Remove files from the index, and the working tree unless --cached
+    Rm(RmArgs),
+    /// Record what is staged as a new commit on the current branch
+    Commit(CommitArgs),
+    /// Create a commit of a tree, printing its id
     CommitTree {
         tree_sha: String,
-        #[clap(short)]
-        parent_sha: String,
+        /// A parent commit; give none for a root commit, several for a merge
+        #[clap(short = 'p')]
+        parent_sha: Vec<String>,
+        /// A paragraph of the message; repeat for more paragraphs
         #[clap(long, short)]
-        message: String,
+        message: Vec<String>,
+        /// Read a paragraph of the message from a file, or stdin for "-".
+        /// Without -m or -F the message is read from stdin.
+        #[clap(short = 'F')]
+        file: Vec<PathBuf>,
+        /// Author as "Name <email>" instead of the configured identity
+        #[clap(long)]
+        author: Option<String>,
+        /// Author date instead of now, exact or relative like "2.days.ago"
+        #[clap(long)]
+        date: Option<String>,
     },
     Clone {
         url: String,
         directory: String,
     },
+    /// Pack the objects named on stdin into <base-name>-<checksum>.pack
+    PackObjects {
+        base_name: PathBuf,
+        /// How many preceding objects to try as delta bases
+        #[clap(long, default_value_t = pack::PackOptions::default().window)]
+        window: usize,
+        /// Longest chain of deltas to allow
+        #[clap(long, default_value_t = pack::PackOptions::default().depth)]
+        depth: usize,
+    },
+}
+
+// Paths are given from the top of the working tree, and name a file or
+// everything under a directory
+#[derive(Debug, clap::Args)]
+struct AddArgs {
+    /// Stage every change under the paths, the whole tree if none are given
+    #[clap(long, short = 'A', conflicts_with = "update")]
+    all: bool,
+    /// Only stage changes to files already tracked
+    #[clap(long, short)]
+    update: bool,
+    /// Show what would be staged without staging it
+    #[clap(long, short = 'n')]
+    dry_run: bool,
+    #[clap(long, short)]
+    verbose: bool,
+    /// Add files even if they are ignored
+    #[clap(long, short)]
+    force: bool,
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+struct RmArgs {
+    /// Only unstage the files, keeping them in the working tree
+    #[clap(long)]
+    cached: bool,
+    /// Remove files even if they have changes that would be lost
+    #[clap(long, short)]
+    force: bool,
+    /// Allow removing everything under a directory
+    #[clap(short)]
+    recursive: bool,
+    /// Show what would be removed without removing it
+    #[clap(long, short = 'n')]
+    dry_run: bool,
+    #[clap(long, short)]
+    quiet: bool,
+    #[clap(required = true)]
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+struct CommitArgs {
+    /// A paragraph of the message; repeat for more paragraphs. Without -m or
+    /// -F the message is written in an editor.
+    #[clap(long, short)]
+    message: Vec<String>,
+    /// Read a paragraph of the message from a file, or stdin for "-"
+    #[clap(long, short = 'F')]
+    file: Vec<PathBuf>,
+    /// Replace the current commit instead of adding one on top of it
+    #[clap(long)]
+    amend: bool,
+    /// Commit even if the tree is the same as the parent's
+    #[clap(long)]
+    allow_empty: bool,
+    /// Stage changes to every tracked file first, as `add -u` does
+    #[clap(long, short)]
+    all: bool,
+    /// Author as "Name <email>" instead of the configured identity
+    #[clap(long)]
+    author: Option<String>,
+    /// Author date instead of now, exact or relative like "2.days.ago"
+    #[clap(long)]
+    date: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+enum ReflogAction {
+    /// List a ref's reflog, newest first
+    Show {
+        #[clap(default_value = "HEAD")]
+        reference: String,
+    },
+    /// Drop reflog entries older than a date
+    Expire {
+        /// Cutoff such as "90.days.ago", "now" or "never"; defaults to
+        /// gc.reflogExpire, then 90 days
+        #[clap(long)]
+        expire: Option<String>,
+        /// Expire the reflogs of every ref
+        #[clap(long)]
+        all: bool,
+        references: Vec<String>,
+    },
 }
 
 fn init(mut filepath: PathBuf) {
@@ -87,485 +347,1452 @@ fn main() {
             println!("Initialized git directory");
         }
         Command::CatFile {
-            pretty_print: _,
-            object_sha,
+            show_type,
+            show_size,
+            exists,
+            pretty_print,
+            batch,
+            batch_check,
+            object,
         } => {
-            let file_data = fs::read(format!(
-                "./.git/objects/{}/{}",
-                &object_sha[..2],
-                &object_sha[2..]
-            ))
-            .expect("Failed to open file");
-
-            let z = flate2::read::ZlibDecoder::new(&file_data[..]);
-
-            let mut object_header = vec![];
-            let mut zreader = io::BufReader::new(z);
-            let _n = zreader
-                .read_until(0, &mut object_header)
-                .expect("Failed to read null byte");
-
-            let header = String::from_utf8(object_header).expect("Failed to read utf-8");
-            let _object_type = header
-                .split_whitespace()
-                .next()
-                .expect("Failed to find object header");
-
-            let nfile_bytes = header
-                .split_whitespace()
-                .last()
-                .expect("Failed to find file length")
-                .strip_suffix("\0")
-                .expect("No null byte found");
-
-            let nfile_bytes = nfile_bytes.parse().expect("File length was not an integer");
-
-            let mut object_data = vec![0; nfile_bytes];
-            zreader
-                .read_exact(&mut object_data)
-                .expect(&format!("Failed to read {} bytes", nfile_bytes));
-            print!(
-                "{}",
-                String::from_utf8(object_data).expect("Failed to parse file data")
-            );
+            let odb = ObjectStore::open(".git").expect("Failed to open object store");
+            if batch || batch_check {
+                cat_file_batch(&odb, batch);
+                return;
+            }
+            let name = object.unwrap();
+            let object_sha = rev_parse(Path::new(".git"), &odb, &name);
+            if exists {
+                // A name that resolves to nothing is an error; only a
+                // well-formed one missing from the store is a plain "no"
+                let id = object_sha
+                    .unwrap_or_else(|_| fatal(format!("Not a valid object name {name}")));
+                std::process::exit(if odb.contains(&id) { 0 } else { 1 });
+            }
+            let object_sha = object_sha.expect("Failed to resolve object name");
+            if show_type || show_size {
+                let (kind, size) = odb.header(&object_sha).expect("Failed to read object");
+                match show_type {
+                    true => println!("{kind}"),
+                    false => println!("{size}"),
+                }
+                return;
+            }
+            let object = odb.read(&object_sha).expect("Failed to read object");
+            let mut stdout = io::stdout().lock();
+            match (pretty_print, object.kind) {
+                (true, ObjectType::Tree) => {
+                    let tree = Tree::parse(&object.data).expect("Failed to parse tree");
+                    for entry in &tree.entries {
+                        write_tree_entry(&mut stdout, entry).unwrap();
+                    }
+                }
+                _ => stdout.write_all(&object.data).unwrap(),
+            }
         }
         Command::LsTree {
+            recursive,
+            show_trees,
+            long,
             name_only,
-            object_sha,
+            full_tree: _,
+            format,
+            tree_ish,
+            paths,
         } => {
-            let file_data = fs::read(format!(
-                "./.git/objects/{}/{}",
-                &object_sha[..2],
-                &object_sha[2..]
-            ))
-            .expect("Failed to open file");
-
-            let z = flate2::read::ZlibDecoder::new(&file_data[..]);
-            let mut object_header = vec![];
-            let mut zreader = io::BufReader::new(z);
-
-            let _n = zreader
-                .read_until(0, &mut object_header)
-                .expect("Failed to read null byte");
-
-            let _header = String::from_utf8(object_header).expect("Failed to read utf-8");
-            loop {
-                let mut meta_data = vec![];
-                if let Err(_n) = zreader.read_until(b'\0', &mut meta_data) {
-                    break;
-                };
-                if meta_data.len() == 0 {
-                    break;
+            let odb = ObjectStore::open(".git").expect("Failed to open object store");
+            let id = rev_parse(Path::new(".git"), &odb, &tree_ish)
+                .unwrap_or_else(|_| fatal(format!("Not a valid object name {tree_ish}")));
+            let tree = rev_parse::peel(&odb, id, ObjectType::Tree)
+                .unwrap_or_else(|_| fatal("not a tree object"));
+            let format = match (format, long, name_only) {
+                (Some(format), _, _) => format,
+                (None, _, true) => "%(path)".to_string(),
+                (None, true, _) => {
+                    "%(objectmode) %(objecttype) %(objectname) %(objectsize:padded)\t%(path)"
+                        .to_string()
                 }
-                meta_data.pop().unwrap();
-
-                let meta = String::from_utf8(meta_data).unwrap();
-
-                let mut meta_split = meta.split(" ");
-                let object_mode = meta_split.next().expect("Failed to find mode");
-                let object_name = meta_split.next().expect("Failed to find name");
-                let object_type = match object_mode.starts_with("40000") {
-                    true => "tree",
-                    false => "blob",
-                };
-
-                let mut sha_data = vec![0; 20];
-                let _ = zreader.read_exact(&mut sha_data);
-                let mut sha_hex = "".to_string();
-                for byte in sha_data {
-                    sha_hex += &format!("{:02x}", byte);
+                (None, false, false) => {
+                    "%(objectmode) %(objecttype) %(objectname)\t%(path)".to_string()
                 }
-
-                if name_only {
-                    println!("{object_name}");
-                } else {
-                    println!("{object_mode} {object_type} {object_sha}\t{object_name}");
+            };
+            let filters: Vec<&[u8]> = paths
+                .iter()
+                .map(|path| path.as_os_str().as_bytes())
+                .collect();
+            let listing = LsTree {
+                odb: &odb,
+                recursive,
+                show_trees,
+                format: &format,
+                filters: &filters,
+            };
+            let mut stdout = io::stdout().lock();
+            listing
+                .list(&mut stdout, tree, b"")
+                .unwrap_or_else(|e| fatal(e));
+        }
+        Command::LsFiles { stage } => {
+            let index = Index::open(".git").expect("Failed to read index");
+            let mut stdout = io::stdout().lock();
+            for entry in index.entries() {
+                if stage {
+                    write!(stdout, "{:06o} {} {}\t", entry.mode, entry.oid, entry.stage)
+                        .expect("Failed to write");
                 }
+                stdout.write_all(&entry.path).expect("Failed to write");
+                writeln!(stdout).expect("Failed to write");
             }
         }
         Command::HashObject { write, file } => {
-            let mut object_data = fs::read(file).expect("Failed reading file");
-
-            let size = object_data.len();
-
-            let size_repr = size.to_string();
-
-            let mut block: Vec<u8> = vec![];
-            block.append(&mut "blob ".as_bytes().to_vec());
-            block.append(&mut size_repr.into_bytes());
-            block.push(0);
-            block.append(&mut object_data);
-
-            let mut hasher = Sha1::new();
-            hasher.update(block.clone());
-            let res = hasher.finalize();
-            let mut object_sha = "".to_string();
-
-            for byte in res {
-                print!("{byte:02x?}");
-                object_sha += &format!("{byte:02x?}");
-            }
-            println!("");
+            let object_data = fs::read(file).expect("Failed reading file");
+            let object = Object::Blob(Blob { data: object_data }).to_raw();
 
             if write {
-                let _ = fs::create_dir(format!(".git/objects/{}", &object_sha[..2]));
-                let file = fs::File::create(format!(
-                    "./.git/objects/{}/{}",
-                    &object_sha[..2],
-                    &object_sha[2..]
-                ))
-                .expect("Failed to open file");
-                let mut zwriter =
-                    flate2::write::ZlibEncoder::new(file, flate2::Compression::new(1));
-                let _n = zwriter.write_all(&block).expect("Failed to write to file");
+                let odb = ObjectStore::open(".git").expect("Failed to open object store");
+                odb.write(&object).expect("Failed to write object");
             }
+            println!("{}", object.hash());
         }
 
-        Command::WriteTree {} => {
-            let tree_ent = write_tree(std::env::current_dir().unwrap(), true);
-
-            let hash: Vec<&u8> = tree_ent
-                .iter()
-                .skip_while(|b| **b != b'\0')
-                .skip(1)
-                .take(20)
-                .collect();
-
-            for byte in hash {
-                print!("{byte:02x}");
+        Command::WriteTree {
+            prefix,
+            missing_ok,
+            worktree,
+        } => {
+            let odb = ObjectStore::open(".git").expect("Failed to open object store");
+            if worktree {
+                let tree_ent = write_tree(&odb, std::env::current_dir().unwrap(), true);
+                println!("{}", tree_ent.oid);
+                return;
+            }
+            let prefix = match prefix.as_deref().map(pathspec).transpose() {
+                Ok(prefix) => prefix.unwrap_or_default(),
+                Err(e) => fatal(e),
+            };
+            let (mut index, lock) = Index::lock(".git").unwrap_or_else(|e| fatal(e));
+            let tree = match index.write_tree(&odb, &prefix, missing_ok) {
+                Ok(tree) => tree,
+                Err(e) => {
+                    drop(lock);
+                    fatal(format!("git-write-tree: {e}"));
+                }
+            };
+            // Keep the trees just written cached for next time
+            index.commit(lock).expect("Failed to write index");
+            println!("{tree}");
+        }
+        Command::Add(args) => {
+            let odb = ObjectStore::open(".git").expect("Failed to open object store");
+            add(&odb, args).unwrap_or_else(|e| fatal(e));
+        }
+        Command::Rm(args) => {
+            let odb = ObjectStore::open(".git").expect("Failed to open object store");
+            rm(&odb, args).unwrap_or_else(|e| fatal(e));
+        }
+        Command::Commit(args) => {
+            let odb = ObjectStore::open(".git").expect("Failed to open object store");
+            commit(&odb, &RefStore::new(".git"), args).unwrap_or_else(|e| fatal(e));
+        }
+        Command::Reflog { action } => {
+            let odb = ObjectStore::open(".git").expect("Failed to open object store");
+            let refs = RefStore::new(".git");
+            match action.unwrap_or(ReflogAction::Show {
+                reference: "HEAD".to_string(),
+            }) {
+                ReflogAction::Show { reference } => {
+                    reflog_show(&odb, &refs, &reference).expect("Failed to show reflog")
+                }
+                ReflogAction::Expire {
+                    expire,
+                    all,
+                    references,
+                } => reflog_expire(&refs, expire, all, references).expect("Failed to expire reflog"),
+            }
+        }
+        Command::UpdateRef {
+            message,
+            delete,
+            no_deref,
+            stdin,
+            reference,
+            values,
+        } => {
+            let odb = ObjectStore::open(".git").expect("Failed to open object store");
+            let refs = RefStore::new(".git");
+            let message = message.unwrap_or_default();
+            if stdin {
+                update_ref_stdin(&odb, &refs, &message).unwrap_or_else(|e| fatal(e));
+                return;
+            }
+            let reference = reference.unwrap();
+            let mut transaction = refs.transaction();
+            let result = (|| {
+                let name = ref_to_update(&refs, &reference, no_deref)?;
+                match (delete, &values[..]) {
+                    (true, [] | [_]) => {
+                        let expected = expected_value(&odb, values.first().map(String::as_str))?;
+                        transaction.delete(&name, expected);
+                    }
+                    (false, [new, ..]) => {
+                        let expected = expected_value(&odb, values.get(1).map(String::as_str))?;
+                        match new_value(&odb, new)? {
+                            Some(id) => transaction.update(&name, RefValue::Direct(id), expected),
+                            None => transaction.delete(&name, expected),
+                        }
+                    }
+                    _ => return Err(invalid_input("usage: update-ref [-d] <ref> <new> [<old>]".to_string())),
+                }
+                transaction.commit(&message)
+            })();
+            result.unwrap_or_else(|e| fatal(e));
+        }
+        Command::SymbolicRef {
+            quiet,
+            delete,
+            short,
+            message,
+            name,
+            target,
+        } => {
+            let refs = RefStore::new(".git");
+            let current = refs.read(&name).unwrap_or_else(|e| fatal(e));
+            match (target, current) {
+                (Some(target), _) => {
+                    if name == "HEAD" && !target.starts_with("refs/") {
+                        fatal(format!("Refusing to point HEAD outside of refs/: {target}"));
+                    }
+                    let message = message.unwrap_or_default();
+                    refs.update(&name, &RefValue::Symbolic(target), Expected::Any, &message)
+                        .unwrap_or_else(|e| fatal(e));
+                }
+                (None, Some(RefValue::Symbolic(_))) if delete => {
+                    if name == "HEAD" {
+                        fatal("deleting 'HEAD' is not allowed");
+                    }
+                    refs.delete(&name, Expected::Any).unwrap_or_else(|e| fatal(e));
+                }
+                (None, Some(RefValue::Symbolic(target))) if short => {
+                    println!("{}", shorten_ref(&target))
+                }
+                (None, Some(RefValue::Symbolic(target))) => println!("{target}"),
+                (None, _) if quiet => std::process::exit(1),
+                (None, _) => fatal(format!("ref {name} is not a symbolic ref")),
+            }
+        }
+        Command::RevParse {
+            verify,
+            short,
+            revisions,
+        } => {
+            if verify && revisions.len() != 1 {
+                fatal("Needed a single revision");
+            }
+            let odb = ObjectStore::open(".git").expect("Failed to open object store");
+            let ids = short.map(|_| ObjectIds::new(&odb).expect("Failed to list objects"));
+            for revision in &revisions {
+                let id = match rev_parse(Path::new(".git"), &odb, revision) {
+                    Ok(id) if !verify || odb.contains(&id) => id,
+                    Ok(_) => fatal("Needed a single revision"),
+                    Err(e) => fatal(e),
+                };
+                match (short, &ids) {
+                    (Some(len), Some(ids)) => println!("{}", ids.abbreviate(id, len)),
+                    _ => println!("{id}"),
+                }
             }
-            println!("");
         }
         Command::CommitTree {
             tree_sha,
             parent_sha,
             message,
+            file,
+            author,
+            date,
         } => {
-            // println!("Tree: {tree_sha}");
-            // println!("Parent: {parent_sha}");
-            // println!("Message: {message}");
-            write_commit(tree_sha, parent_sha, message);
+            let odb = ObjectStore::open(".git").expect("Failed to open object store");
+            let tree_sha = rev_parse(Path::new(".git"), &odb, &tree_sha)
+                .and_then(|id| rev_parse::peel(&odb, id, ObjectType::Tree))
+                .unwrap_or_else(|e| fatal(e));
+            let mut parents = vec![];
+            for parent in &parent_sha {
+                let parent = rev_parse(Path::new(".git"), &odb, parent)
+                    .and_then(|id| rev_parse::peel(&odb, id, ObjectType::Commit))
+                    .unwrap_or_else(|e| fatal(e));
+                if parents.contains(&parent) {
+                    eprintln!("error: duplicate parent {parent} ignored");
+                } else {
+                    parents.push(parent);
+                }
+            }
+            let message = commit_message(&message, &file).unwrap_or_else(|e| fatal(e));
+            let config = Config::open(".git").expect("Failed to read config");
+            let committer = Signature::committer(&config).unwrap_or_else(|e| fatal(e));
+            let signature = Signature::author(&config)
+                .and_then(|signature| override_author(signature, author, date))
+                .unwrap_or_else(|e| fatal(e));
+            let commit = write_commit(&odb, tree_sha, parents, message, &signature, &committer);
+            println!("{commit}");
         }
         Command::Clone { url, directory } => {
-            let (main, items) = clone(url.clone());
-            println!("HEAD should be {main}");
             let repo_name = &directory;
             //let mut repo_name = (url).split("/").last().unwrap();
             //repo_name = repo_name.strip_suffix(".git").unwrap_or(repo_name);
 
             init(repo_name.into());
-            for (obj_type, object_sha, data) in items.values() {
-                let _ = fs::create_dir(format!("{repo_name}/.git/objects/{}", &object_sha[..2]));
-
-                let file = fs::File::create(format!(
-                    "{repo_name}/.git/objects/{}/{}",
-                    &object_sha[..2],
-                    &object_sha[2..]
-                ))
-                .unwrap();
-                let mut zwriter =
-                    flate2::write::ZlibEncoder::new(file, flate2::Compression::new(1));
-                let mut type_header: Vec<u8> = match obj_type {
-                    ObjectType::Commit => "commit ",
-                    ObjectType::Blob => "blob ",
-                    ObjectType::Tag => "tag ",
-                    ObjectType::Tree => "tree ",
+            let git_dir = Path::new(repo_name).join(".git");
+            let pack_dir = git_dir.join("objects").join("pack");
+            fs::create_dir_all(&pack_dir).expect("Failed to create pack directory");
+            let tmp_path = pack_dir.join("tmp_pack");
+            let mut pack_file = File::create(&tmp_path).expect("Failed to create pack file");
+            let (remote_refs, remote_head) = clone(url.clone(), &mut pack_file);
+            drop(pack_file);
+
+            pack::store_pack(&git_dir, &tmp_path).expect("Failed to store pack");
+            let odb = ObjectStore::open(&git_dir).expect("Failed to open object store");
+            let main = remote_refs
+                .iter()
+                .find(|(name, _)| name == "HEAD")
+                .map(|(_, hash)| *hash)
+                .expect("Server did not advertise HEAD");
+            if !odb.contains(&main) {
+                panic!("Server did not send the advertised commit {main}");
+            }
+            let message = format!("clone: from {url}");
+            let refs = RefStore::new(&git_dir);
+            write_clone_refs(&refs, &remote_refs, remote_head, main, &message)
+                .expect("Failed to write refs");
+
+            let tree = get_tree_from_commit(&odb, &main).unwrap_or_else(|e| fatal(e));
+
+            let (mut index, lock) = Index::lock(&git_dir).expect("Failed to lock index");
+            let cache_tree = match tree_to_disk(&odb, tree, Path::new(repo_name), &mut index, b"") {
+                Ok(cache_tree) => cache_tree,
+                Err(e) => {
+                    drop(lock);
+                    fatal(e)
                 }
-                .bytes()
+            };
+            index.cache_tree = Some(cache_tree);
+            index.commit(lock).expect("Failed to write index");
+        }
+        Command::PackObjects {
+            base_name,
+            window,
+            depth,
+        } => {
+            let objects: Vec<(ObjectId, String)> = io::stdin()
+                .lines()
+                .map(|line| {
+                    let line = line.expect("Failed to read stdin");
+                    // Lines may carry a path after the id, as rev-list prints them
+                    let (id, name) = line.split_once(' ').unwrap_or((&line, ""));
+                    (id.parse().expect("Not an object id"), name.to_string())
+                })
                 .collect();
-                type_header.append(&mut (data.len().to_string().bytes().collect()));
-                type_header.push(0);
-                type_header.append(&mut data.clone());
-                let _n = zwriter
-                    .write_all(&type_header)
-                    .expect("Failed to write to file");
-            }
-            std::env::set_current_dir(directory).unwrap();
-
-            let tree = get_tree_from_commit(&main);
-
-            tree_to_disk(tree, "".to_string(), None);
+            let odb = ObjectStore::open(".git").expect("Failed to open object store");
+            let options = pack::PackOptions { window, depth };
+            let name = pack::write_pack(&odb, &objects, &base_name, &options)
+                .expect("Failed to write pack");
+            println!("{name}");
         }
         Command::Debug {} => {
             let delta_buf = fs::read("./test.delta").unwrap();
             let base_buf = fs::read("./base.data").unwrap();
-            let new = dedelta_buf(delta_buf, base_buf);
+            let new = dewey::dedelta_buf(&delta_buf, &base_buf).expect("Failed to apply delta");
 
             println!("{new:?}");
         }
     }
 }
 
-fn get_tree_from_commit(sha: &str) -> String {
-    let file_data = fs::read(format!("./.git/objects/{}/{}", &sha[..2], &sha[2..]))
-        .expect("Failed to open file");
+// Answers each object name on stdin with "<id> <type> <size>", followed by
+// the content if `contents` is set, or with "<name> missing"
+fn cat_file_batch(odb: &dyn ObjectDatabase, contents: bool) {
+    let mut stdout = io::stdout().lock();
+    for line in io::stdin().lines() {
+        let line = line.expect("Failed to read stdin");
+        let name = line.split_whitespace().next().unwrap_or_default();
+        let id = match rev_parse(Path::new(".git"), odb, name) {
+            Ok(id) if odb.contains(&id) => id,
+            _ => {
+                writeln!(stdout, "{name} missing").unwrap();
+                stdout.flush().unwrap();
+                continue;
+            }
+        };
+        if contents {
+            let object = odb.read(&id).expect("Failed to read object");
+            writeln!(stdout, "{id} {} {}", object.kind, object.data.len()).unwrap();
+            stdout.write_all(&object.data).unwrap();
+            writeln!(stdout).unwrap();
+        } else {
+            let (kind, size) = odb.header(&id).expect("Failed to read object");
+            writeln!(stdout, "{id} {kind} {size}").unwrap();
+        }
+        // Callers may be waiting on each answer before asking the next
+        stdout.flush().unwrap();
+    }
+}
+
+// Applies the commands on stdin. Each "start" ... "commit" block is one
+// transaction; without "start", everything read is one transaction
+// committed at the end.
+fn update_ref_stdin(odb: &dyn ObjectDatabase, refs: &RefStore, message: &str) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    let mut transaction = refs.transaction();
+    let mut started = false;
+    let mut pending = false;
+    // Holds the locks between `prepare` and `commit` or `abort`
+    let mut prepared = None;
+    let mut no_deref = false;
+    for line in io::stdin().lines() {
+        let line = line?;
+        let words: Vec<&str> = line.split(' ').collect();
+        let usage = || invalid_input(format!("invalid command: {line}"));
+        if prepared.is_some() && !matches!(words[..], ["commit"] | ["abort"]) {
+            return Err(invalid_input(
+                "prepared transactions can only be closed".to_string(),
+            ));
+        }
+        match words[..] {
+            ["start"] => {
+                if pending {
+                    return Err(invalid_input("start: transaction already open".to_string()));
+                }
+                started = true;
+                writeln!(stdout, "start: ok")?;
+            }
+            ["prepare"] => {
+                let transaction = std::mem::replace(&mut transaction, refs.transaction());
+                prepared = Some(transaction.prepare(message)?);
+                writeln!(stdout, "prepare: ok")?;
+            }
+            ["commit"] => {
+                match prepared.take() {
+                    Some(prepared) => prepared.commit()?,
+                    None => {
+                        std::mem::replace(&mut transaction, refs.transaction()).commit(message)?
+                    }
+                }
+                (started, pending) = (false, false);
+                writeln!(stdout, "commit: ok")?;
+            }
+            ["abort"] => {
+                transaction = refs.transaction();
+                prepared = None;
+                (started, pending) = (false, false);
+                writeln!(stdout, "abort: ok")?;
+            }
+            ["option", "no-deref"] => {
+                no_deref = true;
+                continue;
+            }
+            ["update", name, new, ref old @ ..] if old.len() <= 1 => {
+                let name = ref_to_update(refs, name, no_deref)?;
+                let expected = expected_value(odb, old.first().copied())?;
+                match new_value(odb, new)? {
+                    Some(id) => transaction.update(&name, RefValue::Direct(id), expected),
+                    None => transaction.delete(&name, expected),
+                }
+                pending = true;
+            }
+            ["create", name, new] => {
+                let name = ref_to_update(refs, name, no_deref)?;
+                let id = new_value(odb, new)?.ok_or_else(usage)?;
+                transaction.update(&name, RefValue::Direct(id), Expected::Missing);
+                pending = true;
+            }
+            ["delete", name, ref old @ ..] if old.len() <= 1 => {
+                let name = ref_to_update(refs, name, no_deref)?;
+                transaction.delete(&name, expected_value(odb, old.first().copied())?);
+                pending = true;
+            }
+            ["verify", name, ref old @ ..] if old.len() <= 1 => {
+                let name = ref_to_update(refs, name, no_deref)?;
+                // Verifying against nothing means the ref must not exist
+                let expected = match expected_value(odb, old.first().copied())? {
+                    Expected::Any => Expected::Missing,
+                    expected => expected,
+                };
+                transaction.verify(&name, expected);
+                pending = true;
+            }
+            _ => return Err(usage()),
+        }
+        no_deref = false;
+        stdout.flush()?;
+    }
+    // An explicit transaction left open is abandoned, as git does, and so is
+    // a prepared one
+    if pending && !started && prepared.is_none() {
+        transaction.commit(message)?;
+    }
+    Ok(())
+}
 
-    let z = flate2::read::ZlibDecoder::new(&file_data[..]);
-    let mut object_header = vec![];
-    let mut zreader = io::BufReader::new(z);
+// The ref an update of `name` changes: the end of its chain of symbolic refs
+// unless told not to follow them
+fn ref_to_update(refs: &RefStore, name: &str, no_deref: bool) -> io::Result<String> {
+    match no_deref {
+        true => Ok(name.to_string()),
+        false => Ok(refs.follow(name)?.0),
+    }
+}
+
+// A new value for a ref, or none for the all-zero id that means deleting it
+fn new_value(odb: &dyn ObjectDatabase, value: &str) -> io::Result<Option<ObjectId>> {
+    let id = rev_parse(Path::new(".git"), odb, value)?;
+    if id == ObjectId::default() {
+        return Ok(None);
+    }
+    if !odb.contains(&id) {
+        return Err(invalid_input(format!(
+            "trying to write ref with nonexistent object {id}"
+        )));
+    }
+    Ok(Some(id))
+}
 
-    let _n = zreader
-        .read_until(0, &mut object_header)
-        .expect("Failed to read null byte");
+fn expected_value(odb: &dyn ObjectDatabase, value: Option<&str>) -> io::Result<Expected> {
+    Ok(match value {
+        None => Expected::Any,
+        Some("") => Expected::Missing,
+        Some(value) => match rev_parse(Path::new(".git"), odb, value)? {
+            id if id == ObjectId::default() => Expected::Missing,
+            id => Expected::Value(id),
+        },
+    })
+}
+
+// "refs/heads/main" as "main", and so on
+fn shorten_ref(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/", "refs/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
 
-    let _header = String::from_utf8(object_header).expect("Failed to read utf-8");
+// Reports an error the way git does, for commands scripts check the status of
+fn fatal(message: impl std::fmt::Display) -> ! {
+    eprintln!("fatal: {message}");
+    std::process::exit(128)
+}
+
+// "<short id> <name>@{<n>}: <message>" for each entry, newest first
+fn reflog_show(odb: &dyn ObjectDatabase, refs: &RefStore, name: &str) -> io::Result<()> {
+    let full = refs
+        .dwim(name)?
+        .ok_or_else(|| invalid_input(format!("unknown ref {name}")))?;
+    let ids = ObjectIds::new(odb)?;
+    let mut stdout = io::stdout().lock();
+    for (n, entry) in refs.reflog(&full)?.iter().rev().enumerate() {
+        let short = ids.abbreviate(entry.new, 7);
+        writeln!(stdout, "{short} {name}@{{{n}}}: {}", entry.message)?;
+    }
+    Ok(())
+}
 
-    let mut meta_data = vec![];
-    if let Err(_n) = zreader.read_until(b'\n', &mut meta_data) {
-        panic!();
+fn reflog_expire(
+    refs: &RefStore,
+    expire: Option<String>,
+    all: bool,
+    mut names: Vec<String>,
+) -> io::Result<()> {
+    let config = Config::open(".git")?;
+    let expire = expire
+        .or_else(|| config.get("gc.reflogExpire").map(str::to_string))
+        .unwrap_or_else(|| "90.days.ago".to_string());
+    let cutoff = match expire.as_str() {
+        "never" | "false" => return Ok(()),
+        "all" | "now" => i64::MAX,
+        date => ident::approxidate(date, ident::now())
+            .ok_or_else(|| invalid_input(format!("bad expiry date {date}")))?,
     };
-    meta_data.pop().unwrap();
+    if all {
+        names.push("HEAD".to_string());
+        names.extend(refs.list("refs/")?.into_iter().map(|(name, _)| name));
+    }
+    for name in names {
+        let full = refs.dwim(&name)?.unwrap_or(name);
+        let entries = refs.reflog(&full)?;
+        let kept: Vec<_> = entries
+            .iter()
+            .filter(|entry| entry.who.time >= cutoff)
+            .cloned()
+            .collect();
+        if kept.len() != entries.len() {
+            refs.write_reflog(&full, &kept)?;
+        }
+    }
+    Ok(())
+}
+
+// "<mode> <type> <id>\t<name>" as `cat-file -p` and `ls-tree` print entries
+fn write_tree_entry(out: &mut impl Write, entry: &TreeEntry) -> io::Result<()> {
+    write!(out, "{:0>6} {} {}\t", entry.mode, entry.kind(), entry.oid)?;
+    out.write_all(&entry.name)?;
+    writeln!(out)
+}
+
+struct LsTree<'a> {
+    odb: &'a dyn ObjectDatabase,
+    recursive: bool,
+    show_trees: bool,
+    format: &'a str,
+    filters: &'a [&'a [u8]],
+}
+
+impl LsTree<'_> {
+    fn list(&self, out: &mut impl Write, tree: ObjectId, prefix: &[u8]) -> io::Result<()> {
+        let tree = match self.odb.read_object(&tree)? {
+            Object::Tree(tree) => tree,
+            _ => return Err(invalid(format!("not a tree object: {tree}"))),
+        };
+        for entry in &tree.entries {
+            let path = [prefix, &entry.name].concat();
+            // A filter names the entry, something the entry is inside of, or
+            // with a trailing slash, the directory to list
+            let matched = self.filters.is_empty()
+                || self.filters.iter().any(|filter| {
+                    let filter = filter.strip_suffix(b"/").unwrap_or(filter);
+                    path == filter || path.starts_with(filter) && path[filter.len()] == b'/'
+                });
+            let leading = self.filters.iter().any(|filter| {
+                filter.len() > path.len() && filter.starts_with(&path) && filter[path.len()] == b'/'
+            });
+            let list_dir = self
+                .filters
+                .iter()
+                .any(|filter| filter.strip_suffix(b"/") == Some(&path[..]));
 
-    let tree = String::from_utf8(meta_data).unwrap();
-    let name = tree.split_ascii_whitespace().last().unwrap();
-    name.to_string()
+            if entry.is_tree() && (self.recursive && matched || leading || list_dir) {
+                if self.show_trees && (matched || leading) {
+                    self.write_entry(out, entry, &path)?;
+                }
+                self.list(out, entry.oid, &[&path[..], b"/"].concat())?;
+            } else if matched {
+                self.write_entry(out, entry, &path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_entry(&self, out: &mut impl Write, entry: &TreeEntry, path: &[u8]) -> io::Result<()> {
+        let mut rest = self.format;
+        while let Some(start) = rest.find('%') {
+            out.write_all(&rest.as_bytes()[..start])?;
+            rest = &rest[start + 1..];
+            if let Some(placeholder) = rest.strip_prefix('(') {
+                let end = placeholder
+                    .find(')')
+                    .ok_or_else(|| invalid_input(format!("unterminated placeholder %{rest}")))?;
+                match &placeholder[..end] {
+                    "objectmode" => write!(out, "{:0>6}", entry.mode)?,
+                    "objecttype" => write!(out, "{}", entry.kind())?,
+                    "objectname" => write!(out, "{}", entry.oid)?,
+                    "objectsize" => write!(out, "{}", self.size(entry)?)?,
+                    "objectsize:padded" => write!(out, "{:>7}", self.size(entry)?)?,
+                    "path" => out.write_all(path)?,
+                    other => return Err(invalid_input(format!("bad ls-tree format: %({other})"))),
+                }
+                rest = &placeholder[end + 1..];
+            } else if let Some(after) = rest.strip_prefix('%') {
+                out.write_all(b"%")?;
+                rest = after;
+            } else if let Some(byte) = rest
+                .strip_prefix('x')
+                .and_then(|hex| hex.get(..2))
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                // %xNN stands for the byte NN, as in git's pretty formats
+                out.write_all(&[byte])?;
+                rest = &rest[3..];
+            } else {
+                out.write_all(b"%")?;
+            }
+        }
+        out.write_all(rest.as_bytes())?;
+        writeln!(out)
+    }
+
+    // Only blobs have a size worth showing
+    fn size(&self, entry: &TreeEntry) -> io::Result<String> {
+        match entry.kind() {
+            ObjectType::Blob => Ok(self.odb.header(&entry.oid)?.1.to_string()),
+            _ => Ok("-".to_string()),
+        }
+    }
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn get_tree_from_commit(odb: &dyn ObjectDatabase, sha: &ObjectId) -> io::Result<ObjectId> {
+    match odb.read_object(sha)? {
+        Object::Commit(commit) => Ok(commit.tree),
+        _ => Err(invalid(format!("not a commit object: {sha}"))),
+    }
+}
+
+fn add(odb: &dyn ObjectDatabase, args: AddArgs) -> io::Result<()> {
+    if args.paths.is_empty() && !args.all && !args.update {
+        println!("Nothing specified, nothing added.");
+        return Ok(());
+    }
+    let (mut index, lock) = Index::lock(".git")?;
+    let ignored = stage_paths(odb, &mut index, &args)?;
+    if args.dry_run {
+        drop(lock);
+    } else {
+        index.commit(lock)?;
+    }
+    if !ignored.is_empty() {
+        eprintln!("The following paths are ignored by one of your .gitignore files:");
+        for path in ignored {
+            eprintln!("{}", String::from_utf8_lossy(&path));
+        }
+        eprintln!("hint: Use -f if you really want to add them.");
+        std::process::exit(1);
+    }
+    Ok(())
 }
 
-fn write_commit(tree: String, parent: String, message: String) -> String {
-    let tree_line = format!("tree {tree}\n");
-    let parent_line = format!("parent {parent}\n");
-    let author_line = format!("author Aaron <email.email@gmail.com> 10 -0500\n");
-    let commiter_line =
-        format!("committer aaron <aaron@localhost.localdomain> 1727570086 -0500\n\n");
-    let message = format!("{message}");
-    let len = tree_line.len()
-        + parent_line.len()
-        + author_line.len()
-        + commiter_line.len()
-        + message.len();
-    let len = len.to_string();
+// Hashes new and changed files under the paths into the index, and
+// unstages tracked files that are gone. Untracked files that are ignored are
+// left out unless --force; the ones named outright are returned.
+fn stage_paths(
+    odb: &dyn ObjectDatabase,
+    index: &mut Index,
+    args: &AddArgs,
+) -> io::Result<Vec<Vec<u8>>> {
+    let mut specs: Vec<Vec<u8>> = args
+        .paths
+        .iter()
+        .map(|path| pathspec(path))
+        .collect::<io::Result<_>>()?;
+    if specs.is_empty() {
+        specs.push(vec![]);
+    }
+
+    let mut ignore = if args.force {
+        None
+    } else {
+        Some(Ignore::new(Path::new(".git"), Path::new("."))?)
+    };
+    let mut files = vec![];
+    let mut ignored = vec![];
+    let mut matched = vec![];
+    for spec in &specs {
+        let metadata = fs::symlink_metadata(OsStr::from_bytes(spec));
+        let is_dir = metadata.as_ref().is_ok_and(|metadata| metadata.is_dir());
+        // A path that exists is matched even if nothing under it is staged
+        matched.push(spec.is_empty() || metadata.is_ok());
+        if let (Some(ignore), false) = (ignore.as_mut(), spec.is_empty() || args.update) {
+            if !is_tracked(index, spec, is_dir) {
+                if let Some(excluded) = ignore.excluded_part(spec, is_dir)? {
+                    ignored.push(excluded.to_vec());
+                    continue;
+                }
+            }
+        }
+        working_files(spec, index, ignore.as_mut(), &mut files)?;
+    }
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+    files.dedup_by(|(a, _), (b, _)| a == b);
+    for (spec, matched) in specs.iter().zip(&mut matched) {
+        *matched |= files.iter().any(|(path, _)| in_pathspec(spec, path));
+    }
+    ignored.sort();
+    ignored.dedup();
+
+    let mut added = vec![];
+    for (path, metadata) in files {
+        let stages = index.stages(&path);
+        if args.update && stages.is_empty() {
+            continue;
+        }
+        let stat = Stat::from_metadata(&metadata);
+        if let [staged] = stages {
+            if staged.stage == 0 && index.is_fresh(staged, &stat) {
+                continue;
+            }
+        }
+        let blob = write_blob(odb, PathBuf::from(OsStr::from_bytes(&path)), !args.dry_run)
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("unable to index file '{}': {e}", String::from_utf8_lossy(&path)),
+                )
+            })?;
+        let mode = u32::from_str_radix(&blob.mode, 8).unwrap();
+        let entry = IndexEntry::new(path, mode, blob.oid, stat);
+        let changed = match stages {
+            [staged] => staged.stage != 0 || staged.oid != entry.oid || staged.mode != mode,
+            _ => true,
+        };
+        if changed {
+            added.push(entry);
+        } else if !args.dry_run {
+            // Only the stat data was out of date
+            index.add(entry);
+        }
+    }
+
+    let mut removed = vec![];
+    for entry in index.entries() {
+        if removed.last() == Some(&entry.path) || entry.skip_worktree {
+            continue;
+        }
+        let Some(spec) = specs.iter().position(|spec| in_pathspec(spec, &entry.path)) else {
+            continue;
+        };
+        matched[spec] = true;
+        let gone = match fs::symlink_metadata(OsStr::from_bytes(&entry.path)) {
+            // Submodules are directories, anything else turned into one is gone
+            Ok(metadata) => metadata.is_dir() && entry.mode != 0o160000,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+            Err(e) => return Err(e),
+        };
+        if gone {
+            removed.push(entry.path.clone());
+        }
+    }
 
-    let content = format!("{tree_line}{parent_line}{author_line}{commiter_line}{message}\n");
+    if let Some(spec) = matched.iter().position(|matched| !matched) {
+        return Err(invalid_input(format!(
+            "pathspec '{}' did not match any files",
+            args.paths[spec].display()
+        )));
+    }
+    for entry in added {
+        if args.dry_run || args.verbose {
+            println!("add '{}'", String::from_utf8_lossy(&entry.path));
+        }
+        if !args.dry_run {
+            // Staging a file resolves any conflict over it
+            index.remove(&entry.path);
+            index.add(entry);
+        }
+    }
+    for path in removed {
+        if args.dry_run || args.verbose {
+            println!("remove '{}'", String::from_utf8_lossy(&path));
+        }
+        if !args.dry_run {
+            index.remove(&path);
+        }
+    }
+    Ok(ignored)
+}
 
-    let mut commit_bytes = format!("commit {len}").to_string().into_bytes();
-    commit_bytes.push(0);
-    let mut content_bytes = content.into_bytes();
-    commit_bytes.append(&mut content_bytes);
+// Unstages the files the paths name, and unless --cached deletes them,
+// refusing to lose changes that are in neither HEAD nor the working tree
+fn rm(odb: &dyn ObjectDatabase, args: RmArgs) -> io::Result<()> {
+    let (mut index, lock) = Index::lock(".git")?;
+    let mut paths: Vec<Vec<u8>> = vec![];
+    for path in &args.paths {
+        let spec = pathspec(path)?;
+        let mut found = false;
+        for entry in index.entries() {
+            if !in_pathspec(&spec, &entry.path) {
+                continue;
+            }
+            if entry.path != spec && !args.recursive {
+                return Err(invalid_input(format!(
+                    "not removing '{}' recursively without -r",
+                    path.display()
+                )));
+            }
+            found = true;
+            paths.push(entry.path.clone());
+        }
+        if !found {
+            return Err(invalid_input(format!(
+                "pathspec '{}' did not match any files",
+                path.display()
+            )));
+        }
+    }
+    paths.sort();
+    paths.dedup();
+
+    if !args.force {
+        let errors = removal_errors(odb, &index, &paths, args.cached);
+        if !errors.is_empty() {
+            drop(lock);
+            eprint!("{errors}");
+            std::process::exit(1);
+        }
+    }
+    for path in &paths {
+        if !args.quiet {
+            println!("rm '{}'", String::from_utf8_lossy(path));
+        }
+        if args.dry_run {
+            continue;
+        }
+        index.remove(path);
+        if !args.cached {
+            let file = Path::new(OsStr::from_bytes(path));
+            match fs::remove_file(file) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            // Like git, leave no directories emptied by the removal
+            for dir in file.ancestors().skip(1) {
+                if dir.as_os_str().is_empty() || fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    if !args.dry_run {
+        index.commit(lock)?;
+    }
+    Ok(())
+}
 
-    let mut hasher = Sha1::new();
+// Errors, as git words them, for files whose removal would lose content
+// found nowhere else
+fn removal_errors(
+    odb: &dyn ObjectDatabase,
+    index: &Index,
+    paths: &[Vec<u8>],
+    cached: bool,
+) -> String {
+    let mut both = vec![];
+    let mut staged = vec![];
+    let mut local = vec![];
+    for path in paths {
+        // Removing a conflicted file is how one resolves it that way
+        let Some(entry) = index.entry(path, 0) else {
+            continue;
+        };
+        let name = String::from_utf8_lossy(path).into_owned();
+        let in_head = rev_parse(Path::new(".git"), odb, &format!("HEAD:{name}")).ok();
+        let staged_change = in_head != Some(entry.oid);
+        let file = PathBuf::from(OsStr::from_bytes(path));
+        let local_change = match fs::symlink_metadata(&file) {
+            Ok(metadata) if metadata.is_dir() => false,
+            Ok(metadata) => {
+                !index.is_fresh(entry, &Stat::from_metadata(&metadata))
+                    && !write_blob(odb, file, false).is_ok_and(|blob| blob.oid == entry.oid)
+            }
+            Err(_) => false,
+        };
+        match (staged_change, local_change) {
+            (true, true) => both.push(name),
+            (true, false) if !cached => staged.push(name),
+            (false, true) if !cached => local.push(name),
+            _ => {}
+        }
+    }
 
-    hasher.update(commit_bytes.clone());
-    let res = hasher.finalize();
-    let mut object_sha = "".to_string();
-    let mut hash = vec![];
-    for byte in res {
-        hash.push(byte);
-        object_sha += &format!("{byte:02x?}");
+    let mut message = String::new();
+    for (paths, what, hint) in [
+        (
+            both,
+            "staged content different from both the\nfile and the HEAD",
+            "(use -f to force removal)",
+        ),
+        (
+            staged,
+            "changes staged in the index",
+            "(use --cached to keep the file, or -f to force removal)",
+        ),
+        (
+            local,
+            "local modifications",
+            "(use --cached to keep the file, or -f to force removal)",
+        ),
+    ] {
+        if paths.is_empty() {
+            continue;
+        }
+        let (subject, verb) = match paths.len() {
+            1 => ("file", "has"),
+            _ => ("files", "have"),
+        };
+        message.push_str(&format!("error: the following {subject} {verb} {what}:\n"));
+        for path in paths {
+            message.push_str(&format!("    {path}\n"));
+        }
+        message.push_str(hint);
+        message.push('\n');
     }
+    message
+}
 
-    let _ = fs::create_dir(format!(".git/objects/{}", &object_sha[..2]));
-    let file = fs::File::create(format!(
-        "./.git/objects/{}/{}",
-        &object_sha[..2],
-        &object_sha[2..]
-    ))
-    .expect("Failed to open file");
-    let mut zwriter = flate2::write::ZlibEncoder::new(file, flate2::Compression::new(1));
-    let _n = zwriter
-        .write_all(&commit_bytes)
-        .expect("Failed to write to file");
-    println!("{object_sha}");
-    object_sha
+// A path as given on the command line, in the form the index stores paths:
+// relative to the top of the work tree, which commands run from, with "."
+// naming the whole tree. Paths leading out of the work tree are an error.
+fn pathspec(path: &Path) -> io::Result<Vec<u8>> {
+    use std::path::Component;
+
+    let top = env::current_dir()?;
+    let mut resolved: Vec<&OsStr> = vec![];
+    for component in top.components().chain(path.components()) {
+        match component {
+            Component::Prefix(_) | Component::RootDir => resolved.clear(),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::Normal(name) => resolved.push(name),
+        }
+    }
+    let top: Vec<&OsStr> = top
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        })
+        .collect();
+    if !resolved.starts_with(&top) {
+        return Err(invalid_input(format!(
+            "'{}' is outside repository",
+            path.display()
+        )));
+    }
+    Ok(resolved[top.len()..]
+        .iter()
+        .map(|name| name.as_bytes())
+        .collect::<Vec<_>>()
+        .join(&b'/'))
 }
 
-fn write_blob(file: PathBuf, write: bool) -> Vec<u8> {
-    let mut object_data = fs::read(&file).expect("Failed reading file");
+fn in_pathspec(spec: &[u8], path: &[u8]) -> bool {
+    spec.is_empty()
+        || path
+            .strip_prefix(spec)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(b"/"))
+}
 
-    let size = object_data.len();
+fn is_tracked(index: &Index, path: &[u8], is_dir: bool) -> bool {
+    if is_dir {
+        index.contains_dir(path)
+    } else {
+        !index.stages(path).is_empty()
+    }
+}
 
-    let size_repr = size.to_string();
+// Files and symlinks at or under `spec`, leaving out .git, repositories
+// nested in the working tree and, given `ignore`, untracked paths it ignores
+fn working_files(
+    spec: &[u8],
+    index: &Index,
+    mut ignore: Option<&mut Ignore>,
+    files: &mut Vec<(Vec<u8>, fs::Metadata)>,
+) -> io::Result<()> {
+    let path = if spec.is_empty() {
+        Path::new(".")
+    } else {
+        Path::new(OsStr::from_bytes(spec))
+    };
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.is_dir() {
+        files.push((spec.to_vec(), metadata));
+        return Ok(());
+    }
+    if !spec.is_empty() && path.join(".git").exists() {
+        return Ok(());
+    }
+    for dir_entry in fs::read_dir(path)? {
+        let dir_entry = dir_entry?;
+        let name = dir_entry.file_name();
+        if name == ".git" {
+            continue;
+        }
+        let mut child = spec.to_vec();
+        if !child.is_empty() {
+            child.push(b'/');
+        }
+        child.extend_from_slice(name.as_bytes());
+        if let Some(ignore) = ignore.as_deref_mut() {
+            let is_dir = dir_entry.file_type()?.is_dir();
+            if !is_tracked(index, &child, is_dir) && ignore.is_ignored(&child, is_dir)? {
+                continue;
+            }
+        }
+        working_files(&child, index, ignore.as_deref_mut(), files)?;
+    }
+    Ok(())
+}
 
-    let mut block: Vec<u8> = vec![];
-    block.append(&mut "blob ".as_bytes().to_vec());
-    block.append(&mut size_repr.into_bytes());
-    block.push(0);
-    block.append(&mut object_data);
+// Commits what is staged on top of HEAD and moves the branch HEAD is on, or
+// HEAD itself when detached
+fn commit(odb: &dyn ObjectDatabase, refs: &RefStore, args: CommitArgs) -> io::Result<()> {
+    let config = Config::open(".git")?;
+    let (branch, head) = refs.follow("HEAD")?;
+    let head_commit = match head {
+        Some(head) => match odb.read_object(&head)? {
+            Object::Commit(commit) => Some(commit),
+            other => return Err(invalid_input(format!("HEAD is a {}", other.kind()))),
+        },
+        None => None,
+    };
 
-    let mut hasher = Sha1::new();
+    let (parents, author, template) = match (&head_commit, args.amend) {
+        (Some(old), true) => (
+            old.parents.clone(),
+            Signature::parse(&String::from_utf8_lossy(&old.author))?,
+            old.message.clone(),
+        ),
+        (None, true) => return Err(invalid_input("You have nothing to amend.".to_string())),
+        (_, false) => (head.into_iter().collect(), Signature::author(&config)?, vec![]),
+    };
+    let author = override_author(author, args.author, args.date)?;
+    let committer = Signature::committer(&config)?;
+
+    let (mut index, lock) = Index::lock(".git")?;
+    if args.all {
+        let tracked = AddArgs {
+            all: false,
+            update: true,
+            dry_run: false,
+            verbose: false,
+            force: false,
+            paths: vec![],
+        };
+        stage_paths(odb, &mut index, &tracked)?;
+    }
+    let tree = index.write_tree(odb, b"", false)?;
+    if !args.allow_empty && !args.amend {
+        let unchanged = match &head_commit {
+            Some(parent) => parent.tree == tree,
+            None => tree == Object::Tree(Tree { entries: vec![] }).to_raw().hash(),
+        };
+        if unchanged {
+            drop(lock);
+            println!("nothing to commit");
+            std::process::exit(1);
+        }
+    }
 
-    hasher.update(block.clone());
-    let res = hasher.finalize();
-    let mut object_sha = "".to_string();
-    let mut hash = vec![];
-    for byte in res {
-        hash.push(byte);
-        object_sha += &format!("{byte:02x?}");
+    let message = if args.message.is_empty() && args.file.is_empty() {
+        cleanup_message(&edit_message(&config, &template)?, true)
+    } else {
+        cleanup_message(&commit_message(&args.message, &args.file)?, false)
+    };
+    if message.is_empty() {
+        return Err(invalid_input(
+            "Aborting commit due to empty commit message.".to_string(),
+        ));
     }
 
-    if write {
-        let _ = fs::create_dir(format!(".git/objects/{}", &object_sha[..2]));
-        
-        let file = fs::File::create(format!(
-            "./.git/objects/{}/{}",
-            &object_sha[..2],
-            &object_sha[2..]
-        ))
-        .expect("Failed to open file");
+    let subject = String::from_utf8_lossy(message.split(|&b| b == b'\n').next().unwrap());
+    let subject = subject.into_owned();
+    let id = write_commit(odb, tree, parents, message, &author, &committer);
+    let kind = match (args.amend, &head_commit) {
+        (true, _) => "commit (amend)",
+        (false, None) => "commit (initial)",
+        (false, Some(_)) => "commit",
+    };
+    let expected = head.map_or(Expected::Missing, Expected::Value);
+    refs.update(
+        &branch,
+        &RefValue::Direct(id),
+        expected,
+        &format!("{kind}: {subject}"),
+    )?;
+    // Saves what -a staged, and the trees cached for the next commit
+    index.commit(lock)?;
+
+    let short = ObjectIds::new(odb)?.abbreviate(id, 7);
+    let on = branch.strip_prefix("refs/heads/").unwrap_or("detached HEAD");
+    let root = if head_commit.is_none() { " (root-commit)" } else { "" };
+    println!("[{on}{root} {short}] {subject}");
+    Ok(())
+}
 
+// `--author` and `--date` applied over a signature
+fn override_author(
+    mut author: Signature,
+    identity: Option<String>,
+    date: Option<String>,
+) -> io::Result<Signature> {
+    if let Some(identity) = identity {
+        author.set_identity(&identity)?;
+    }
+    if let Some(date) = date {
+        author.set_date(&date)?;
+    }
+    Ok(author)
+}
+
+// Has the user write a message in COMMIT_EDITMSG, starting from `template`,
+// with GIT_EDITOR, core.editor, VISUAL or EDITOR, falling back to vi
+fn edit_message(config: &Config, template: &[u8]) -> io::Result<Vec<u8>> {
+    let path = Path::new(".git").join("COMMIT_EDITMSG");
+    let mut content = template.to_vec();
+    content.extend_from_slice(
+        b"\n# Please enter the commit message for your changes. Lines starting\n\
+          # with '#' will be ignored, and an empty message aborts the commit.\n",
+    );
+    fs::write(&path, content)?;
+
+    let editor = env::var("GIT_EDITOR")
+        .ok()
+        .or_else(|| config.get("core.editor").map(str::to_string))
+        .or_else(|| env::var("VISUAL").ok())
+        .or_else(|| env::var("EDITOR").ok())
+        .unwrap_or_else(|| "vi".to_string());
+    // ":" is how scripts ask for the message to be taken as it is
+    if editor != ":" {
+        // Through the shell, since editors are often set with arguments
+        let status = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("{editor} \"$@\""))
+            .arg(&editor)
+            .arg(&path)
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "There was a problem with the editor '{editor}'."
+            )));
+        }
+    }
+    fs::read(&path)
+}
 
+// Drops trailing whitespace, runs of blank lines and blank lines at either
+// end, and with `strip_comments`, lines starting with '#'
+fn cleanup_message(message: &[u8], strip_comments: bool) -> Vec<u8> {
+    let mut out = vec![];
+    let mut blank = false;
+    for line in message.split(|&b| b == b'\n') {
+        if strip_comments && line.starts_with(b"#") {
+            continue;
+        }
+        let end = line
+            .iter()
+            .rposition(|b| !b.is_ascii_whitespace())
+            .map_or(0, |last| last + 1);
+        let line = &line[..end];
+        if line.is_empty() {
+            blank = true;
+            continue;
+        }
+        if blank && !out.is_empty() {
+            out.push(b'\n');
+        }
+        blank = false;
+        out.extend_from_slice(line);
+        out.push(b'\n');
+    }
+    out
+}
 
-        let mut zwriter = flate2::write::ZlibEncoder::new(file, flate2::Compression::new(1));
-        let _n = zwriter.write_all(&block).expect("Failed to write to file");
+// Each -m and -F is a paragraph, as git joins them: -m ones get a final
+// newline, files are taken as they are. With neither, stdin is the message.
+fn commit_message(messages: &[String], files: &[PathBuf]) -> io::Result<Vec<u8>> {
+    let mut message = vec![];
+    if messages.is_empty() && files.is_empty() {
+        io::stdin().read_to_end(&mut message)?;
+        return Ok(message);
     }
+    for paragraph in messages {
+        if !message.is_empty() {
+            message.push(b'\n');
+        }
+        message.extend(paragraph.as_bytes());
+        if message.last().is_some_and(|&b| b != b'\n') {
+            message.push(b'\n');
+        }
+    }
+    for file in files {
+        if !message.is_empty() {
+            message.push(b'\n');
+        }
+        match file.to_str() {
+            Some("-") => io::stdin().read_to_end(&mut message)?,
+            _ => File::open(file)
+                .and_then(|mut file| file.read_to_end(&mut message))
+                .map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("could not read log file '{}': {e}", file.display()),
+                    )
+                })?,
+        };
+    }
+    Ok(message)
+}
+
+fn write_commit(
+    odb: &dyn ObjectDatabase,
+    tree: ObjectId,
+    parents: Vec<ObjectId>,
+    message: Vec<u8>,
+    author: &Signature,
+    committer: &Signature,
+) -> ObjectId {
+    let commit = Commit {
+        tree,
+        parents,
+        author: author.to_string().into_bytes(),
+        committer: committer.to_string().into_bytes(),
+        extra_headers: vec![],
+        message,
+    };
+
+    odb.write(&Object::Commit(commit).to_raw())
+        .expect("Failed to write object")
+}
+
+fn write_blob(odb: &dyn ObjectDatabase, file: PathBuf, write: bool) -> io::Result<TreeEntry> {
+    let metadata = fs::symlink_metadata(&file)?;
+    // A symlink is stored as a blob of where it points
+    let object_data = if metadata.file_type().is_symlink() {
+        fs::read_link(&file)?.into_os_string().into_vec()
+    } else {
+        fs::read(&file)?
+    };
+    let object = Object::Blob(Blob { data: object_data }).to_raw();
 
-    let metadata = fs::metadata(&file).unwrap();
+    let oid = if write {
+        odb.write(&object)?
+    } else {
+        object.hash()
+    };
 
-    let mode = if metadata.permissions().mode() & 0o111 == 0 {
+    let mode = if metadata.file_type().is_symlink() {
+        "120000"
+    } else if metadata.permissions().mode() & 0o111 == 0 {
         "100644"
     } else {
         "100755"
     };
-    let name = file.file_name().unwrap().to_str().unwrap();
-    let mut preface = format!("{mode} {name}").into_bytes();
-    preface.push(b'\0');
-    preface.append(&mut hash);
-    preface
+    Ok(TreeEntry {
+        mode: mode.to_string(),
+        name: file.file_name().unwrap_or_default().as_bytes().to_vec(),
+        oid,
+    })
 }
 
-fn write_tree(path: PathBuf, write: bool) -> Vec<u8> {
+fn write_tree(odb: &dyn ObjectDatabase, path: PathBuf, write: bool) -> TreeEntry {
     let dir_ents = fs::read_dir(&path).expect("Failed to get dir ents from tree");
-    let mut output = vec![];
+    let mut builder = TreeBuilder::new();
     for ent in dir_ents {
-        let repr;
         let ent = ent.unwrap();
 
         let ft = ent.file_type().unwrap();
 
-        if ft.is_dir() {
+        let entry = if ft.is_dir() {
             let name = ent.file_name();
             if name == ".git" {
                 continue;
             }
-            repr = write_tree(ent.path(), true);
+            let subtree = write_tree(odb, ent.path(), write);
+            // Git has no way to record an empty directory
+            if subtree.oid == Object::Tree(Tree::default()).to_raw().hash() {
+                continue;
+            }
+            subtree
         } else {
-            repr = write_blob(ent.path(), true);
-        }
-
-        output.push(repr);
-    }
-
-    output.sort_by_key(|input| {
-        let mut key = "".to_string();
-        for byte in input.iter().skip_while(|b| **b != b' ') {
-            key += &byte.to_string();
-        }
-        key
-    });
-
-    let mut object_data = output.concat();
-    let n = object_data.len();
-    let mut object_header: Vec<u8> = format!("tree {n}").bytes().collect();
-    object_header.push(b'\0');
-    let mut hasher = Sha1::new();
-
-    object_header.append(&mut object_data);
-    let object = object_header;
-    hasher.update(&object);
-    let res = hasher.finalize();
-    let mut hash = vec![];
-    let mut object_sha = "".to_string();
-    for byte in res {
-        hash.push(byte);
-        object_sha += &format!("{byte:02x?}");
-    }
-
-    if write {
-        let _ = fs::create_dir(format!(".git/objects/{}", &object_sha[..2]));
-        let file = fs::File::create(format!(
-            "./.git/objects/{}/{}",
-            &object_sha[..2],
-            &object_sha[2..]
-        ))
-        .expect("Failed to open file");
-        eprintln!("Wrote file {:?}", object_sha);
-        let mut zwriter = flate2::write::ZlibEncoder::new(file, flate2::Compression::new(1));
-        let _n = zwriter.write_all(&object).expect("Failed to write to file");
-    };
-
-    let file_name = path.file_name().unwrap().to_str().unwrap();
-
-    // Tree format ret
-    let mut preface: Vec<u8> = format!("40000 {file_name}").bytes().collect();
-    preface.push(b'\0');
-    preface.append(&mut hash);
-    preface
-}
-
-fn tree_to_disk(sha: String, mode: String, name: Option<String>) {
-    println!("Creating {} with name {:?}", sha, name);
-    let file_data = fs::read(format!("./.git/objects/{}/{}", &sha[..2], &sha[2..]))
-        .expect("Failed to open file");
+            write_blob(odb, ent.path(), write).unwrap_or_else(|e| {
+                fatal(format!("unable to index file '{}': {e}", ent.path().display()))
+            })
+        };
 
-    if name.is_some() {
-        fs::create_dir(name.clone().unwrap()).unwrap();
+        builder.push(entry);
     }
 
-    let z = flate2::read::ZlibDecoder::new(&file_data[..]);
-    let mut object_header = vec![];
-    let mut zreader = io::BufReader::new(z);
-
-    let _n = zreader
-        .read_until(0, &mut object_header)
-        .expect("Failed to read null byte");
+    let tree = builder.build().expect("Failed to build tree");
+    let object = Object::Tree(tree).to_raw();
+    let object_sha = if write {
+        odb.write(&object).expect("Failed to write object")
+    } else {
+        object.hash()
+    };
 
-    let _header = String::from_utf8(object_header).expect("Failed to read utf-8");
-    loop {
-        let mut meta_data = vec![];
-        if let Err(_n) = zreader.read_until(b'\0', &mut meta_data) {
-            break;
-        };
-        if meta_data.len() == 0 {
-            break;
-        }
-        meta_data.pop().unwrap();
+    TreeEntry {
+        mode: "40000".to_string(),
+        name: path.file_name().unwrap_or_default().as_bytes().to_vec(),
+        oid: object_sha,
+    }
+}
 
-        let meta = String::from_utf8(meta_data).unwrap();
+// Checks out a tree at `path`, staging each file at its place under
+// `prefix`, and returns the tree as the index caches it
+fn tree_to_disk(
+    odb: &dyn ObjectDatabase,
+    sha: ObjectId,
+    path: &Path,
+    index: &mut Index,
+    prefix: &[u8],
+) -> io::Result<CacheTree> {
+    let tree = match odb.read_object(&sha)? {
+        Object::Tree(tree) => tree,
+        _ => return Err(invalid(format!("not a tree object: {sha}"))),
+    };
 
-        let mut meta_split = meta.split(" ");
-        let object_mode = meta_split.next().expect("Failed to find mode");
-        let object_name = meta_split.next().expect("Failed to find name");
+    fs::create_dir_all(path)?;
 
-        let mut sha_data = vec![0; 20];
-        let _ = zreader.read_exact(&mut sha_data);
-        let mut sha_hex = "".to_string();
-        for byte in sha_data {
-            sha_hex += &format!("{:02x}", byte);
+    let entries_before = index.entries().len();
+    let mut children = vec![];
+    for entry in tree.entries {
+        let child = path.join(OsStr::from_bytes(&entry.name));
+        let mut entry_path = prefix.to_vec();
+        if !entry_path.is_empty() {
+            entry_path.push(b'/');
         }
-        let child = match name {
-            None => object_name.to_string(),
-            Some(ref root) => format!("{}/{}", root, object_name),
-        };
-        if object_mode.starts_with("40000") {
-            tree_to_disk(sha_hex, object_mode.to_string(), Some(child.to_string()));
-        } else {
-            blob_to_disk(sha_hex, object_mode.to_string(), child);
+        entry_path.extend_from_slice(&entry.name);
+        match entry.kind() {
+            ObjectType::Tree => {
+                let subtree = tree_to_disk(odb, entry.oid, &child, index, &entry_path)?;
+                children.push((entry.name, subtree));
+            }
+            // Submodules are checked out as an empty directory
+            ObjectType::Commit => {
+                fs::create_dir(child)?;
+                index.add(IndexEntry::new(entry_path, 0o160000, entry.oid, Stat::default()));
+            }
+            _ => {
+                let mode = match entry.mode.as_str() {
+                    "100755" => 0o100755,
+                    "120000" => 0o120000,
+                    _ => 0o100644,
+                };
+                blob_to_disk(odb, entry.oid, &child, mode)?;
+                let metadata = fs::symlink_metadata(&child)?;
+                let stat = Stat::from_metadata(&metadata);
+                index.add(IndexEntry::new(entry_path, mode, entry.oid, stat));
+            }
         }
     }
+    // Git keeps subtrees ordered by name length first
+    children.sort_by(|(a, _), (b, _)| (a.len(), a).cmp(&(b.len(), b)));
+    Ok(CacheTree {
+        oid: Some(sha),
+        entry_count: index.entries().len() - entries_before,
+        children,
+    })
 }
 
-fn blob_to_disk(sha: String, mode: String, path: String) {
-
-
-let file_data = fs::read(format!("./.git/objects/{}/{}", &sha[..2], &sha[2..]))
-        .expect("Failed to open file");
-
-    let z = flate2::read::ZlibDecoder::new(&file_data[..]);
-    let mut object_header = vec![];
-    let mut zreader = io::BufReader::new(z);
-
-    let _n = zreader
-        .read_until(0, &mut object_header)
-        .expect("Failed to read null byte");
-
-    let _header = String::from_utf8(object_header).expect("Failed to read utf-8");
-
-    let mut buffer = vec![];
-    zreader.read_to_end(&mut buffer).unwrap();
-    fs::write(path, buffer).unwrap();
-
+fn blob_to_disk(odb: &dyn ObjectDatabase, sha: ObjectId, path: &Path, mode: u32) -> io::Result<()> {
+    let object = odb.read(&sha)?;
+    if mode == 0o120000 {
+        return std::os::unix::fs::symlink(OsStr::from_bytes(&object.data), path);
+    }
+    fs::write(path, object.data)?;
+    if mode == 0o100755 {
+        let mut permissions = fs::metadata(path)?.permissions();
+        permissions.set_mode(permissions.mode() | 0o111);
+        fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
 }
 
-fn clone(url: String) -> (String, HashMap<usize, (ObjectType, String, Vec<u8>)>) {
+// Fetches every ref the remote advertises into `pack_file`, returning the
+// refs and the branch its HEAD points at, if it says
+fn clone(url: String, pack_file: &mut File) -> (Vec<(String, ObjectId)>, Option<String>) {
     let service_url = url.clone() + "/info/refs?service=git-upload-pack";
     let upload_url = url + "/git-upload-pack";
     let client = reqwest::blocking::Client::new();
@@ -574,7 +1801,8 @@ fn clone(url: String) -> (String, HashMap<usize, (ObjectType, String, Vec<u8>)>)
     let mut bytes = binding.into_iter();
     let mut end = false;
     let mut cap = false;
-    let mut ref_hash = String::new();
+    let mut refs = vec![];
+    let mut head = None;
     // Read ref advert
     loop {
         let len: Vec<u8> = bytes.by_ref().take(4).collect();
@@ -590,54 +1818,64 @@ fn clone(url: String) -> (String, HashMap<usize, (ObjectType, String, Vec<u8>)>)
         }
 
         let content: Vec<u8> = bytes.by_ref().take(len - 4).collect();
-        if len < 63 {
-            let comment = String::from_utf8(content).unwrap();
+        let mut ref_line = String::from_utf8(content).unwrap();
+        // "# service=git-upload-pack" before the refs
+        if ref_line.starts_with('#') {
             continue;
         }
         if !cap {
-            let nul = content.iter().position(|n| *n == 0).unwrap();
-
-            let mut cap_ref = String::new();
-            content.take(nul as u64).read_to_string(&mut cap_ref);
-
-            let caps: Vec<u8> = content.into_iter().skip(nul).collect();
-            let caps = String::from_utf8(caps).unwrap();
+            let nul = ref_line.find('\0').unwrap();
+            let caps = ref_line.split_off(nul);
+            // symref=HEAD:refs/heads/main says which branch HEAD is on
+            head = caps
+                .split_whitespace()
+                .find_map(|cap| cap.strip_prefix("symref=HEAD:"))
+                .map(str::to_string);
             cap = true;
-        } else {
-            let ref_line = String::from_utf8(content).unwrap();
-
-            ref_hash = ref_line.split(" ").take(1).collect();
         }
+        let (hash, name) = ref_line.trim_end().split_once(' ').unwrap();
+        let hash = hash.parse().expect("Server advertised a bad object id");
+        refs.push((name.to_string(), hash));
     }
+    // Peeled tags are advertised as "<tag>^{}" but are not refs of their own
+    refs.retain(|(name, _)| !name.ends_with("^{}"));
+
+    let mut wants: Vec<ObjectId> = refs.iter().map(|(_, hash)| *hash).collect();
+    wants.sort();
+    wants.dedup();
     let mut body: Vec<u8> = vec![];
+    for (n, hash) in wants.iter().enumerate() {
+        let line = match n {
+            0 => format!("want {hash} multi_ack_detailed side-band-64k thin-pack ofs-delta agent=git/1.8.2\n"),
+            _ => format!("want {hash}\n"),
+        };
+        body.extend(format!("{:04x}{line}", line.len() + 4).bytes());
+    }
     let mut eom = "0000".bytes().collect();
-    let mut want = format!("0077want {ref_hash} multi_ack_detailed side-band-64k thin-pack ofs-delta agent=git/1.8.2\n").bytes().collect();
     let mut done = "0009done\n".bytes().collect();
 
-    body.append(&mut want);
     body.append(&mut eom);
     body.append(&mut done);
 
     let resp = client.post(upload_url).body(body).send().unwrap();
-    let mut resp_data = resp.bytes().unwrap().into_iter();
-    let len_data: Vec<u8> = resp_data.by_ref().take(4).collect();
-    let len = usize::from_str_radix(&String::from_utf8(len_data).unwrap(), 16).unwrap();
-
-    let data: Vec<u8> = resp_data.by_ref().take(len - 4).collect();
+    let mut resp_data = BufReader::new(resp);
+    let len = read_pkt_len(&mut resp_data);
 
-    let mut file = File::create("./tmp.pack").unwrap();
+    let mut _nak = vec![0; len - 4];
+    resp_data.read_exact(&mut _nak).unwrap();
 
+    // Pack data goes straight to disk rather than being held in memory
+    let mut data = vec![];
     loop {
-        let len_data: Vec<u8> = resp_data.by_ref().take(4).collect();
-        let len = usize::from_str_radix(&String::from_utf8(len_data).unwrap(), 16).unwrap();
+        let len = read_pkt_len(&mut resp_data);
         if len == 0 {
             break;
         }
-        let type_val = resp_data.by_ref().next().unwrap();
-        let data: Vec<u8> = resp_data.by_ref().take(len - 5).collect();
-        match type_val {
+        data.resize(len - 4, 0);
+        resp_data.read_exact(&mut data).unwrap();
+        match data[0] {
             1 => {
-                file.write(data.as_slice());
+                pack_file.write_all(&data[1..]).unwrap();
             }
             2 => {}
             3 => {}
@@ -647,199 +1885,245 @@ fn clone(url: String) -> (String, HashMap<usize, (ObjectType, String, Vec<u8>)>)
         }
     }
 
-    file.flush();
-    drop(file);
-    let mut pack_data = vec![];
-    let mut file = File::open("./tmp.pack").unwrap();
-    file.read_to_end(&mut pack_data).unwrap();
-
-    (ref_hash, parse_pack(pack_data))
+    (refs, head)
 }
 
-enum ObjectType {
-    Blob,
-    Commit,
-    Tree,
-    Tag,
-}
-
-fn parse_pack(pack_data: Vec<u8>) -> HashMap<usize, (ObjectType, String, Vec<u8>)> {
-    let mut pack_items: HashMap<usize, (ObjectType, String, Vec<u8>)> = HashMap::new();
-
-    let mut cursor = 0;
-    let mut pack_data = pack_data.into_iter();
-    let pack: Vec<u8> = pack_data.by_ref().take(4).collect();
-    cursor += 4;
-    let version: Vec<u8> = pack_data.by_ref().take(4).collect();
-    cursor += 4;
-    let n_obj_1 = pack_data.by_ref().next().unwrap();
-    let n_obj_2 = pack_data.by_ref().next().unwrap();
-    let n_obj_3 = pack_data.by_ref().next().unwrap();
-    let n_obj_4 = pack_data.by_ref().next().unwrap();
-    cursor += 4;
-    let n_obj = u32::from_le_bytes([n_obj_4, n_obj_3, n_obj_2, n_obj_1]);
-
-    loop {
-        let obj_pos = cursor;
-        let (n, mut item_type, size) = dewey::pack_item(&mut pack_data);
-        cursor += n;
-        let mut sha_bytes: Vec<u8> = vec![];
-        let mut ofs: u128 = 0;
-
-        let mut base_data = vec![];
-        let mut base_hash = String::new();
-        let target_type = match item_type {
-            1..=5 => item_type,
-
-            6 => {
-                // Init: len MSB + 7bit Size
-                let (dofs_n, dofs_ofs) = dewey::delta_offset(&mut pack_data);
-                cursor += dofs_n;
-                ofs = dofs_ofs;
-
-                let base_pos = obj_pos as u128 - ofs;
-
-                let (base_type, hash, data) = pack_items.get(&(base_pos as usize)).unwrap();
-                base_data = data.to_vec();
-                base_hash = hash.to_string();
-                match base_type {
-                    ObjectType::Commit => 1,
-                    ObjectType::Tree => 2,
-                    ObjectType::Blob => 3,
-                    ObjectType::Tag => 4,
-                }
-            }
-            7 => {
-                sha_bytes = pack_data.by_ref().take(20).collect();
-                cursor += 20;
-                //Get base object data and type
-                1
-            }
-            _ => {
-                panic!();
-            }
+// Branches become remote-tracking refs under origin and tags are copied as
+// they are; HEAD gets its own branch, or is left detached if the remote's is
+fn write_clone_refs(
+    refs: &RefStore,
+    remote_refs: &[(String, ObjectId)],
+    remote_head: Option<String>,
+    head: ObjectId,
+    message: &str,
+) -> io::Result<()> {
+    for (name, hash) in remote_refs {
+        let local = match name.strip_prefix("refs/heads/") {
+            Some(branch) => format!("refs/remotes/origin/{branch}"),
+            None if name.starts_with("refs/tags/") => name.clone(),
+            None => continue,
         };
-
-        let bind = pack_data.collect::<Vec<u8>>();
-        let mut zlib = flate2::bufread::ZlibDecoder::new(bind.as_slice());
-
-        let mut d = vec![];
-        let header_len = cursor - obj_pos;
-
-        // Reading object data
-        match zlib.read_to_end(&mut d) {
-            Ok(_) => {}
-            Err(_) => {
-                break;
+        refs.update(&local, &RefValue::Direct(*hash), Expected::Any, message)?;
+    }
+    match remote_head {
+        Some(branch) => {
+            if let Some(short) = branch.strip_prefix("refs/heads/") {
+                let tracking = RefValue::Symbolic(format!("refs/remotes/origin/{short}"));
+                refs.update(
+                    "refs/remotes/origin/HEAD",
+                    &tracking,
+                    Expected::Any,
+                    message,
+                )?;
             }
-        };
-
-        // Dedelta if delta type
-        if item_type == 6 || item_type == 7 {
-            d = dedelta_buf(d, base_data);
-        };
-
-        // Writing lengths
-        let mut in_c = zlib.total_in();
-        cursor += in_c;
-        let out_c = zlib.total_out();
-
-        // Generating object hash
-        let mut object_data: Vec<u8> = vec![];
-        let type_header = match target_type {
-            1 => "commit",
-            2 => "tree",
-            3 => "blob",
-            4 => "tag",
-            _ => panic!(),
-        };
-        let length_string = out_c.to_string();
-
-        object_data.append(&mut type_header.bytes().collect());
-        object_data.push(b' ');
-
-        if item_type == 6 || item_type == 7 {
-            object_data.append(&mut d.len().to_string().bytes().collect());
-        } else {
-            object_data.append(&mut length_string.bytes().collect());
+            // HEAD goes on the branch first so that creating the branch is
+            // what its reflog records
+            let on_branch = RefValue::Symbolic(branch.clone());
+            refs.update("HEAD", &on_branch, Expected::Any, message)?;
+            refs.update(&branch, &RefValue::Direct(head), Expected::Missing, message)
         }
-        object_data.push(0);
-        object_data.append(&mut (d.clone()));
+        None => refs.update("HEAD", &RefValue::Direct(head), Expected::Any, message),
+    }
+}
 
-        let mut hasher = Sha1::new();
-        hasher.update(object_data);
-        let res = hasher.finalize();
-        let mut object_sha = "".to_string();
+fn read_pkt_len(reader: &mut impl Read) -> usize {
+    let mut len_data = [0; 4];
+    reader.read_exact(&mut len_data).unwrap();
+    usize::from_str_radix(std::str::from_utf8(&len_data).unwrap(), 16).unwrap()
+}
 
-        for byte in res {
-            object_sha += &format!("{byte:02x?}");
+#[cfg(test)]
+mod tests {
+    use std::panic;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::object::RawObject;
+    use crate::test_util::TempDir;
+
+    // Commands work on the repository in the current directory, which every
+    // test in the process shares
+    static CWD: Mutex<()> = Mutex::new(());
+
+    // Runs `test` from the top of a new, empty repository
+    fn in_repo(test: impl FnOnce(&ObjectStore)) {
+        let _cwd = CWD.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let dir = TempDir::new();
+        fs::create_dir_all(dir.path().join(".git/objects")).unwrap();
+        fs::create_dir_all(dir.path().join(".git/refs/heads")).unwrap();
+        fs::write(dir.path().join(".git/HEAD"), "ref: refs/heads/main\n").unwrap();
+        let before = env::current_dir().unwrap();
+        env::set_current_dir(dir.path()).unwrap();
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            test(&ObjectStore::open(".git").unwrap())
+        }));
+        env::set_current_dir(before).unwrap();
+        if let Err(panicked) = result {
+            panic::resume_unwind(panicked);
         }
+    }
 
-        let ty = match target_type {
-            1 => ObjectType::Commit,
-            2 => ObjectType::Tree,
-            3 => ObjectType::Blob,
-            4 => ObjectType::Tag,
-            _ => panic!(),
-        };
-
-        pack_items.insert(obj_pos as usize, (ty, object_sha.clone(), d));
-        // Returning the rest of the iterator
-        pack_data = zlib.into_inner().to_vec().into_iter();
-        match item_type {
-            1..=4 => {
-                // Add extra length of object for length header
-                in_c += n;
+    fn write(path: &str, content: &str) {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::write(path, content).unwrap();
+    }
 
-                println!("{object_sha} {type_header}\t{size} {in_c} {obj_pos}")
-            }
-            6 => {
-                println!("{object_sha} {type_header}\t{size} {in_c} {obj_pos} {base_hash}");
-            }
-            7 => {
-                let mut s = String::new();
-                for b in sha_bytes {
-                    s += &format!("{b:02x}");
-                }
-                println!("Type full ref, Obj: {s}, Size: {size}, Bytes read: {in_c}")
-            }
-            _ => panic!(),
+    fn add_args(paths: &[&str], force: bool) -> AddArgs {
+        AddArgs {
+            all: false,
+            update: false,
+            dry_run: false,
+            verbose: false,
+            force,
+            paths: paths.iter().map(PathBuf::from).collect(),
         }
     }
-    pack_items
-}
 
-fn dedelta_buf(delta_buf: Vec<u8>, base_buf: Vec<u8>) -> Vec<u8> {
-    let mut delta_stream = &mut delta_buf.into_iter();
-    let mut new_buf = vec![];
+    fn staged(index: &Index) -> Vec<String> {
+        index
+            .entries()
+            .iter()
+            .map(|entry| String::from_utf8_lossy(&entry.path).into_owned())
+            .collect()
+    }
 
-    let (_n1, size1) = dewey::delta_buf_length(&mut delta_stream);
-    let (_n2, size2) = dewey::delta_buf_length(&mut delta_stream);
-    // Byte pos counter
-    let n = 0;
+    #[test]
+    fn pathspecs_are_relative_to_the_top_of_the_work_tree() {
+        in_repo(|_| {
+            assert_eq!(pathspec(Path::new(".")).unwrap(), b"");
+            assert_eq!(pathspec(Path::new("./a/../b/c")).unwrap(), b"b/c");
+            let top = env::current_dir().unwrap();
+            assert_eq!(pathspec(&top.join("d/e")).unwrap(), b"d/e");
+            for outside in ["..", "../elsewhere", "a/../../b"] {
+                let e = pathspec(Path::new(outside)).unwrap_err();
+                assert_eq!(e.to_string(), format!("'{outside}' is outside repository"));
+            }
+        });
+    }
 
-    while new_buf.len() < size2 as usize {
-        let instruction = delta_stream.next().unwrap();
-        let ins_type = instruction & 128;
+    #[test]
+    fn add_and_rm_reject_paths_outside_the_work_tree() {
+        in_repo(|odb| {
+            write("a.txt", "a\n");
+            let mut index = Index::new(2);
+            let args = add_args(&["a.txt", "../a.txt"], false);
+            let e = stage_paths(odb, &mut index, &args).unwrap_err();
+            assert_eq!(e.to_string(), "'../a.txt' is outside repository");
+            assert!(index.entries().is_empty());
+
+            let args = RmArgs {
+                cached: true,
+                force: false,
+                recursive: false,
+                dry_run: false,
+                quiet: true,
+                paths: vec![PathBuf::from("../a.txt")],
+            };
+            let e = rm(odb, args).unwrap_err();
+            assert_eq!(e.to_string(), "'../a.txt' is outside repository");
+            // The index lock was let go
+            assert!(!Path::new(".git/index.lock").exists());
+        });
+    }
 
-        match ins_type {
-            // Insert
-            0 => {
-                let n_bytes = instruction & 127;
-                let mut bytes = delta_stream.take(n_bytes.into()).collect();
-                new_buf.append(&mut bytes);
+    #[test]
+    fn add_skips_ignored_paths() {
+        in_repo(|odb| {
+            write(".gitignore", "*.log\nbuild/\n");
+            for path in [
+                "a.txt",
+                "debug.log",
+                "build/out.txt",
+                "src/b.txt",
+                "src/trace.log",
+            ] {
+                write(path, path);
             }
-            // Copy
-            128 => {
-                let (src_ofs, n_copy) = dewey::delta_copy_length(delta_stream, instruction);
+            let mut index = Index::new(2);
+            let ignored = stage_paths(odb, &mut index, &add_args(&["."], false)).unwrap();
+            // Only ignored paths named outright are reported
+            assert!(ignored.is_empty());
+            assert_eq!(staged(&index), [".gitignore", "a.txt", "src/b.txt"]);
+
+            let args = add_args(&["debug.log", "build/out.txt", "src/trace.log"], false);
+            let ignored = stage_paths(odb, &mut index, &args).unwrap();
+            assert_eq!(ignored, [&b"build"[..], b"debug.log", b"src/trace.log"]);
+            assert_eq!(staged(&index), [".gitignore", "a.txt", "src/b.txt"]);
+
+            let ignored = stage_paths(odb, &mut index, &add_args(&["build"], true)).unwrap();
+            assert!(ignored.is_empty());
+            assert_eq!(
+                staged(&index),
+                [".gitignore", "a.txt", "build/out.txt", "src/b.txt"]
+            );
+        });
+    }
 
-                let bytes = &base_buf[src_ofs as usize..(src_ofs + n_copy) as usize];
+    #[test]
+    fn listing_or_checking_out_a_blob_is_an_error() {
+        in_repo(|odb| {
+            let blob = odb
+                .write(&RawObject::new(ObjectType::Blob, b"a\n".to_vec()))
+                .unwrap();
+            let listing = LsTree {
+                odb,
+                recursive: false,
+                show_trees: false,
+                format: "%(path)",
+                filters: &[],
+            };
+            let e = listing.list(&mut vec![], blob, b"").unwrap_err();
+            assert_eq!(e.to_string(), format!("not a tree object: {blob}"));
+
+            let mut index = Index::new(2);
+            let e = tree_to_disk(odb, blob, Path::new("out"), &mut index, b"").unwrap_err();
+            assert_eq!(e.to_string(), format!("not a tree object: {blob}"));
+            let e = get_tree_from_commit(odb, &blob).unwrap_err();
+            assert_eq!(e.to_string(), format!("not a commit object: {blob}"));
+        });
+    }
 
-                new_buf.extend_from_slice(bytes);
+    #[test]
+    fn reflog_expire_drops_entries_older_than_the_cutoff() {
+        in_repo(|_| {
+            let refs = RefStore::new(".git");
+            let id = "9c8aaa55820822e67b4743c838458f7693a8f541";
+            write(".git/refs/heads/main", &format!("{id}\n"));
+            let entries: Vec<_> = [100, 200, 300]
+                .iter()
+                .map(|time| {
+                    let line = format!("{id} {id} a <a@b> {time} +0000\tcommit: {time}");
+                    refs::ReflogEntry::parse(&line).unwrap()
+                })
+                .collect();
+            let times = |name: &str| -> Vec<i64> {
+                let log = refs.reflog(name).unwrap();
+                log.iter().map(|entry| entry.who.time).collect()
+            };
+            for name in ["HEAD", "refs/heads/main"] {
+                refs.write_reflog(name, &entries).unwrap();
             }
-            _ => panic!(),
-        }
-    }
 
-    new_buf
+            let main = vec!["main".to_string()];
+            reflog_expire(&refs, Some("never".to_string()), false, main.clone()).unwrap();
+            assert_eq!(times("refs/heads/main"), [100, 200, 300]);
+            reflog_expire(&refs, Some("@250".to_string()), false, main.clone()).unwrap();
+            assert_eq!(times("refs/heads/main"), [300]);
+            assert_eq!(times("HEAD"), [100, 200, 300]);
+
+            // Without --expire, gc.reflogExpire says how long to keep entries
+            write(".git/config", "[gc]\n\treflogExpire = @150\n");
+            reflog_expire(&refs, None, false, vec!["HEAD".to_string()]).unwrap();
+            assert_eq!(times("HEAD"), [200, 300]);
+
+            reflog_expire(&refs, Some("all".to_string()), true, vec![]).unwrap();
+            assert_eq!(times("HEAD"), []);
+            assert_eq!(times("refs/heads/main"), []);
+
+            let e = reflog_expire(&refs, Some("whenever".to_string()), false, main).unwrap_err();
+            assert_eq!(e.to_string(), "bad expiry date whenever");
+        });
+    }
 }
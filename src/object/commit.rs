@@ -0,0 +1,170 @@
+use std::io;
+
+use super::header_str;
+use super::invalid;
+use super::parse_headers;
+use super::write_header;
+use super::ObjectId;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commit {
+    pub tree: ObjectId,
+    pub parents: Vec<ObjectId>,
+    // Kept as bytes, since names are in the commit's encoding
+    pub author: Vec<u8>,
+    pub committer: Vec<u8>,
+    // Anything after the committer line (encoding, mergetag, gpgsig, ...) in
+    // the order it was found.
+    pub extra_headers: Vec<(String, Vec<u8>)>,
+    pub message: Vec<u8>,
+}
+
+impl Commit {
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        let (headers, message) = parse_headers(data)?;
+        let mut headers = headers.into_iter().peekable();
+
+        let tree = match headers.next() {
+            Some((key, value)) if key == "tree" => header_str("tree", &value)?.parse()?,
+            _ => return Err(invalid("commit does not start with a tree")),
+        };
+        let mut parents = vec![];
+        while let Some((_, value)) = headers.next_if(|(key, _)| key == "parent") {
+            parents.push(header_str("parent", &value)?.parse()?);
+        }
+        let author = match headers.next() {
+            Some((key, value)) if key == "author" => value,
+            _ => return Err(invalid("commit has no author")),
+        };
+        let committer = match headers.next() {
+            Some((key, value)) if key == "committer" => value,
+            _ => return Err(invalid("commit has no committer")),
+        };
+
+        Ok(Commit {
+            tree,
+            parents,
+            author,
+            committer,
+            extra_headers: headers.collect(),
+            message,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = vec![];
+        write_header(&mut out, "tree", self.tree.to_hex().as_bytes());
+        for parent in &self.parents {
+            write_header(&mut out, "parent", parent.to_hex().as_bytes());
+        }
+        write_header(&mut out, "author", &self.author);
+        write_header(&mut out, "committer", &self.committer);
+        for (key, value) in &self.extra_headers {
+            write_header(&mut out, key, value);
+        }
+        out.push(b'\n');
+        out.extend_from_slice(&self.message);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Object;
+
+    // A merge with an encoding and a signature, whose blank continuation
+    // line is a lone space
+    const SIGNED_MERGE: &[u8] = b"\
+tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904
+parent 1111111111111111111111111111111111111111
+parent 2222222222222222222222222222222222222222
+author A U Thor <author@example.com> 1700000000 +0100
+committer C O Mitter <committer@example.com> 1700000100 -0500
+encoding ISO-8859-1
+gpgsig -----BEGIN PGP SIGNATURE-----
+ 
+ iQEzBAABCAAdFiEE
+ =abcd
+ -----END PGP SIGNATURE-----
+
+Merge branches
+
+With a body.
+";
+
+    #[test]
+    fn round_trips_multi_line_headers() {
+        let commit = Commit::parse(SIGNED_MERGE).unwrap();
+        assert_eq!(commit.parents.len(), 2);
+        assert_eq!(
+            commit.author,
+            b"A U Thor <author@example.com> 1700000000 +0100"
+        );
+        assert_eq!(
+            commit.extra_headers,
+            [
+                ("encoding".to_string(), b"ISO-8859-1".to_vec()),
+                (
+                    "gpgsig".to_string(),
+                    b"-----BEGIN PGP SIGNATURE-----\n\niQEzBAABCAAdFiEE\n=abcd\n-----END PGP SIGNATURE-----"
+                        .to_vec()
+                ),
+            ]
+        );
+        assert_eq!(commit.message, b"Merge branches\n\nWith a body.\n");
+
+        assert_eq!(commit.serialize(), SIGNED_MERGE);
+        // As hashed by `git hash-object -t commit`
+        assert_eq!(
+            Object::Commit(commit).to_raw().hash().to_hex(),
+            "30588877fe204be64e29e2021df3fdfc8a0392c5"
+        );
+    }
+
+    #[test]
+    fn round_trips_names_in_a_legacy_encoding() {
+        let data = b"\
+tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904
+author Ren\xe9 Fran\xe7ois <rene@example.com> 1700000000 +0100
+committer Ren\xe9 Fran\xe7ois <rene@example.com> 1700000000 +0100
+encoding ISO-8859-1
+
+Caf\xe9
+";
+        let commit = Commit::parse(data).unwrap();
+        assert!(commit.author.starts_with(b"Ren\xe9 Fran\xe7ois <"));
+        assert_eq!(commit.committer, commit.author);
+        assert_eq!(commit.serialize(), data);
+        // As hashed by `git hash-object -t commit`
+        assert_eq!(
+            Object::Commit(commit).to_raw().hash().to_hex(),
+            "78bde8db9d59d9b8effe34372a353e4fe4f6a65e"
+        );
+    }
+
+    #[test]
+    fn round_trips_a_root_commit_without_a_message() {
+        let data = b"\
+tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904
+author A <a@b> 0 +0000
+committer A <a@b> 0 +0000
+
+";
+        let commit = Commit::parse(data).unwrap();
+        assert!(commit.parents.is_empty());
+        assert!(commit.extra_headers.is_empty());
+        assert!(commit.message.is_empty());
+        assert_eq!(commit.serialize(), data);
+    }
+
+    #[test]
+    fn rejects_commits_missing_headers() {
+        let no_author =
+            b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\ncommitter A <a@b> 0 +0000\n\n";
+        assert!(Commit::parse(no_author).is_err());
+        assert!(Commit::parse(b"author A <a@b> 0 +0000\n\n").is_err());
+        // No blank line before the message
+        assert!(Commit::parse(b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n").is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use super::invalid;
+
+/// A SHA-1 object name.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ObjectId([u8; 20]);
+
+impl ObjectId {
+    pub const LEN: usize = 20;
+    pub const HEX_LEN: usize = 40;
+
+    pub fn from_bytes(bytes: [u8; 20]) -> Self {
+        ObjectId(bytes)
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> io::Result<Self> {
+        let bytes: [u8; 20] = bytes
+            .try_into()
+            .map_err(|_| invalid(format!("object id must be 20 bytes, got {}", bytes.len())))?;
+        Ok(ObjectId(bytes))
+    }
+
+    pub fn from_hex(hex: &str) -> io::Result<Self> {
+        if hex.len() != Self::HEX_LEN {
+            return Err(invalid(format!("{hex:?} is not a 40 character object id")));
+        }
+        let mut bytes = [0; 20];
+        hex::decode_to_slice(hex, &mut bytes)
+            .map_err(|_| invalid(format!("{hex:?} is not a hex object id")))?;
+        Ok(ObjectId(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    pub fn to_hex(self) -> String {
+        hex::encode(self.0)
+    }
+
+    /// The first `len` hex digits, as used for short names in output.
+    pub fn abbrev(self, len: usize) -> String {
+        let mut hex = self.to_hex();
+        hex.truncate(len.clamp(4, Self::HEX_LEN));
+        hex
+    }
+
+    // Fan-out directory and file name of the loose object
+    pub fn loose_path(&self) -> (String, String) {
+        let hex = self.to_hex();
+        (hex[..2].to_string(), hex[2..].to_string())
+    }
+}
+
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ObjectId({self})")
+    }
+}
+
+impl FromStr for ObjectId {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        ObjectId::from_hex(s)
+    }
+}
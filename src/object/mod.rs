@@ -0,0 +1,237 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use sha1::Digest;
+use sha1::Sha1;
+
+mod commit;
+mod id;
+mod tag;
+mod tree;
+
+pub use commit::Commit;
+pub use id::ObjectId;
+pub use tag::Tag;
+pub use tree::Tree;
+pub use tree::TreeBuilder;
+pub use tree::TreeEntry;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObjectType {
+    Blob,
+    Commit,
+    Tree,
+    Tag,
+}
+
+impl ObjectType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ObjectType::Blob => "blob",
+            ObjectType::Commit => "commit",
+            ObjectType::Tree => "tree",
+            ObjectType::Tag => "tag",
+        }
+    }
+}
+
+impl fmt::Display for ObjectType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ObjectType {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s {
+            "blob" => Ok(ObjectType::Blob),
+            "commit" => Ok(ObjectType::Commit),
+            "tree" => Ok(ObjectType::Tree),
+            "tag" => Ok(ObjectType::Tag),
+            _ => Err(invalid(format!("unknown object type {s:?}"))),
+        }
+    }
+}
+
+/// An object as stored on disk: its type and the body without the
+/// `"<type> <size>\0"` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawObject {
+    pub kind: ObjectType,
+    pub data: Vec<u8>,
+}
+
+impl RawObject {
+    pub fn new(kind: ObjectType, data: Vec<u8>) -> Self {
+        RawObject { kind, data }
+    }
+
+    /// Splits a decompressed loose object into its header and body.
+    pub fn from_loose(bytes: &[u8]) -> io::Result<Self> {
+        let (kind, size, header_len) = parse_header(bytes)?;
+        let data = &bytes[header_len..];
+        if data.len() != size {
+            return Err(invalid(format!(
+                "object header says {size} bytes but body has {}",
+                data.len()
+            )));
+        }
+        Ok(RawObject::new(kind, data.to_vec()))
+    }
+
+    /// Header followed by body, the form that is hashed and zlib'd to disk.
+    pub fn to_loose(&self) -> Vec<u8> {
+        let mut bytes = header(self.kind, self.data.len());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    pub fn hash(&self) -> ObjectId {
+        let mut hasher = Sha1::new();
+        hasher.update(header(self.kind, self.data.len()));
+        hasher.update(&self.data);
+        ObjectId::from_bytes(hasher.finalize().into())
+    }
+
+    pub fn parse(&self) -> io::Result<Object> {
+        Object::parse(self.kind, &self.data)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blob {
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Object {
+    Blob(Blob),
+    Tree(Tree),
+    Commit(Commit),
+    Tag(Tag),
+}
+
+impl Object {
+    pub fn parse(kind: ObjectType, data: &[u8]) -> io::Result<Self> {
+        Ok(match kind {
+            ObjectType::Blob => Object::Blob(Blob {
+                data: data.to_vec(),
+            }),
+            ObjectType::Tree => Object::Tree(Tree::parse(data)?),
+            ObjectType::Commit => Object::Commit(Commit::parse(data)?),
+            ObjectType::Tag => Object::Tag(Tag::parse(data)?),
+        })
+    }
+
+    pub fn kind(&self) -> ObjectType {
+        match self {
+            Object::Blob(_) => ObjectType::Blob,
+            Object::Tree(_) => ObjectType::Tree,
+            Object::Commit(_) => ObjectType::Commit,
+            Object::Tag(_) => ObjectType::Tag,
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        match self {
+            Object::Blob(blob) => blob.data.clone(),
+            Object::Tree(tree) => tree.serialize(),
+            Object::Commit(commit) => commit.serialize(),
+            Object::Tag(tag) => tag.serialize(),
+        }
+    }
+
+    pub fn to_raw(&self) -> RawObject {
+        RawObject::new(self.kind(), self.serialize())
+    }
+}
+
+pub fn header(kind: ObjectType, size: usize) -> Vec<u8> {
+    let mut header = format!("{kind} {size}").into_bytes();
+    header.push(0);
+    header
+}
+
+// Return is (type, body size, header length including the null byte)
+pub fn parse_header(bytes: &[u8]) -> io::Result<(ObjectType, usize, usize)> {
+    let nul = bytes
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or_else(|| invalid("object header has no null byte"))?;
+    let header =
+        std::str::from_utf8(&bytes[..nul]).map_err(|_| invalid("object header is not utf-8"))?;
+    let (kind, size) = header
+        .split_once(' ')
+        .ok_or_else(|| invalid(format!("malformed object header {header:?}")))?;
+    let size = size
+        .parse()
+        .map_err(|_| invalid(format!("object size {size:?} is not an integer")))?;
+    Ok((kind.parse()?, size, nul + 1))
+}
+
+pub(crate) fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+pub(crate) type Headers = Vec<(String, Vec<u8>)>;
+
+// Commits and tags share the same "key value" header block. Values that span
+// several lines are continued with a leading space, which is stripped here.
+// Keys are ASCII, but values are kept as bytes: a commit with an `encoding`
+// header has its names in that encoding.
+pub(crate) fn parse_headers(data: &[u8]) -> io::Result<(Headers, Vec<u8>)> {
+    let mut headers: Headers = vec![];
+    let mut rest = data;
+    loop {
+        let eol = rest
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or_else(|| invalid("object headers are not terminated by a blank line"))?;
+        let line = &rest[..eol];
+        rest = &rest[eol + 1..];
+        if line.is_empty() {
+            break;
+        }
+        if let Some(continuation) = line.strip_prefix(b" ") {
+            let (_, value) = headers
+                .last_mut()
+                .ok_or_else(|| invalid("continuation line before any header"))?;
+            value.push(b'\n');
+            value.extend_from_slice(continuation);
+            continue;
+        }
+        let space = line.iter().position(|b| *b == b' ').ok_or_else(|| {
+            invalid(format!(
+                "malformed object header {:?}",
+                String::from_utf8_lossy(line)
+            ))
+        })?;
+        let key = &line[..space];
+        if !key.is_ascii() {
+            return Err(invalid("object header name is not ascii"));
+        }
+        let key = String::from_utf8(key.to_vec()).unwrap();
+        headers.push((key, line[space + 1..].to_vec()));
+    }
+    Ok((headers, rest.to_vec()))
+}
+
+pub(crate) fn write_header(out: &mut Vec<u8>, key: &str, value: &[u8]) {
+    out.extend_from_slice(key.as_bytes());
+    out.push(b' ');
+    for &b in value {
+        out.push(b);
+        if b == b'\n' {
+            out.push(b' ');
+        }
+    }
+    out.push(b'\n');
+}
+
+// A header value that is an object id, type or name, so must be text
+pub(crate) fn header_str<'a>(key: &str, value: &'a [u8]) -> io::Result<&'a str> {
+    std::str::from_utf8(value).map_err(|_| invalid(format!("{key} header is not utf-8")))
+}
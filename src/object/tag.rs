@@ -0,0 +1,145 @@
+use std::io;
+
+use super::header_str;
+use super::invalid;
+use super::parse_headers;
+use super::write_header;
+use super::ObjectId;
+use super::ObjectType;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    pub object: ObjectId,
+    pub kind: ObjectType,
+    pub tag: String,
+    // Very old tags were written without a tagger
+    pub tagger: Option<Vec<u8>>,
+    pub extra_headers: Vec<(String, Vec<u8>)>,
+    pub message: Vec<u8>,
+}
+
+impl Tag {
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        let (headers, message) = parse_headers(data)?;
+        let mut headers = headers.into_iter().peekable();
+
+        let object = match headers.next() {
+            Some((key, value)) if key == "object" => header_str("object", &value)?.parse()?,
+            _ => return Err(invalid("tag does not start with an object")),
+        };
+        let kind = match headers.next() {
+            Some((key, value)) if key == "type" => header_str("type", &value)?.parse()?,
+            _ => return Err(invalid("tag has no type")),
+        };
+        let tag = match headers.next() {
+            Some((key, value)) if key == "tag" => header_str("tag", &value)?.to_string(),
+            _ => return Err(invalid("tag has no name")),
+        };
+        let tagger = headers
+            .next_if(|(key, _)| key == "tagger")
+            .map(|(_, value)| value);
+
+        Ok(Tag {
+            object,
+            kind,
+            tag,
+            tagger,
+            extra_headers: headers.collect(),
+            message,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = vec![];
+        write_header(&mut out, "object", self.object.to_hex().as_bytes());
+        write_header(&mut out, "type", self.kind.as_str().as_bytes());
+        write_header(&mut out, "tag", self.tag.as_bytes());
+        if let Some(tagger) = &self.tagger {
+            write_header(&mut out, "tagger", tagger);
+        }
+        for (key, value) in &self.extra_headers {
+            write_header(&mut out, key, value);
+        }
+        out.push(b'\n');
+        out.extend_from_slice(&self.message);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Object;
+
+    const SIGNED_TAG: &[u8] = b"\
+object 1111111111111111111111111111111111111111
+type commit
+tag v1.0
+tagger T Agger <tagger@example.com> 1700000000 +0000
+
+Release 1.0
+-----BEGIN PGP SIGNATURE-----
+abc
+-----END PGP SIGNATURE-----
+";
+
+    #[test]
+    fn round_trips_a_signed_tag() {
+        let tag = Tag::parse(SIGNED_TAG).unwrap();
+        assert_eq!(tag.kind, ObjectType::Commit);
+        assert_eq!(tag.tag, "v1.0");
+        assert_eq!(
+            tag.tagger.as_deref(),
+            Some(&b"T Agger <tagger@example.com> 1700000000 +0000"[..])
+        );
+        assert!(tag.message.starts_with(b"Release 1.0\n-----BEGIN"));
+
+        assert_eq!(tag.serialize(), SIGNED_TAG);
+        // As hashed by `git hash-object -t tag`
+        assert_eq!(
+            Object::Tag(tag).to_raw().hash().to_hex(),
+            "93023dd08df6b73bf71eac218467c9518fd1cb67"
+        );
+    }
+
+    #[test]
+    fn round_trips_a_tag_without_a_tagger() {
+        let data = b"\
+object 1111111111111111111111111111111111111111
+type commit
+tag v0.1
+
+Old tag
+";
+        let tag = Tag::parse(data).unwrap();
+        assert_eq!(tag.tagger, None);
+        assert_eq!(tag.serialize(), data);
+        assert_eq!(
+            Object::Tag(tag).to_raw().hash().to_hex(),
+            "b05b919233945f5ac49cc01564e3f3bae58e2b1a"
+        );
+    }
+
+    #[test]
+    fn round_trips_multi_line_extra_headers() {
+        let tag = Tag {
+            object: "1111111111111111111111111111111111111111".parse().unwrap(),
+            kind: ObjectType::Tree,
+            tag: "t".to_string(),
+            tagger: None,
+            extra_headers: vec![("x-note".to_string(), b"line one\nline two".to_vec())],
+            message: b"msg\n".to_vec(),
+        };
+        let data = tag.serialize();
+        assert!(data.ends_with(b"x-note line one\n line two\n\nmsg\n"));
+        assert_eq!(Tag::parse(&data).unwrap(), tag);
+    }
+
+    #[test]
+    fn rejects_tags_missing_headers() {
+        assert!(Tag::parse(b"type commit\ntag v1\n\n").is_err());
+        assert!(
+            Tag::parse(b"object 1111111111111111111111111111111111111111\ntag v1\n\n").is_err()
+        );
+    }
+}
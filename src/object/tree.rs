@@ -0,0 +1,223 @@
+use std::cmp::Ordering;
+use std::io;
+
+use super::invalid;
+use super::ObjectId;
+use super::ObjectType;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeEntry {
+    // Kept as the octal text from the object so that odd modes such as
+    // "040000" written by old tools survive a round trip.
+    pub mode: String,
+    pub name: Vec<u8>,
+    pub oid: ObjectId,
+}
+
+impl TreeEntry {
+    pub fn is_tree(&self) -> bool {
+        self.mode == "40000" || self.mode == "040000"
+    }
+
+    pub fn kind(&self) -> ObjectType {
+        if self.is_tree() {
+            ObjectType::Tree
+        } else if self.mode == "160000" {
+            // Submodules are recorded as a link to a commit in another repo
+            ObjectType::Commit
+        } else {
+            ObjectType::Blob
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tree {
+    pub entries: Vec<TreeEntry>,
+}
+
+impl Tree {
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        let mut entries = vec![];
+        let mut rest = data;
+        while !rest.is_empty() {
+            let space = rest
+                .iter()
+                .position(|b| *b == b' ')
+                .ok_or_else(|| invalid("tree entry has no mode"))?;
+            let mode = std::str::from_utf8(&rest[..space])
+                .map_err(|_| invalid("tree entry mode is not utf-8"))?
+                .to_string();
+            rest = &rest[space + 1..];
+
+            let nul = rest
+                .iter()
+                .position(|b| *b == 0)
+                .ok_or_else(|| invalid("tree entry name is not terminated"))?;
+            let name = rest[..nul].to_vec();
+            rest = &rest[nul + 1..];
+
+            if rest.len() < ObjectId::LEN {
+                return Err(invalid("tree entry is truncated"));
+            }
+            let oid = ObjectId::from_slice(&rest[..ObjectId::LEN])?;
+            rest = &rest[ObjectId::LEN..];
+
+            entries.push(TreeEntry { mode, name, oid });
+        }
+        Ok(Tree { entries })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = vec![];
+        for entry in &self.entries {
+            out.extend_from_slice(entry.mode.as_bytes());
+            out.push(b' ');
+            out.extend_from_slice(&entry.name);
+            out.push(0);
+            out.extend_from_slice(entry.oid.as_bytes());
+        }
+        out
+    }
+}
+
+/// Gathers the entries of a tree in any order and builds it as git would:
+/// entries sorted by name bytes, with trees compared as if their names ended
+/// in `/`, and no name given twice. Every tree written should go through
+/// one, or it may hash differently from the same tree written by git.
+#[derive(Debug, Default)]
+pub struct TreeBuilder {
+    entries: Vec<TreeEntry>,
+}
+
+impl TreeBuilder {
+    pub fn new() -> Self {
+        TreeBuilder::default()
+    }
+
+    pub fn push(&mut self, entry: TreeEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn build(self) -> io::Result<Tree> {
+        let mut entries = self.entries;
+        for entry in &entries {
+            let name = &entry.name;
+            if name.is_empty()
+                || name == b"."
+                || name == b".."
+                || name.contains(&b'/')
+                || name.contains(&0)
+            {
+                return Err(invalid(format!(
+                    "invalid tree entry name '{}'",
+                    String::from_utf8_lossy(name)
+                )));
+            }
+        }
+
+        // A file and a directory of the same name need not end up next to
+        // each other, so duplicates are looked for by name alone
+        let mut names: Vec<&[u8]> = entries.iter().map(|entry| &entry.name[..]).collect();
+        names.sort_unstable();
+        if let Some(pair) = names.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(invalid(format!(
+                "duplicate entry '{}' in tree",
+                String::from_utf8_lossy(pair[0])
+            )));
+        }
+
+        entries.sort_by(tree_order);
+        Ok(Tree { entries })
+    }
+}
+
+fn tree_order(a: &TreeEntry, b: &TreeEntry) -> Ordering {
+    sort_name(a).cmp(sort_name(b))
+}
+
+// The name an entry sorts by, with a `/` after the names of trees
+fn sort_name(entry: &TreeEntry) -> impl Iterator<Item = &u8> {
+    let slash: &[u8] = if entry.is_tree() { b"/" } else { b"" };
+    entry.name.iter().chain(slash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Object;
+
+    const EMPTY_BLOB: &str = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391";
+    const EMPTY_TREE: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+
+    fn entry(mode: &str, name: &[u8], oid: &str) -> TreeEntry {
+        TreeEntry {
+            mode: mode.to_string(),
+            name: name.to_vec(),
+            oid: oid.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn builds_trees_in_git_order() {
+        let mut builder = TreeBuilder::new();
+        builder.push(entry("120000", b"link", EMPTY_BLOB));
+        builder.push(entry("40000", b"a", EMPTY_TREE));
+        builder.push(entry("100644", b"a.txt", EMPTY_BLOB));
+        builder.push(entry("100755", b"a-b", EMPTY_BLOB));
+        let tree = builder.build().unwrap();
+
+        let names: Vec<&[u8]> = tree.entries.iter().map(|e| &e.name[..]).collect();
+        assert_eq!(names, [&b"a-b"[..], b"a.txt", b"a", b"link"]);
+        // As hashed by `git mktree`
+        assert_eq!(
+            Object::Tree(tree).to_raw().hash().to_hex(),
+            "e3e6535cd76701693c19b7585f8fa846b4e04302"
+        );
+    }
+
+    #[test]
+    fn round_trips_odd_modes_and_names() {
+        let tree = Tree {
+            entries: vec![
+                entry("040000", b"old", EMPTY_TREE),
+                entry("100644", b"caf\xe9", EMPTY_BLOB),
+                entry("160000", b"sub", EMPTY_BLOB),
+            ],
+        };
+        let data = tree.serialize();
+        assert!(data.starts_with(b"040000 old\0"));
+        assert_eq!(Tree::parse(&data).unwrap(), tree);
+    }
+
+    #[test]
+    fn empty_tree_hashes_like_git() {
+        let tree = Tree::parse(b"").unwrap();
+        assert!(tree.entries.is_empty());
+        assert_eq!(Object::Tree(tree).to_raw().hash().to_hex(), EMPTY_TREE);
+    }
+
+    #[test]
+    fn rejects_truncated_entries() {
+        let mut data = Tree {
+            entries: vec![entry("100644", b"a", EMPTY_BLOB)],
+        }
+        .serialize();
+        data.pop();
+        assert!(Tree::parse(&data).is_err());
+        assert!(Tree::parse(b"100644 a").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_and_duplicate_names() {
+        for name in [&b""[..], b".", b"..", b"a/b"] {
+            let mut builder = TreeBuilder::new();
+            builder.push(entry("100644", name, EMPTY_BLOB));
+            assert!(builder.build().is_err());
+        }
+        let mut builder = TreeBuilder::new();
+        builder.push(entry("100644", b"a", EMPTY_BLOB));
+        builder.push(entry("40000", b"a", EMPTY_TREE));
+        assert!(builder.build().is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use super::not_found;
+use super::ObjectDatabase;
+use crate::object::parse_header;
+use crate::object::ObjectId;
+use crate::object::ObjectType;
+use crate::object::RawObject;
+
+/// Objects stored one per file as `objects/xx/yyyy...`, zlib compressed.
+pub struct LooseObjectDb {
+    objects_dir: PathBuf,
+}
+
+impl LooseObjectDb {
+    pub fn new(git_dir: impl AsRef<Path>) -> Self {
+        LooseObjectDb {
+            objects_dir: git_dir.as_ref().join("objects"),
+        }
+    }
+
+    fn path(&self, id: &ObjectId) -> PathBuf {
+        let (dir, file) = id.loose_path();
+        self.objects_dir.join(dir).join(file)
+    }
+
+    fn open(&self, id: &ObjectId) -> io::Result<flate2::read::ZlibDecoder<fs::File>> {
+        match fs::File::open(self.path(id)) {
+            Ok(file) => Ok(flate2::read::ZlibDecoder::new(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(not_found(id)),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl ObjectDatabase for LooseObjectDb {
+    fn read(&self, id: &ObjectId) -> io::Result<RawObject> {
+        let mut bytes = vec![];
+        self.open(id)?.read_to_end(&mut bytes)?;
+        RawObject::from_loose(&bytes)
+    }
+
+    fn write(&self, object: &RawObject) -> io::Result<ObjectId> {
+        let id = object.hash();
+        let path = self.path(&id);
+        if path.exists() {
+            return Ok(id);
+        }
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir)?;
+
+        // Write next to the final name and rename so readers never see a
+        // partially written object.
+        let tmp = dir.join(format!("tmp_obj_{}", std::process::id()));
+        let file = fs::File::create(&tmp)?;
+        let mut zwriter = flate2::write::ZlibEncoder::new(file, flate2::Compression::new(1));
+        zwriter.write_all(&object.to_loose())?;
+        zwriter.finish()?;
+        fs::rename(&tmp, &path)?;
+        Ok(id)
+    }
+
+    fn contains(&self, id: &ObjectId) -> bool {
+        self.path(id).is_file()
+    }
+
+    fn header(&self, id: &ObjectId) -> io::Result<(ObjectType, usize)> {
+        let mut zreader = self.open(id)?;
+        let mut bytes = vec![];
+        let mut buf = [0; 64];
+        while !bytes.contains(&0) {
+            let n = zreader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            bytes.extend_from_slice(&buf[..n]);
+        }
+        let (kind, size, _) = parse_header(&bytes)?;
+        Ok((kind, size))
+    }
+
+    fn iter(&self) -> io::Result<Box<dyn Iterator<Item = ObjectId> + '_>> {
+        let mut ids = vec![];
+        for dir in fs::read_dir(&self.objects_dir)? {
+            let dir = dir?;
+            let prefix = dir.file_name();
+            let prefix = match prefix.to_str() {
+                Some(prefix) if prefix.len() == 2 && dir.file_type()?.is_dir() => prefix,
+                _ => continue,
+            };
+            for file in fs::read_dir(dir.path())? {
+                let name = file?.file_name();
+                if let Some(id) = name
+                    .to_str()
+                    .and_then(|rest| ObjectId::from_hex(&format!("{prefix}{rest}")).ok())
+                {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.sort();
+        Ok(Box::new(ids.into_iter()))
+    }
+}
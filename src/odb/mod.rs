@@ -0,0 +1,88 @@
+use std::io;
+use std::path::Path;
+
+use crate::object::Object;
+use crate::object::ObjectId;
+use crate::object::ObjectType;
+use crate::object::RawObject;
+
+mod loose;
+mod packed;
+
+pub use loose::LooseObjectDb;
+pub use packed::PackedObjectDb;
+
+/// Storage for git objects, addressed by their id.
+pub trait ObjectDatabase {
+    fn read(&self, id: &ObjectId) -> io::Result<RawObject>;
+
+    /// Stores the object and returns its id. Writing an object that is
+    /// already present is not an error.
+    fn write(&self, object: &RawObject) -> io::Result<ObjectId>;
+
+    fn contains(&self, id: &ObjectId) -> bool;
+
+    // Type and size without reading the whole body where the backend allows it
+    fn header(&self, id: &ObjectId) -> io::Result<(ObjectType, usize)>;
+
+    fn iter(&self) -> io::Result<Box<dyn Iterator<Item = ObjectId> + '_>>;
+
+    fn read_object(&self, id: &ObjectId) -> io::Result<Object> {
+        self.read(id)?.parse()
+    }
+}
+
+/// The objects of a repository: loose objects first, then packs. New objects
+/// are always written loose.
+pub struct ObjectStore {
+    loose: LooseObjectDb,
+    packed: PackedObjectDb,
+}
+
+impl ObjectStore {
+    pub fn open(git_dir: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(ObjectStore {
+            loose: LooseObjectDb::new(&git_dir),
+            packed: PackedObjectDb::open(&git_dir)?,
+        })
+    }
+}
+
+impl ObjectDatabase for ObjectStore {
+    fn read(&self, id: &ObjectId) -> io::Result<RawObject> {
+        match self.loose.read(id) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.packed.read(id),
+            result => result,
+        }
+    }
+
+    fn write(&self, object: &RawObject) -> io::Result<ObjectId> {
+        let id = object.hash();
+        if self.packed.contains(&id) {
+            return Ok(id);
+        }
+        self.loose.write(object)
+    }
+
+    fn contains(&self, id: &ObjectId) -> bool {
+        self.loose.contains(id) || self.packed.contains(id)
+    }
+
+    fn header(&self, id: &ObjectId) -> io::Result<(ObjectType, usize)> {
+        match self.loose.header(id) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.packed.header(id),
+            result => result,
+        }
+    }
+
+    fn iter(&self) -> io::Result<Box<dyn Iterator<Item = ObjectId> + '_>> {
+        let mut ids: Vec<ObjectId> = self.loose.iter()?.chain(self.packed.iter()?).collect();
+        ids.sort();
+        ids.dedup();
+        Ok(Box::new(ids.into_iter()))
+    }
+}
+
+pub(crate) fn not_found(id: &ObjectId) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("object {id} not found"))
+}
@@ -0,0 +1,85 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::not_found;
+use super::ObjectDatabase;
+use crate::object::ObjectId;
+use crate::object::ObjectType;
+use crate::object::RawObject;
+use crate::pack::Pack;
+
+/// Read-only access to every `objects/pack/pack-*.pack` that has an index.
+pub struct PackedObjectDb {
+    packs: Vec<Pack>,
+}
+
+impl PackedObjectDb {
+    pub fn open(git_dir: impl AsRef<Path>) -> io::Result<Self> {
+        let pack_dir = git_dir.as_ref().join("objects").join("pack");
+        let mut packs = vec![];
+        let dir_ents = match fs::read_dir(&pack_dir) {
+            Ok(dir_ents) => dir_ents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(PackedObjectDb { packs }),
+            Err(e) => return Err(e),
+        };
+        let mut idx_paths = vec![];
+        for ent in dir_ents {
+            let path = ent?.path();
+            if path.extension().is_some_and(|ext| ext == "idx") {
+                idx_paths.push(path);
+            }
+        }
+        idx_paths.sort();
+        for idx_path in idx_paths {
+            let pack_path = idx_path.with_extension("pack");
+            // An index without its pack is left over from an interrupted write
+            if pack_path.is_file() {
+                packs.push(Pack::open(pack_path, idx_path)?);
+            }
+        }
+        Ok(PackedObjectDb { packs })
+    }
+}
+
+impl ObjectDatabase for PackedObjectDb {
+    fn read(&self, id: &ObjectId) -> io::Result<RawObject> {
+        for pack in &self.packs {
+            if let Some(object) = pack.read(id)? {
+                return Ok(object);
+            }
+        }
+        Err(not_found(id))
+    }
+
+    fn write(&self, _object: &RawObject) -> io::Result<ObjectId> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "objects cannot be added to an existing pack",
+        ))
+    }
+
+    fn contains(&self, id: &ObjectId) -> bool {
+        self.packs.iter().any(|pack| pack.contains(id))
+    }
+
+    fn header(&self, id: &ObjectId) -> io::Result<(ObjectType, usize)> {
+        for pack in &self.packs {
+            if let Some(header) = pack.header(id)? {
+                return Ok(header);
+            }
+        }
+        Err(not_found(id))
+    }
+
+    fn iter(&self) -> io::Result<Box<dyn Iterator<Item = ObjectId> + '_>> {
+        let mut ids: Vec<ObjectId> = self
+            .packs
+            .iter()
+            .flat_map(|pack| pack.index().ids())
+            .collect();
+        ids.sort();
+        ids.dedup();
+        Ok(Box::new(ids.into_iter()))
+    }
+}
@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::object::ObjectType;
+
+/// Recently inflated objects by pack offset, so walking many deltas that share
+/// a base does not inflate the base over and over. Bounded by total bytes and
+/// evicts the least recently used entry first.
+pub struct DeltaBaseCache {
+    limit: usize,
+    used: usize,
+    tick: u64,
+    entries: HashMap<u64, (ObjectType, Rc<Vec<u8>>, u64)>,
+}
+
+impl DeltaBaseCache {
+    // Same default as git's core.deltaBaseCacheLimit
+    pub const DEFAULT_LIMIT: usize = 96 * 1024 * 1024;
+
+    pub fn new(limit: usize) -> Self {
+        DeltaBaseCache {
+            limit,
+            used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, offset: u64) -> Option<(ObjectType, Rc<Vec<u8>>)> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.entries.get_mut(&offset).map(|(kind, data, used)| {
+            *used = tick;
+            (*kind, data.clone())
+        })
+    }
+
+    pub fn insert(&mut self, offset: u64, kind: ObjectType, data: Rc<Vec<u8>>) {
+        if data.len() > self.limit || self.entries.contains_key(&offset) {
+            return;
+        }
+        self.tick += 1;
+        self.used += data.len();
+        self.entries.insert(offset, (kind, data, self.tick));
+        while self.used > self.limit {
+            let oldest = *self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, _, used))| *used)
+                .map(|(offset, _)| offset)
+                .unwrap();
+            let (_, data, _) = self.entries.remove(&oldest).unwrap();
+            self.used -= data.len();
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+use crate::dewey;
+
+// Bytes hashed per block of the base; matches shorter than this are not found
+const BLOCK: usize = 16;
+// Multiplier of the rolling hash over a block
+const PRIME: u32 = 0x01000193;
+// Longest copy we emit, as git does, so that it is written as a size of zero
+const MAX_COPY: usize = 0x10000;
+// Longest run of literal bytes a single insert can carry
+const MAX_INSERT: usize = 127;
+// Candidates kept per hash, so repetitive bases do not make lookups quadratic
+const MAX_CHAIN: usize = 64;
+
+/// Where each block of a delta base starts, keyed by the block's hash. Built
+/// once per base and then matched against any number of targets.
+pub struct DeltaIndex {
+    blocks: HashMap<u32, Vec<usize>>,
+}
+
+impl DeltaIndex {
+    pub fn new(base: &[u8]) -> Self {
+        let mut blocks: HashMap<u32, Vec<usize>> = HashMap::new();
+        for start in (0..base.len() / BLOCK).map(|n| n * BLOCK) {
+            let chain = blocks
+                .entry(block_hash(&base[start..start + BLOCK]))
+                .or_default();
+            if chain.len() < MAX_CHAIN {
+                chain.push(start);
+            }
+        }
+        DeltaIndex { blocks }
+    }
+
+    /// Encodes `target` as copies from `base`, which must be what the index
+    /// was built from, and literal inserts. Gives up once the delta would be
+    /// longer than `max_size`.
+    pub fn delta(&self, base: &[u8], target: &[u8], max_size: usize) -> Option<Vec<u8>> {
+        let mut out = dewey::encode_delta_buf_length(base.len() as u64);
+        out.extend(dewey::encode_delta_buf_length(target.len() as u64));
+        let mut pending = 0;
+        let mut pos = 0;
+        let mut hash = None;
+
+        while pos < target.len() {
+            if target.len() - pos < BLOCK {
+                pos = target.len();
+                break;
+            }
+            let h = match hash {
+                Some(h) => h,
+                None => block_hash(&target[pos..pos + BLOCK]),
+            };
+            if let Some((base_start, len)) = self.longest_match(base, h, &target[pos..]) {
+                // Pull the match backwards over unflushed literals that also match
+                let mut back = 0;
+                while back < pos - pending
+                    && back < base_start
+                    && base[base_start - back - 1] == target[pos - back - 1]
+                {
+                    back += 1;
+                }
+                emit_inserts(&mut out, &target[pending..pos - back]);
+                emit_copies(&mut out, base_start - back, len + back);
+                pos += len;
+                pending = pos;
+                hash = None;
+            } else {
+                if pos + BLOCK < target.len() {
+                    hash = Some(roll(h, target[pos], target[pos + BLOCK]));
+                } else {
+                    hash = None;
+                }
+                pos += 1;
+            }
+            if out.len() + (pos - pending) > max_size {
+                return None;
+            }
+        }
+        emit_inserts(&mut out, &target[pending..pos]);
+        (out.len() <= max_size).then_some(out)
+    }
+
+    fn longest_match(&self, base: &[u8], hash: u32, target: &[u8]) -> Option<(usize, usize)> {
+        let mut best: Option<(usize, usize)> = None;
+        for &start in self.blocks.get(&hash)? {
+            let len = base[start..]
+                .iter()
+                .zip(target)
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len > best.map_or(BLOCK - 1, |(_, best_len)| best_len) {
+                best = Some((start, len));
+            }
+        }
+        best
+    }
+}
+
+fn block_hash(block: &[u8]) -> u32 {
+    block
+        .iter()
+        .fold(0u32, |h, &b| h.wrapping_mul(PRIME).wrapping_add(b as u32))
+}
+
+// Slides the hash of a block one byte forward
+fn roll(hash: u32, out: u8, new: u8) -> u32 {
+    let top = PRIME.wrapping_pow(BLOCK as u32 - 1);
+    hash.wrapping_sub((out as u32).wrapping_mul(top))
+        .wrapping_mul(PRIME)
+        .wrapping_add(new as u32)
+}
+
+fn emit_inserts(out: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_INSERT) {
+        out.push(chunk.len() as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+fn emit_copies(out: &mut Vec<u8>, mut offset: usize, mut len: usize) {
+    while len > 0 {
+        let size = len.min(MAX_COPY);
+        out.extend(dewey::encode_delta_copy(offset as u64, size as u64));
+        offset += size;
+        len -= size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Bytes that don't repeat, so only deliberate matches are found
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed.max(1);
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn round_trip(base: &[u8], target: &[u8]) -> Vec<u8> {
+        let delta = DeltaIndex::new(base)
+            .delta(base, target, usize::MAX)
+            .unwrap();
+        assert_eq!(dewey::dedelta_buf(&delta, base).unwrap(), target);
+        delta
+    }
+
+    #[test]
+    fn identical_objects_are_all_copies() {
+        let base = noise(1000, 1);
+        let delta = round_trip(&base, &base);
+        assert!(delta.len() < 16, "delta is {} bytes", delta.len());
+    }
+
+    #[test]
+    fn edits_round_trip() {
+        let base = noise(5000, 2);
+        let mut inserted = base[..2000].to_vec();
+        inserted.extend_from_slice(b"something new in the middle");
+        inserted.extend_from_slice(&base[2000..]);
+        let delta = round_trip(&base, &inserted);
+        assert!(delta.len() < 100, "delta is {} bytes", delta.len());
+
+        let mut replaced = base.clone();
+        replaced[..10].copy_from_slice(b"0123456789");
+        replaced[4990..].copy_from_slice(b"9876543210");
+        round_trip(&base, &replaced);
+
+        round_trip(&base, &base[1234..4321]);
+        let mut appended = base.clone();
+        appended.extend(noise(300, 3));
+        round_trip(&base, &appended);
+    }
+
+    #[test]
+    fn unrelated_and_tiny_objects_round_trip() {
+        let base = noise(2000, 4);
+        // Literal runs longer than a single insert can carry
+        round_trip(&base, &noise(1000, 5));
+        round_trip(&base, b"short");
+        round_trip(&base, b"");
+        round_trip(b"", b"no base to copy from");
+        round_trip(b"", b"");
+    }
+
+    #[test]
+    fn long_matches_are_split_into_several_copies() {
+        let base = noise(3 * MAX_COPY + 100, 6);
+        let mut target = b"head".to_vec();
+        target.extend_from_slice(&base);
+        let delta = round_trip(&base, &target);
+        assert!(delta.len() < 40, "delta is {} bytes", delta.len());
+    }
+
+    #[test]
+    fn repetitive_objects_round_trip() {
+        let base = vec![b'a'; 10_000];
+        let mut target = vec![b'a'; 12_345];
+        target[5000] = b'b';
+        round_trip(&base, &target);
+    }
+
+    #[test]
+    fn gives_up_past_max_size() {
+        let base = noise(1000, 7);
+        let target = noise(1000, 8);
+        assert_eq!(DeltaIndex::new(&base).delta(&base, &target, 500), None);
+        let exact = round_trip(&base, &target);
+        assert_eq!(
+            DeltaIndex::new(&base).delta(&base, &target, exact.len()),
+            Some(exact)
+        );
+    }
+}
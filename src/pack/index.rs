@@ -0,0 +1,253 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use sha1::Digest;
+use sha1::Sha1;
+
+use crate::object::invalid;
+use crate::object::ObjectId;
+
+const IDX_MAGIC: [u8; 4] = [0xff, b't', b'O', b'c'];
+const FANOUT_LEN: usize = 256 * 4;
+const HEADER_LEN: usize = 8;
+
+/// What the index records about each object in a pack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub oid: ObjectId,
+    pub offset: u64,
+    // Of the entry's bytes as stored in the pack, header included
+    pub crc32: u32,
+}
+
+/// A version 2 `.idx` file, kept in memory and searched in place.
+pub struct PackIndex {
+    data: Vec<u8>,
+    count: usize,
+}
+
+impl PackIndex {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        PackIndex::parse(fs::read(path)?)
+    }
+
+    pub fn parse(data: Vec<u8>) -> io::Result<Self> {
+        if data.len() < HEADER_LEN + FANOUT_LEN || data[..4] != IDX_MAGIC {
+            return Err(invalid("not a version 2 pack index"));
+        }
+        let version = u32::from_be_bytes(data[4..8].try_into().unwrap());
+        if version != 2 {
+            return Err(invalid(format!("unsupported pack index version {version}")));
+        }
+        let count = read_u32(&data, HEADER_LEN + 255 * 4) as usize;
+        // Lookups trust each fanout entry to bound the names below it
+        let fanout = |byte: usize| read_u32(&data, HEADER_LEN + byte * 4);
+        if (1..256).any(|byte| fanout(byte - 1) > fanout(byte)) {
+            return Err(invalid("corrupt pack index fanout"));
+        }
+
+        // names, crcs, small offsets and the two trailing checksums
+        let min_len = HEADER_LEN + FANOUT_LEN + count * (20 + 4 + 4) + 40;
+        if data.len() < min_len {
+            return Err(invalid("pack index is truncated"));
+        }
+        Ok(PackIndex { data, count })
+    }
+
+    fn fanout(&self, byte: usize) -> usize {
+        read_u32(&self.data, HEADER_LEN + byte * 4) as usize
+    }
+
+    fn names_start(&self) -> usize {
+        HEADER_LEN + FANOUT_LEN
+    }
+
+    pub fn id_at(&self, n: usize) -> ObjectId {
+        let start = self.names_start() + n * 20;
+        ObjectId::from_slice(&self.data[start..start + 20]).unwrap()
+    }
+
+    pub fn offset_at(&self, n: usize) -> io::Result<u64> {
+        let small_start = self.names_start() + self.count * 24;
+        let small = read_u32(&self.data, small_start + n * 4);
+        if small & 0x8000_0000 == 0 {
+            return Ok(small as u64);
+        }
+        // Offsets past 2GiB live in a separate table of 64-bit values
+        let large_start = small_start + self.count * 4 + (small & 0x7fff_ffff) as usize * 8;
+        let bytes = self
+            .data
+            .get(large_start..large_start + 8)
+            .ok_or_else(|| invalid("pack index large offset is out of range"))?;
+        Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Position of `id` in the sorted name table.
+    pub fn find(&self, id: &ObjectId) -> Option<usize> {
+        let first = id.as_bytes()[0] as usize;
+        let mut lo = if first == 0 {
+            0
+        } else {
+            self.fanout(first - 1)
+        };
+        let mut hi = self.fanout(first);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.id_at(mid).cmp(id) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+
+    pub fn offset_of(&self, id: &ObjectId) -> io::Result<Option<u64>> {
+        self.find(id).map(|n| self.offset_at(n)).transpose()
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = ObjectId> + '_ {
+        (0..self.count).map(|n| self.id_at(n))
+    }
+}
+
+/// Builds a version 2 index laid out exactly as `git index-pack` does.
+pub fn write_index(entries: &[IndexEntry], pack_checksum: &[u8; 20]) -> Vec<u8> {
+    let mut entries = entries.to_vec();
+    entries.sort_by_key(|entry| entry.oid);
+
+    let mut out = IDX_MAGIC.to_vec();
+    out.extend_from_slice(&2u32.to_be_bytes());
+
+    let mut fanout = [0u32; 256];
+    for entry in &entries {
+        fanout[entry.oid.as_bytes()[0] as usize] += 1;
+    }
+    let mut total = 0;
+    for count in fanout {
+        total += count;
+        out.extend_from_slice(&total.to_be_bytes());
+    }
+
+    for entry in &entries {
+        out.extend_from_slice(entry.oid.as_bytes());
+    }
+    for entry in &entries {
+        out.extend_from_slice(&entry.crc32.to_be_bytes());
+    }
+    let mut large_offsets = vec![];
+    for entry in &entries {
+        if entry.offset > 0x7fff_ffff {
+            let slot = 0x8000_0000 | large_offsets.len() as u32;
+            out.extend_from_slice(&slot.to_be_bytes());
+            large_offsets.push(entry.offset);
+        } else {
+            out.extend_from_slice(&(entry.offset as u32).to_be_bytes());
+        }
+    }
+    for offset in large_offsets {
+        out.extend_from_slice(&offset.to_be_bytes());
+    }
+
+    out.extend_from_slice(pack_checksum);
+    let checksum = Sha1::digest(&out);
+    out.extend_from_slice(&checksum);
+    out
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(oid: &str, offset: u64, crc32: u32) -> IndexEntry {
+        IndexEntry {
+            oid: oid.parse().unwrap(),
+            offset,
+            crc32,
+        }
+    }
+
+    fn checksum(hex: &str) -> [u8; 20] {
+        *ObjectId::from_hex(hex).unwrap().as_bytes()
+    }
+
+    #[test]
+    fn matches_git_index_pack() {
+        // A five object pack written by `git pack-objects`, as listed by
+        // `git show-index`, given in pack order
+        let entries = [
+            entry("3d5b7ddf36ac6df5d9a28e9fbf3aada16dcb39e3", 12, 0xd4592d8e),
+            entry("938de2b7ecba93e15fc0090eb4b9331e3be35210", 105, 0x503660cb),
+            entry("366f17ff507eeda97ee143e1ae7ef7933e52f89b", 197, 0x100bfe2e),
+            entry("fac580e980837fda4244a08ee543e0e0aa16f2e2", 213, 0x00518730),
+            entry("dc43ed8669d01ce53843e2fc282718ebe5d81232", 229, 0x6e9326f7),
+        ];
+        let pack_checksum = checksum("834c39166fdabea61c7bd980ed28bfd370102c15");
+        let data = write_index(&entries, &pack_checksum);
+
+        assert_eq!(data.len(), HEADER_LEN + FANOUT_LEN + 5 * 28 + 40);
+        assert_eq!(&data[data.len() - 40..data.len() - 20], pack_checksum);
+        // The .idx git wrote ends in the same checksum, so it is the same file
+        assert_eq!(
+            data[data.len() - 20..],
+            checksum("74ebfaccf9e77594c3f9eb615f3c65c02cbece81")
+        );
+    }
+
+    #[test]
+    fn round_trips_through_pack_index() {
+        let entries = [
+            entry("ff00000000000000000000000000000000000000", 12, 1),
+            entry("0000000000000000000000000000000000000001", 0x7fff_ffff, 2),
+            entry("8000000000000000000000000000000000000000", 0x8000_0000, 3),
+            entry("8000000000000000000000000000000000000001", 0x1_0000_0000, 4),
+            entry("0000000000000000000000000000000000000000", 40, 5),
+        ];
+        let index = PackIndex::parse(write_index(&entries, &[0; 20])).unwrap();
+
+        let mut sorted: Vec<ObjectId> = entries.iter().map(|entry| entry.oid).collect();
+        sorted.sort();
+        assert_eq!(index.ids().collect::<Vec<_>>(), sorted);
+        for entry in &entries {
+            assert_eq!(index.offset_of(&entry.oid).unwrap(), Some(entry.offset));
+        }
+        let missing = "8000000000000000000000000000000000000002".parse().unwrap();
+        assert_eq!(index.offset_of(&missing).unwrap(), None);
+        // Two offsets need the 64-bit table
+        assert_eq!(
+            index.data.len(),
+            HEADER_LEN + FANOUT_LEN + 5 * 28 + 2 * 8 + 40
+        );
+    }
+
+    #[test]
+    fn empty_index_round_trips() {
+        let index = PackIndex::parse(write_index(&[], &[0; 20])).unwrap();
+        assert_eq!(index.ids().count(), 0);
+        assert_eq!(index.find(&ObjectId::default()), None);
+    }
+
+    #[test]
+    fn rejects_truncated_indexes() {
+        let entries = [entry("3d5b7ddf36ac6df5d9a28e9fbf3aada16dcb39e3", 12, 0)];
+        let mut data = write_index(&entries, &[0; 20]);
+        data.truncate(data.len() - 41);
+        assert!(PackIndex::parse(data).is_err());
+        assert!(PackIndex::parse(b"\xfftOc\0\0\0\x01".to_vec()).is_err());
+    }
+
+    #[test]
+    fn rejects_a_fanout_that_goes_down() {
+        let entries = [entry("3d5b7ddf36ac6df5d9a28e9fbf3aada16dcb39e3", 12, 0)];
+        let mut data = write_index(&entries, &[0; 20]);
+        let at = HEADER_LEN + 0x10 * 4;
+        data[at..at + 4].copy_from_slice(&2u32.to_be_bytes());
+        let err = PackIndex::parse(data).err().unwrap();
+        assert_eq!(err.to_string(), "corrupt pack index fanout");
+    }
+}
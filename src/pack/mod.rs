@@ -0,0 +1,379 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::rc::Rc;
+
+use sha1::Digest;
+use sha1::Sha1;
+
+use crate::dewey;
+use crate::object::invalid;
+use crate::object::ObjectId;
+use crate::object::ObjectType;
+use crate::object::RawObject;
+use crate::odb::ObjectDatabase;
+
+mod cache;
+mod delta;
+mod index;
+mod parse;
+mod write;
+
+pub use cache::DeltaBaseCache;
+pub use delta::DeltaIndex;
+pub use index::write_index;
+pub use index::IndexEntry;
+pub use index::PackIndex;
+pub use parse::parse_pack;
+pub use parse::ParsedPack;
+pub use write::encode_entry;
+pub use write::write_pack;
+pub use write::PackOptions;
+
+pub const PACK_MAGIC: [u8; 4] = *b"PACK";
+
+pub const OBJ_COMMIT: u8 = 1;
+pub const OBJ_TREE: u8 = 2;
+pub const OBJ_BLOB: u8 = 3;
+pub const OBJ_TAG: u8 = 4;
+pub const OBJ_OFS_DELTA: u8 = 6;
+pub const OBJ_REF_DELTA: u8 = 7;
+
+// The most bytes a count or size read from a pack reserves up front. Past
+// that, buffers grow as data actually arrives, so a corrupt header cannot
+// make us allocate gigabytes before anything is read.
+const MAX_PREALLOC: usize = 1 << 20;
+
+pub fn pack_type(kind: ObjectType) -> u8 {
+    match kind {
+        ObjectType::Commit => OBJ_COMMIT,
+        ObjectType::Tree => OBJ_TREE,
+        ObjectType::Blob => OBJ_BLOB,
+        ObjectType::Tag => OBJ_TAG,
+    }
+}
+
+pub fn object_type(pack_type: u8) -> Option<ObjectType> {
+    match pack_type {
+        OBJ_COMMIT => Some(ObjectType::Commit),
+        OBJ_TREE => Some(ObjectType::Tree),
+        OBJ_BLOB => Some(ObjectType::Blob),
+        OBJ_TAG => Some(ObjectType::Tag),
+        _ => None,
+    }
+}
+
+/// Moves a received pack from `tmp_path` to `objects/pack/pack-<checksum>.pack`
+/// next to a freshly built `.idx`, returning the checksum that names them.
+pub fn store_pack(git_dir: &Path, tmp_path: &Path) -> io::Result<ObjectId> {
+    let odb = crate::odb::ObjectStore::open(git_dir)?;
+    let ParsedPack {
+        mut entries,
+        external_bases,
+    } = parse_pack(BufReader::new(File::open(tmp_path)?), &odb)?;
+
+    if !external_bases.is_empty() {
+        complete_thin_pack(tmp_path, &mut entries, &external_bases, &odb)?;
+    }
+    let mut file = File::open(tmp_path)?;
+    let mut checksum = [0; 20];
+    file.seek(SeekFrom::End(-(ObjectId::LEN as i64)))?;
+    file.read_exact(&mut checksum)?;
+    let name = ObjectId::from_bytes(checksum);
+
+    let pack_dir = git_dir.join("objects").join("pack");
+    fs::create_dir_all(&pack_dir)?;
+    // The index goes last so that a pack is never visible without its data
+    fs::rename(tmp_path, pack_dir.join(format!("pack-{name}.pack")))?;
+    write_index_file(
+        &pack_dir.join(format!("pack-{name}.idx")),
+        &entries,
+        &checksum,
+    )?;
+    Ok(name)
+}
+
+// Goes through a temporary file so a reader never sees a partial index
+fn write_index_file(path: &Path, entries: &[IndexEntry], checksum: &[u8; 20]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, write_index(entries, checksum))?;
+    fs::rename(&tmp_path, path)
+}
+
+// Appends the external delta bases as whole objects so the stored pack can be
+// read on its own, then fixes up the object count and trailing checksum.
+fn complete_thin_pack(
+    pack_path: &Path,
+    entries: &mut Vec<IndexEntry>,
+    bases: &[ObjectId],
+    odb: &dyn ObjectDatabase,
+) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(pack_path)?;
+    let mut offset = file.seek(SeekFrom::End(-(ObjectId::LEN as i64)))?;
+    for id in bases {
+        if entries.iter().any(|entry| entry.oid == *id) {
+            continue;
+        }
+        let entry = encode_entry(&odb.read(id)?)?;
+        file.write_all(&entry)?;
+        entries.push(IndexEntry {
+            oid: *id,
+            offset,
+            crc32: crc32fast::hash(&entry),
+        });
+        offset += entry.len() as u64;
+    }
+    file.set_len(offset)?;
+    file.write_all_at(&(entries.len() as u32).to_be_bytes(), 8)?;
+
+    file.seek(SeekFrom::Start(0))?;
+    let mut hasher = Sha1::new();
+    io::copy(&mut (&file).take(offset), &mut hasher)?;
+    file.seek(SeekFrom::End(0))?;
+    file.write_all(&hasher.finalize())?;
+    Ok(())
+}
+
+// Where the data for a pack entry comes from
+pub(crate) enum EntryKind {
+    Base(ObjectType),
+    OfsDelta(u64),
+    RefDelta(ObjectId),
+}
+
+pub(crate) struct EntryHeader {
+    pub kind: EntryKind,
+    pub size: usize,
+    // Bytes between the start of the entry and its zlib stream
+    pub len: u64,
+}
+
+/// Reads the type, size and delta base that precede an entry's data.
+pub(crate) fn read_entry_header(reader: &mut impl Read, offset: u64) -> io::Result<EntryHeader> {
+    let varint_error = |e| match e {
+        dewey::VarintError::Io(e) => e,
+        e => invalid(format!("entry header: {e}")),
+    };
+    let (len, pack_type, size) = dewey::pack_item(reader).map_err(varint_error)?;
+    let mut len = len as u64;
+    let kind = match pack_type {
+        OBJ_OFS_DELTA => {
+            let (ofs_len, ofs) = dewey::delta_offset(reader).map_err(varint_error)?;
+            len += ofs_len as u64;
+            if ofs == 0 {
+                return Err(invalid("delta is its own base"));
+            }
+            let base_offset = offset
+                .checked_sub(ofs)
+                .ok_or_else(|| invalid("delta base offset points before the pack"))?;
+            EntryKind::OfsDelta(base_offset)
+        }
+        OBJ_REF_DELTA => {
+            let mut base = [0; 20];
+            reader
+                .read_exact(&mut base)
+                .map_err(|_| invalid("entry header: input ends in the middle of a base id"))?;
+            len += ObjectId::LEN as u64;
+            EntryKind::RefDelta(ObjectId::from_bytes(base))
+        }
+        _ => EntryKind::Base(
+            object_type(pack_type)
+                .ok_or_else(|| invalid(format!("bad object type {pack_type}")))?,
+        ),
+    };
+    Ok(EntryHeader {
+        kind,
+        size: usize::try_from(size).map_err(|_| invalid("entry is too large"))?,
+        len,
+    })
+}
+
+/// A `.pack` file together with its `.idx`, read on demand.
+pub struct Pack {
+    file: File,
+    index: PackIndex,
+    cache: RefCell<DeltaBaseCache>,
+}
+
+impl Pack {
+    pub fn open(pack_path: impl AsRef<Path>, idx_path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Pack {
+            file: File::open(pack_path)?,
+            index: PackIndex::open(idx_path)?,
+            cache: RefCell::new(DeltaBaseCache::new(DeltaBaseCache::DEFAULT_LIMIT)),
+        })
+    }
+
+    pub fn index(&self) -> &PackIndex {
+        &self.index
+    }
+
+    pub fn contains(&self, id: &ObjectId) -> bool {
+        self.index.find(id).is_some()
+    }
+
+    pub fn read(&self, id: &ObjectId) -> io::Result<Option<RawObject>> {
+        match self.index.offset_of(id)? {
+            Some(offset) => self.read_at(offset).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn header(&self, id: &ObjectId) -> io::Result<Option<(ObjectType, usize)>> {
+        let offset = match self.index.offset_of(id)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        let entry = self.entry_header(offset)?;
+        match entry.kind {
+            EntryKind::Base(kind) => Ok(Some((kind, entry.size))),
+            // The final type is only known once the delta chain is walked
+            _ => self
+                .read_at(offset)
+                .map(|object| Some((object.kind, object.data.len()))),
+        }
+    }
+
+    /// Inflates the entry at `offset`, applying any chain of deltas.
+    pub fn read_at(&self, offset: u64) -> io::Result<RawObject> {
+        let mut deltas = vec![];
+        let mut offset = offset;
+        // A corrupt pack can chain deltas in a circle
+        let mut visited = HashSet::new();
+        let (kind, mut data) = loop {
+            if !visited.insert(offset) {
+                return Err(invalid(format!(
+                    "delta chain loops back to the pack entry at {offset}"
+                )));
+            }
+            if let Some((kind, data)) = self.cache.borrow_mut().get(offset) {
+                break (kind, data);
+            }
+            let entry = self.entry_header(offset)?;
+            let data = self.inflate(offset + entry.len, entry.size)?;
+            match entry.kind {
+                EntryKind::Base(kind) => break (kind, Rc::new(data)),
+                EntryKind::OfsDelta(base_offset) => deltas.push((offset, data, base_offset)),
+                EntryKind::RefDelta(base) => {
+                    let base_offset = self
+                        .index
+                        .offset_of(&base)?
+                        .ok_or_else(|| invalid(format!("delta base {base} is not in this pack")))?;
+                    deltas.push((offset, data, base_offset))
+                }
+            }
+            offset = deltas.last().unwrap().2;
+        };
+        // Everything below the requested object is a base for something
+        self.cache.borrow_mut().insert(offset, kind, data.clone());
+        while let Some((offset, delta, _)) = deltas.pop() {
+            data = Rc::new(
+                dewey::dedelta_buf(&delta, &data)
+                    .map_err(|e| invalid(format!("pack entry at {offset}: {e}")))?,
+            );
+            if !deltas.is_empty() {
+                self.cache.borrow_mut().insert(offset, kind, data.clone());
+            }
+        }
+        Ok(RawObject::new(
+            kind,
+            Rc::try_unwrap(data).unwrap_or_else(|data| data.to_vec()),
+        ))
+    }
+
+    fn entry_header(&self, offset: u64) -> io::Result<EntryHeader> {
+        // Large enough for the longest size varint plus a base id
+        let mut window = [0; 64];
+        let n = self.file.read_at(&mut window, offset)?;
+        read_entry_header(&mut &window[..n], offset)
+            .map_err(|e| invalid(format!("pack entry at {offset}: {e}")))
+    }
+
+    fn inflate(&self, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+        let reader = PackReader {
+            file: &self.file,
+            pos: offset,
+        };
+        let mut data = Vec::with_capacity(size.min(MAX_PREALLOC));
+        flate2::read::ZlibDecoder::new(reader)
+            .take(size as u64)
+            .read_to_end(&mut data)?;
+        if data.len() != size {
+            return Err(invalid(format!("pack entry at {offset} is truncated")));
+        }
+        Ok(data)
+    }
+}
+
+// Sequential reads from a fixed position without moving a shared cursor, so
+// a `Pack` can be read through `&self`.
+struct PackReader<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+impl Read for PackReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    // A REF_DELTA entry, which `PackWriter` has no need to write itself
+    pub(super) fn ref_delta_entry(base: ObjectId, delta: &[u8]) -> Vec<u8> {
+        let mut entry = dewey::encode_pack_item(OBJ_REF_DELTA, delta.len() as u64);
+        entry.extend_from_slice(base.as_bytes());
+        let mut zwriter = flate2::write::ZlibEncoder::new(entry, flate2::Compression::default());
+        zwriter.write_all(delta).unwrap();
+        zwriter.finish().unwrap()
+    }
+
+    #[test]
+    fn rejects_an_ofs_delta_based_on_itself() {
+        let mut header = dewey::encode_pack_item(OBJ_OFS_DELTA, 5);
+        header.extend(dewey::encode_delta_offset(0));
+        assert!(read_entry_header(&mut &header[..], 100).is_err());
+    }
+
+    #[test]
+    fn rejects_ref_deltas_that_form_a_cycle() {
+        let dir = TempDir::new();
+        let a = ObjectId::from_bytes([0xaa; 20]);
+        let b = ObjectId::from_bytes([0xbb; 20]);
+        let delta = [1, 1, 0x01, b'x'];
+
+        let mut pack = vec![];
+        let mut writer = write::PackWriter::new(&mut pack, 3).unwrap();
+        writer.push(a, &ref_delta_entry(b, &delta)).unwrap();
+        writer.push(b, &ref_delta_entry(a, &delta)).unwrap();
+        let c = ObjectId::from_bytes([0xcc; 20]);
+        writer.push(c, &ref_delta_entry(c, &delta)).unwrap();
+        let (checksum, entries) = writer.finish().unwrap();
+        fs::write(dir.path().join("p.pack"), &pack).unwrap();
+        fs::write(dir.path().join("p.idx"), write_index(&entries, &checksum)).unwrap();
+
+        let pack = Pack::open(dir.path().join("p.pack"), dir.path().join("p.idx")).unwrap();
+        for id in [a, b, c] {
+            let e = pack.read(&id).unwrap_err();
+            assert!(e.to_string().contains("loops back"), "{e}");
+        }
+    }
+}
@@ -0,0 +1,554 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::mem;
+use std::rc::Rc;
+
+use sha1::Digest;
+use sha1::Sha1;
+
+use super::read_entry_header;
+use super::EntryKind;
+use super::IndexEntry;
+use super::MAX_PREALLOC;
+use super::PACK_MAGIC;
+use crate::dewey;
+use crate::object::invalid;
+use crate::object::ObjectId;
+use crate::object::ObjectType;
+use crate::object::RawObject;
+use crate::odb::ObjectDatabase;
+
+pub struct ParsedPack {
+    pub entries: Vec<IndexEntry>,
+    // Bases of REF_DELTA entries that came from the repository rather than
+    // the pack itself, i.e. the pack is thin.
+    pub external_bases: Vec<ObjectId>,
+}
+
+// What the first pass learns about an entry without keeping its data
+struct Entry {
+    offset: u64,
+    data_offset: u64,
+    size: usize,
+    crc32: u32,
+    kind: EntryKind,
+    oid: Option<ObjectId>,
+}
+
+// Tracks the position in the pack, the CRC-32 of the bytes consumed since the
+// last reset and the SHA-1 of everything consumed, so entries can be indexed
+// and the trailer checked as they stream past.
+struct PackStream<R> {
+    inner: R,
+    offset: u64,
+    crc32: crc32fast::Hasher,
+    sha1: Sha1,
+}
+
+impl<R: BufRead> PackStream<R> {
+    fn start_entry(&mut self) -> u64 {
+        self.crc32 = crc32fast::Hasher::new();
+        self.offset
+    }
+}
+
+impl<R: BufRead> Read for PackStream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.fill_buf()?.read(buf)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for PackStream<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // Already buffered, so this does no I/O
+        if let Ok(buf) = self.inner.fill_buf() {
+            self.crc32.update(&buf[..amt]);
+            self.sha1.update(&buf[..amt]);
+        }
+        self.offset += amt as u64;
+        self.inner.consume(amt);
+    }
+}
+
+// Counts inflated bytes, hashing them only when we are after an object id
+struct Sink {
+    len: usize,
+    hasher: Option<Sha1>,
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.len += buf.len();
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Walks every entry of a pack, resolving deltas, and returns what is needed
+/// to index it. REF_DELTA bases may appear anywhere in the pack or already be
+/// in `odb`.
+///
+/// The pack is read twice: once front to back to find every entry and hash the
+/// whole objects, then again only for the delta chains. Neither pass holds more
+/// than the objects along a single chain in memory.
+pub fn parse_pack<R: BufRead + Seek>(
+    source: R,
+    odb: &dyn ObjectDatabase,
+) -> io::Result<ParsedPack> {
+    let mut stream = PackStream {
+        inner: source,
+        offset: 0,
+        crc32: crc32fast::Hasher::new(),
+        sha1: Sha1::new(),
+    };
+    let mut header = [0; 12];
+    stream
+        .read_exact(&mut header)
+        .map_err(|_| invalid("pack is too short for its header"))?;
+    if header[..4] != PACK_MAGIC {
+        return Err(invalid("not a pack file"));
+    }
+    let version = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    if version != 2 && version != 3 {
+        return Err(invalid(format!("unsupported pack version {version}")));
+    }
+    let n_obj = u32::from_be_bytes(header[8..12].try_into().unwrap());
+
+    let mut entries =
+        Vec::with_capacity((n_obj as usize).min(MAX_PREALLOC / mem::size_of::<Entry>()));
+    for n in 0..n_obj {
+        let offset = stream.start_entry();
+        let entry = read_entry(&mut stream, offset)
+            .map_err(|e| invalid(format!("pack entry {n} at offset {offset}: {e}")))?;
+        entries.push(entry);
+    }
+
+    let checksum = stream.sha1.clone().finalize();
+    let mut trailer = [0; 20];
+    stream.read_exact(&mut trailer).map_err(|_| {
+        invalid(format!(
+            "pack ends after {n_obj} entries without a checksum"
+        ))
+    })?;
+    if checksum[..] != trailer {
+        return Err(invalid(format!(
+            "pack checksum mismatch: trailer says {}, contents hash to {}",
+            hex::encode(trailer),
+            hex::encode(checksum)
+        )));
+    }
+    if !stream.fill_buf()?.is_empty() {
+        return Err(invalid("pack has junk after its checksum"));
+    }
+
+    let mut resolver = Resolver {
+        source: stream.inner,
+        entries,
+        ofs_children: HashMap::new(),
+        ref_children: HashMap::new(),
+        external_bases: vec![],
+    };
+    resolver.resolve(odb)?;
+    Ok(ParsedPack {
+        entries: resolver
+            .entries
+            .iter()
+            .map(|entry| IndexEntry {
+                oid: entry.oid.unwrap(),
+                offset: entry.offset,
+                crc32: entry.crc32,
+            })
+            .collect(),
+        external_bases: resolver.external_bases,
+    })
+}
+
+// Reads one entry's header and inflates its data, hashing it when it is a
+// whole object
+fn read_entry<R: BufRead>(stream: &mut PackStream<R>, offset: u64) -> io::Result<Entry> {
+    let header = read_entry_header(stream, offset)?;
+    let mut sink = Sink {
+        len: 0,
+        hasher: match header.kind {
+            EntryKind::Base(kind) => {
+                let mut hasher = Sha1::new();
+                hasher.update(crate::object::header(kind, header.size));
+                Some(hasher)
+            }
+            _ => None,
+        },
+    };
+    io::copy(
+        &mut flate2::bufread::ZlibDecoder::new(&mut *stream),
+        &mut sink,
+    )?;
+    if sink.len != header.size {
+        return Err(invalid(format!(
+            "inflates to {} bytes, expected {}",
+            sink.len, header.size
+        )));
+    }
+    Ok(Entry {
+        offset,
+        data_offset: offset + header.len,
+        size: header.size,
+        crc32: stream.crc32.clone().finalize(),
+        kind: header.kind,
+        oid: sink
+            .hasher
+            .map(|hasher| ObjectId::from_bytes(hasher.finalize().into())),
+    })
+}
+
+struct Resolver<R> {
+    source: R,
+    entries: Vec<Entry>,
+    // Deltas waiting on a base, as positions in `entries`
+    ofs_children: HashMap<u64, Vec<usize>>,
+    ref_children: HashMap<ObjectId, Vec<usize>>,
+    external_bases: Vec<ObjectId>,
+}
+
+impl<R: BufRead + Seek> Resolver<R> {
+    fn resolve(&mut self, odb: &dyn ObjectDatabase) -> io::Result<()> {
+        for (n, entry) in self.entries.iter().enumerate() {
+            match entry.kind {
+                EntryKind::OfsDelta(base) => self.ofs_children.entry(base).or_default().push(n),
+                EntryKind::RefDelta(base) => self.ref_children.entry(base).or_default().push(n),
+                EntryKind::Base(_) => {}
+            }
+        }
+
+        for n in 0..self.entries.len() {
+            if let EntryKind::Base(kind) = self.entries[n].kind {
+                let children = self.children(n);
+                if !children.is_empty() {
+                    let data = Rc::new(self.inflate(n)?);
+                    self.apply(kind, data, children)?;
+                }
+            }
+        }
+
+        // Deltas against objects the pack never sent must use ones we already
+        // have. Go in pack order so that a base which is itself a delta in the
+        // pack gets resolved before we would look for it elsewhere. Like git, a
+        // base found in neither place is left for later, as resolving another
+        // external base may yet produce it inside the pack.
+        let mut missing: Vec<(u64, ObjectId)> = self
+            .ref_children
+            .iter()
+            .map(|(id, children)| (self.entries[children[0]].offset, *id))
+            .collect();
+        missing.sort();
+        for (_, id) in missing {
+            let children = match self.ref_children.remove(&id) {
+                Some(children) => children,
+                None => continue,
+            };
+            let base = match odb.read(&id) {
+                Ok(base) => base,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    self.ref_children.insert(id, children);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if !self.entries.iter().any(|entry| entry.oid == Some(id)) {
+                self.external_bases.push(id);
+            }
+            self.apply(base.kind, Rc::new(base.data), children)?;
+        }
+
+        let unresolved = self
+            .ref_children
+            .iter()
+            .map(|(id, children)| (self.entries[children[0]].offset, *id))
+            .min();
+        if let Some((first, id)) = unresolved {
+            return Err(invalid(format!(
+                "REF_DELTA entry at offset {first} needs base {id}, which is neither in the \
+                 pack nor in the repository"
+            )));
+        }
+        if let Some(entry) = self.entries.iter().find(|entry| entry.oid.is_none()) {
+            return Err(invalid(format!(
+                "OFS_DELTA entry at offset {} points at an entry that could not be resolved",
+                entry.offset
+            )));
+        }
+        Ok(())
+    }
+
+    // Deltas that can be applied now that entry `n` is known
+    fn children(&mut self, n: usize) -> Vec<usize> {
+        let entry = &self.entries[n];
+        let mut children = self.ofs_children.remove(&entry.offset).unwrap_or_default();
+        if let Some(oid) = entry.oid {
+            children.extend(self.ref_children.remove(&oid).unwrap_or_default());
+        }
+        children
+    }
+
+    // Applies a base to its deltas and theirs in turn, depth first, so only
+    // one chain of inflated objects is alive at a time
+    fn apply(
+        &mut self,
+        kind: ObjectType,
+        base: Rc<Vec<u8>>,
+        children: Vec<usize>,
+    ) -> io::Result<()> {
+        let mut stack: Vec<(usize, Rc<Vec<u8>>)> =
+            children.into_iter().map(|n| (n, base.clone())).collect();
+        drop(base);
+        while let Some((n, base)) = stack.pop() {
+            let delta = self.inflate(n)?;
+            let data = dewey::dedelta_buf(&delta, &base).map_err(|e| {
+                invalid(format!(
+                    "pack entry at offset {}: {e}",
+                    self.entries[n].offset
+                ))
+            })?;
+            let object = RawObject::new(kind, data);
+            self.entries[n].oid = Some(object.hash());
+            let data = Rc::new(object.data);
+            for child in self.children(n) {
+                stack.push((child, data.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn inflate(&mut self, n: usize) -> io::Result<Vec<u8>> {
+        let entry = &self.entries[n];
+        self.source.seek(SeekFrom::Start(entry.data_offset))?;
+        let mut data = Vec::with_capacity(entry.size.min(MAX_PREALLOC));
+        flate2::bufread::ZlibDecoder::new(&mut self.source)
+            .take(entry.size as u64)
+            .read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::odb::LooseObjectDb;
+    use crate::pack::complete_thin_pack;
+    use crate::pack::encode_entry;
+    use crate::pack::tests::ref_delta_entry;
+    use crate::pack::write::PackWriter;
+    use crate::pack::write_index;
+    use crate::pack::Pack;
+    use crate::test_util::TempDir;
+
+    fn blob(data: &[u8]) -> RawObject {
+        RawObject::new(ObjectType::Blob, data.to_vec())
+    }
+
+    // `base` with `extra` on the end, and the delta that makes it from `base`
+    fn extended(base: &RawObject, extra: &[u8]) -> (RawObject, Vec<u8>) {
+        let len = base.data.len() as u64;
+        let mut delta = dewey::encode_delta_buf_length(len);
+        delta.extend(dewey::encode_delta_buf_length(len + extra.len() as u64));
+        delta.extend(dewey::encode_delta_copy(0, len));
+        delta.push(extra.len() as u8);
+        delta.extend_from_slice(extra);
+        let mut data = base.data.clone();
+        data.extend_from_slice(extra);
+        (blob(&data), delta)
+    }
+
+    // Parses a pack written entry by entry, as (id, entry bytes) pairs
+    fn parse(entries: &[(ObjectId, Vec<u8>)], odb: &dyn ObjectDatabase) -> ParsedPack {
+        let mut pack = vec![];
+        let mut writer = PackWriter::new(&mut pack, entries.len() as u32).unwrap();
+        for (id, entry) in entries {
+            writer.push(*id, entry).unwrap();
+        }
+        writer.finish().unwrap();
+        parse_pack(Cursor::new(pack), odb).unwrap()
+    }
+
+    fn ids(parsed: &ParsedPack) -> Vec<ObjectId> {
+        parsed.entries.iter().map(|entry| entry.oid).collect()
+    }
+
+    #[test]
+    fn resolves_ref_deltas_against_earlier_entries() {
+        let dir = TempDir::new();
+        let base = blob(b"the base of a ref delta\n");
+        let (target, delta) = extended(&base, b"and then some\n");
+        let parsed = parse(
+            &[
+                (base.hash(), encode_entry(&base).unwrap()),
+                (target.hash(), ref_delta_entry(base.hash(), &delta)),
+            ],
+            &LooseObjectDb::new(dir.path()),
+        );
+        assert_eq!(ids(&parsed), [base.hash(), target.hash()]);
+        assert!(parsed.external_bases.is_empty());
+    }
+
+    #[test]
+    fn defers_ref_deltas_until_their_base_turns_up() {
+        let dir = TempDir::new();
+        let base = blob(b"sent last\n");
+        let (middle, first_delta) = extended(&base, b"one\n");
+        let (top, second_delta) = extended(&middle, b"two\n");
+        // Each delta comes before its base, the top one two entries early
+        let parsed = parse(
+            &[
+                (top.hash(), ref_delta_entry(middle.hash(), &second_delta)),
+                (middle.hash(), ref_delta_entry(base.hash(), &first_delta)),
+                (base.hash(), encode_entry(&base).unwrap()),
+            ],
+            &LooseObjectDb::new(dir.path()),
+        );
+        assert_eq!(ids(&parsed), [top.hash(), middle.hash(), base.hash()]);
+        assert!(parsed.external_bases.is_empty());
+    }
+
+    #[test]
+    fn rejects_ref_deltas_with_no_base_anywhere() {
+        let dir = TempDir::new();
+        let base = blob(b"never sent\n");
+        let (target, delta) = extended(&base, b"x");
+        let mut pack = vec![];
+        let mut writer = PackWriter::new(&mut pack, 1).unwrap();
+        writer
+            .push(target.hash(), &ref_delta_entry(base.hash(), &delta))
+            .unwrap();
+        writer.finish().unwrap();
+        let e = parse_pack(Cursor::new(pack), &LooseObjectDb::new(dir.path()))
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("neither in the pack"), "{e}");
+    }
+
+    #[test]
+    fn thin_packs_get_their_bases_appended() {
+        let dir = TempDir::new();
+        let odb = LooseObjectDb::new(dir.path().join("repo"));
+        let base = blob(b"already in the repository\n");
+        odb.write(&base).unwrap();
+        let (target, delta) = extended(&base, b"new on the other side\n");
+
+        let mut pack = vec![];
+        let mut writer = PackWriter::new(&mut pack, 1).unwrap();
+        writer
+            .push(target.hash(), &ref_delta_entry(base.hash(), &delta))
+            .unwrap();
+        writer.finish().unwrap();
+        let pack_path = dir.path().join("thin.pack");
+        fs::write(&pack_path, &pack).unwrap();
+
+        let ParsedPack {
+            mut entries,
+            external_bases,
+        } = parse_pack(Cursor::new(pack), &odb).unwrap();
+        assert_eq!(external_bases, [base.hash()]);
+        complete_thin_pack(&pack_path, &mut entries, &external_bases, &odb).unwrap();
+        assert_eq!(entries.len(), 2);
+
+        // The completed pack stands on its own, count and checksum included
+        let completed = fs::read(&pack_path).unwrap();
+        let empty = LooseObjectDb::new(dir.path().join("empty"));
+        let reparsed = parse_pack(Cursor::new(&completed), &empty).unwrap();
+        assert_eq!(ids(&reparsed), [target.hash(), base.hash()]);
+        assert!(reparsed.external_bases.is_empty());
+
+        let checksum = completed[completed.len() - 20..].try_into().unwrap();
+        let idx_path = dir.path().join("thin.idx");
+        fs::write(&idx_path, write_index(&entries, &checksum)).unwrap();
+        let pack = Pack::open(&pack_path, &idx_path).unwrap();
+        assert_eq!(pack.read(&target.hash()).unwrap(), Some(target));
+        assert_eq!(pack.read(&base.hash()).unwrap(), Some(base));
+    }
+
+    // A two object pack with nothing wrong with it
+    fn valid_pack() -> Vec<u8> {
+        let mut pack = vec![];
+        let mut writer = PackWriter::new(&mut pack, 2).unwrap();
+        writer.add(&blob(b"first\n")).unwrap();
+        writer.add(&blob(b"second\n")).unwrap();
+        writer.finish().unwrap();
+        pack
+    }
+
+    fn parse_error(pack: Vec<u8>) -> String {
+        let dir = TempDir::new();
+        match parse_pack(Cursor::new(pack), &LooseObjectDb::new(dir.path())) {
+            Ok(_) => panic!("corrupt pack parsed"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn valid_pack_parses() {
+        let dir = TempDir::new();
+        let parsed = parse_pack(Cursor::new(valid_pack()), &LooseObjectDb::new(dir.path()));
+        assert_eq!(parsed.unwrap().entries.len(), 2);
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut pack = valid_pack();
+        pack[..4].copy_from_slice(b"KCAP");
+        assert_eq!(parse_error(pack), "not a pack file");
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        let mut pack = valid_pack();
+        pack[4..8].copy_from_slice(&4u32.to_be_bytes());
+        assert_eq!(parse_error(pack), "unsupported pack version 4");
+    }
+
+    #[test]
+    fn rejects_an_object_count_that_does_not_match() {
+        let mut pack = valid_pack();
+        pack[8..12].copy_from_slice(&3u32.to_be_bytes());
+        let e = parse_error(pack);
+        assert!(e.starts_with("pack entry 2 at offset "), "{e}");
+
+        let mut pack = valid_pack();
+        pack[8..12].copy_from_slice(&1u32.to_be_bytes());
+        let e = parse_error(pack);
+        assert!(e.starts_with("pack checksum mismatch"), "{e}");
+    }
+
+    #[test]
+    fn rejects_a_trailer_that_does_not_match() {
+        let mut pack = valid_pack();
+        *pack.last_mut().unwrap() ^= 1;
+        let e = parse_error(pack);
+        assert!(e.starts_with("pack checksum mismatch"), "{e}");
+    }
+
+    #[test]
+    fn rejects_junk_after_the_trailer() {
+        let mut pack = valid_pack();
+        pack.extend_from_slice(b"junk");
+        assert_eq!(parse_error(pack), "pack has junk after its checksum");
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use sha1::Digest;
+use sha1::Sha1;
+
+use super::pack_type;
+use super::write_index_file;
+use super::DeltaIndex;
+use super::IndexEntry;
+use super::OBJ_OFS_DELTA;
+use super::PACK_MAGIC;
+use crate::dewey;
+use crate::object::invalid;
+use crate::object::ObjectId;
+use crate::object::ObjectType;
+use crate::object::RawObject;
+use crate::odb::ObjectDatabase;
+
+/// Writes a version 2 pack of a known number of objects to `out`, keeping
+/// track of what an index of it will need.
+pub struct PackWriter<W: Write> {
+    out: W,
+    sha1: Sha1,
+    offset: u64,
+    count: u32,
+    entries: Vec<IndexEntry>,
+}
+
+impl<W: Write> PackWriter<W> {
+    pub fn new(out: W, count: u32) -> io::Result<Self> {
+        let mut writer = PackWriter {
+            out,
+            sha1: Sha1::new(),
+            offset: 0,
+            count,
+            entries: Vec::with_capacity(count as usize),
+        };
+        let mut header = PACK_MAGIC.to_vec();
+        header.extend_from_slice(&2u32.to_be_bytes());
+        header.extend_from_slice(&count.to_be_bytes());
+        writer.write(&header)?;
+        Ok(writer)
+    }
+
+    /// Appends `object` as a whole, undeltified entry.
+    pub fn add(&mut self, object: &RawObject) -> io::Result<IndexEntry> {
+        let entry = encode_entry(object)?;
+        self.push(object.hash(), &entry)
+    }
+
+    /// Appends the object `oid` as `delta` against the entry at `base_offset`,
+    /// which must already have been written.
+    pub fn add_delta(
+        &mut self,
+        oid: ObjectId,
+        base_offset: u64,
+        delta: &[u8],
+    ) -> io::Result<IndexEntry> {
+        let distance = self
+            .offset
+            .checked_sub(base_offset)
+            .filter(|distance| *distance > 0)
+            .ok_or_else(|| invalid(format!("delta base at {base_offset} is not yet written")))?;
+        let mut entry = dewey::encode_pack_item(OBJ_OFS_DELTA, delta.len() as u64);
+        entry.extend(dewey::encode_delta_offset(distance));
+        let mut zwriter = flate2::write::ZlibEncoder::new(entry, flate2::Compression::default());
+        zwriter.write_all(delta)?;
+        self.push(oid, &zwriter.finish()?)
+    }
+
+    /// Appends an entry the caller encoded, header and all.
+    pub(super) fn push(&mut self, oid: ObjectId, entry: &[u8]) -> io::Result<IndexEntry> {
+        if self.entries.len() == self.count as usize {
+            return Err(invalid(format!(
+                "pack was started for {} objects",
+                self.count
+            )));
+        }
+        let index_entry = IndexEntry {
+            oid,
+            offset: self.offset,
+            crc32: crc32fast::hash(entry),
+        };
+        self.write(entry)?;
+        self.entries.push(index_entry);
+        Ok(index_entry)
+    }
+
+    /// Writes the trailing checksum, returning it along with every entry.
+    pub fn finish(mut self) -> io::Result<([u8; 20], Vec<IndexEntry>)> {
+        if self.entries.len() != self.count as usize {
+            return Err(invalid(format!(
+                "pack was started for {} objects but got {}",
+                self.count,
+                self.entries.len()
+            )));
+        }
+        let checksum: [u8; 20] = self.sha1.clone().finalize().into();
+        self.out.write_all(&checksum)?;
+        self.out.flush()?;
+        Ok((checksum, self.entries))
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes)?;
+        self.sha1.update(bytes);
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+/// An entry header followed by the zlib compressed object.
+pub fn encode_entry(object: &RawObject) -> io::Result<Vec<u8>> {
+    let entry = dewey::encode_pack_item(pack_type(object.kind), object.data.len() as u64);
+    let mut zwriter = flate2::write::ZlibEncoder::new(entry, flate2::Compression::default());
+    zwriter.write_all(&object.data)?;
+    zwriter.finish()
+}
+
+/// How hard `write_pack` looks for deltas.
+pub struct PackOptions {
+    // How many of the preceding objects each one is tried against
+    pub window: usize,
+    // Longest chain of deltas allowed
+    pub depth: usize,
+}
+
+impl Default for PackOptions {
+    fn default() -> Self {
+        // Same as git's pack.window and pack.depth
+        PackOptions {
+            window: 10,
+            depth: 50,
+        }
+    }
+}
+
+// An object recently written, kept around as a possible delta base
+struct Candidate {
+    kind: ObjectType,
+    data: Vec<u8>,
+    index: Option<DeltaIndex>,
+    depth: usize,
+    offset: u64,
+}
+
+/// Packs `objects` from `odb` into `<base_name>-<checksum>.pack` and a
+/// matching `.idx`, returning the checksum. Each object comes with the path it
+/// was found at, if any, which is used to pair it up with likely delta bases.
+/// Repeated ids are only written once.
+pub fn write_pack(
+    odb: &dyn ObjectDatabase,
+    objects: &[(ObjectId, String)],
+    base_name: &Path,
+    options: &PackOptions,
+) -> io::Result<ObjectId> {
+    let mut seen = HashSet::new();
+    let mut order = vec![];
+    for (id, name) in objects {
+        if seen.insert(*id) {
+            let (kind, size) = odb.header(id)?;
+            order.push((kind, name_hash(name), size, *id));
+        }
+    }
+    // Objects of the same type and similar name end up next to each other,
+    // largest first so that deltas mostly remove data
+    order.sort_by(|a, b| (a.0.as_str(), a.1, b.2, a.3).cmp(&(b.0.as_str(), b.1, a.2, b.3)));
+
+    let with_suffix = |suffix: &str| {
+        let mut path = base_name.as_os_str().to_owned();
+        path.push(suffix);
+        PathBuf::from(path)
+    };
+    let tmp_path = with_suffix(".tmp");
+    let file = BufWriter::new(File::create(&tmp_path)?);
+    let mut writer = PackWriter::new(file, order.len() as u32)?;
+    let mut window: VecDeque<Candidate> = VecDeque::with_capacity(options.window + 1);
+    for (kind, _, _, id) in order {
+        let object = odb.read(&id)?;
+        let mut best: Option<(usize, Vec<u8>)> = None;
+        // Not worth it unless the delta is less than half the object
+        let mut max_size = (object.data.len() / 2).saturating_sub(20);
+        for (n, candidate) in window.iter_mut().enumerate().rev() {
+            if candidate.kind != kind || candidate.depth >= options.depth {
+                continue;
+            }
+            if candidate.data.len().abs_diff(object.data.len()) >= max_size {
+                continue;
+            }
+            let index = candidate
+                .index
+                .get_or_insert_with(|| DeltaIndex::new(&candidate.data));
+            if let Some(delta) = index.delta(&candidate.data, &object.data, max_size) {
+                max_size = delta.len().saturating_sub(1);
+                best = Some((n, delta));
+            }
+        }
+
+        let (entry, depth) = match best {
+            Some((n, delta)) => {
+                let base = &window[n];
+                let entry = writer.add_delta(id, base.offset, &delta)?;
+                (entry, base.depth + 1)
+            }
+            None => (writer.add(&object)?, 0),
+        };
+        if options.window > 0 {
+            if window.len() == options.window {
+                window.pop_front();
+            }
+            window.push_back(Candidate {
+                kind,
+                data: object.data,
+                index: None,
+                depth,
+                offset: entry.offset,
+            });
+        }
+    }
+    let (checksum, entries) = writer.finish()?;
+    let name = ObjectId::from_bytes(checksum);
+
+    fs::rename(&tmp_path, with_suffix(&format!("-{name}.pack")))?;
+    write_index_file(&with_suffix(&format!("-{name}.idx")), &entries, &checksum)?;
+    Ok(name)
+}
+
+// Git's pack name hash, which weighs the last characters of a path most so
+// that files with the same extension sort together
+fn name_hash(name: &str) -> u32 {
+    name.bytes()
+        .filter(|c| !c.is_ascii_whitespace())
+        .fold(0u32, |hash, c| (hash >> 2).wrapping_add((c as u32) << 24))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+
+    use super::*;
+    use crate::odb::LooseObjectDb;
+    use crate::pack::parse_pack;
+    use crate::pack::read_entry_header;
+    use crate::pack::write_index;
+    use crate::pack::EntryKind;
+    use crate::pack::Pack;
+    use crate::test_util::TempDir;
+
+    fn lines(changed: usize) -> Vec<u8> {
+        let mut text = String::new();
+        for n in 0..200 {
+            if n == changed {
+                text.push_str(&format!("changed line {n}\n"));
+            } else {
+                text.push_str(&format!("line {n} of a file that changes a little\n"));
+            }
+        }
+        text.into_bytes()
+    }
+
+    #[test]
+    fn written_packs_parse_and_read_back() {
+        let dir = TempDir::new();
+        let odb = LooseObjectDb::new(dir.path());
+        let mut objects = vec![];
+        let mut add = |kind, data: Vec<u8>, name: &str| {
+            let id = odb.write(&RawObject::new(kind, data)).unwrap();
+            objects.push((id, name.to_string()));
+            id
+        };
+        // Versions of one file, which should mostly become deltas
+        for changed in [10, 50, 90, 130] {
+            add(ObjectType::Blob, lines(changed), "file.txt");
+        }
+        let small = add(ObjectType::Blob, b"small\n".to_vec(), "small");
+        let mut tree = b"100644 small\0".to_vec();
+        tree.extend_from_slice(small.as_bytes());
+        let tree = add(ObjectType::Tree, tree, "");
+        let commit =
+            format!("tree {tree}\nauthor a <a@b> 0 +0000\ncommitter a <a@b> 0 +0000\n\nmessage\n");
+        add(ObjectType::Commit, commit.into_bytes(), "");
+
+        let name = write_pack(
+            &odb,
+            &objects,
+            &dir.path().join("pack"),
+            &PackOptions::default(),
+        )
+        .unwrap();
+        let pack_path = dir.path().join(format!("pack-{name}.pack"));
+        let idx_path = dir.path().join(format!("pack-{name}.idx"));
+
+        let parsed = parse_pack(BufReader::new(File::open(&pack_path).unwrap()), &odb).unwrap();
+        let mut parsed_ids: Vec<ObjectId> = parsed.entries.iter().map(|entry| entry.oid).collect();
+        let mut ids: Vec<ObjectId> = objects.iter().map(|(id, _)| *id).collect();
+        parsed_ids.sort();
+        ids.sort();
+        assert_eq!(parsed_ids, ids);
+        assert!(parsed.external_bases.is_empty());
+        assert_eq!(
+            write_index(&parsed.entries, name.as_bytes()),
+            fs::read(&idx_path).unwrap()
+        );
+
+        let data = fs::read(&pack_path).unwrap();
+        let deltas = parsed
+            .entries
+            .iter()
+            .map(|entry| read_entry_header(&mut &data[entry.offset as usize..], entry.offset))
+            .filter(|header| matches!(header.as_ref().unwrap().kind, EntryKind::OfsDelta(_)))
+            .count();
+        assert!(deltas >= 2, "only {deltas} deltas");
+
+        let pack = Pack::open(&pack_path, &idx_path).unwrap();
+        for id in &ids {
+            assert_eq!(pack.read(id).unwrap(), Some(odb.read(id).unwrap()));
+        }
+    }
+}
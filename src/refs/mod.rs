@@ -0,0 +1,644 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::config::Config;
+use crate::ident::Signature;
+use crate::lock::Lock;
+use crate::object::invalid;
+use crate::object::ObjectId;
+
+mod reflog;
+mod transaction;
+
+pub use reflog::ReflogEntry;
+pub use transaction::Transaction;
+
+// Git gives up on symbolic refs nested deeper than this
+const MAX_SYMREF_DEPTH: usize = 5;
+
+/// What a ref holds: an object id, or the name of another ref.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefValue {
+    Direct(ObjectId),
+    Symbolic(String),
+}
+
+/// What a ref must hold for a compare-and-swap update to go ahead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expected {
+    Any,
+    Missing,
+    Value(ObjectId),
+}
+
+impl RefValue {
+    fn to_file(&self) -> String {
+        match self {
+            RefValue::Direct(id) => format!("{id}\n"),
+            RefValue::Symbolic(target) => format!("ref: {target}\n"),
+        }
+    }
+
+    fn parse(name: &str, content: &str) -> io::Result<Self> {
+        let content = content.trim_end();
+        match content.strip_prefix("ref:") {
+            Some(target) => Ok(RefValue::Symbolic(target.trim_start().to_string())),
+            None => content
+                .parse()
+                .map(RefValue::Direct)
+                .map_err(|_| invalid(format!("ref {name} is not an object id: {content}"))),
+        }
+    }
+}
+
+/// The refs of a repository, as loose files under the git dir and lines of
+/// `packed-refs`. Loose refs win over packed ones of the same name.
+pub struct RefStore {
+    git_dir: PathBuf,
+}
+
+impl RefStore {
+    pub fn new(git_dir: impl AsRef<Path>) -> Self {
+        RefStore {
+            git_dir: git_dir.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn read(&self, name: &str) -> io::Result<Option<RefValue>> {
+        check_name(name)?;
+        match fs::read_to_string(self.git_dir.join(name)) {
+            Ok(content) => return RefValue::parse(name, &content).map(Some),
+            // A directory of refs such as `refs/heads` is not itself a ref
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::IsADirectory
+                ) => {}
+            Err(e) => return Err(e),
+        }
+        Ok(self
+            .packed()?
+            .into_iter()
+            .find(|(packed_name, _)| packed_name == name)
+            .map(|(_, id)| RefValue::Direct(id)))
+    }
+
+    /// Follows symbolic refs from `name` to an object id, if there is one.
+    pub fn resolve(&self, name: &str) -> io::Result<Option<ObjectId>> {
+        Ok(self.follow(name)?.1)
+    }
+
+    /// The ref at the end of any symbolic refs starting at `name`, and the
+    /// object id it holds if it exists. `HEAD` on an unborn branch gives the
+    /// branch and no id.
+    pub fn follow(&self, name: &str) -> io::Result<(String, Option<ObjectId>)> {
+        let mut chain = vec![name.to_string()];
+        loop {
+            let current = chain.last().unwrap();
+            let target = match self.read(current)? {
+                Some(RefValue::Direct(id)) => return Ok((current.clone(), Some(id))),
+                Some(RefValue::Symbolic(target)) => target,
+                None => return Ok((current.clone(), None)),
+            };
+            if chain.contains(&target) {
+                chain.push(target);
+                return Err(invalid(format!(
+                    "symbolic ref cycle: {}",
+                    chain.join(" -> ")
+                )));
+            }
+            if chain.len() > MAX_SYMREF_DEPTH {
+                return Err(invalid(format!("symbolic ref {name} is nested too deeply")));
+            }
+            chain.push(target);
+        }
+    }
+
+    /// Every ref whose name starts with `prefix`, sorted by name, with loose
+    /// refs shadowing packed ones.
+    pub fn list(&self, prefix: &str) -> io::Result<Vec<(String, RefValue)>> {
+        let mut refs = vec![];
+        self.list_loose("refs", &mut refs)?;
+        for (name, id) in self.packed()? {
+            if !refs.iter().any(|(loose, _)| *loose == name) {
+                refs.push((name, RefValue::Direct(id)));
+            }
+        }
+        refs.retain(|(name, _)| name.starts_with(prefix));
+        refs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(refs)
+    }
+
+    fn list_loose(&self, dir: &str, refs: &mut Vec<(String, RefValue)>) -> io::Result<()> {
+        let entries = match fs::read_dir(self.git_dir.join(dir)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let Some(file_name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            let name = format!("{dir}/{file_name}");
+            if entry.file_type()?.is_dir() {
+                self.list_loose(&name, refs)?;
+            } else if check_name(&name).is_ok() {
+                let content = fs::read_to_string(entry.path())?;
+                refs.push((name.clone(), RefValue::parse(&name, &content)?));
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes to any number of refs, made together or not at all.
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    /// Points `name` itself, not what it refers to, at `new` if it currently
+    /// holds what `expected` says, and records the move with `message` in its
+    /// reflog.
+    pub fn update(
+        &self,
+        name: &str,
+        new: &RefValue,
+        expected: Expected,
+        message: &str,
+    ) -> io::Result<()> {
+        let mut transaction = self.transaction();
+        transaction.update(name, new.clone(), expected);
+        transaction.commit(message)
+    }
+
+    /// Removes `name`, loose and packed, along with its reflog if it holds
+    /// what `expected` says.
+    pub fn delete(&self, name: &str, expected: Expected) -> io::Result<()> {
+        let mut transaction = self.transaction();
+        transaction.delete(name, expected);
+        transaction.commit("")
+    }
+
+    /// The reflog of `name`, oldest entry first. Refs without one have an
+    /// empty log.
+    pub fn reflog(&self, name: &str) -> io::Result<Vec<ReflogEntry>> {
+        check_name(name)?;
+        let content = match fs::read_to_string(self.log_path(name)) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        content
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(ReflogEntry::parse)
+            .collect()
+    }
+
+    /// Replaces the reflog of `name` with `entries`, as expiring it does.
+    pub fn write_reflog(&self, name: &str, entries: &[ReflogEntry]) -> io::Result<()> {
+        check_name(name)?;
+        let lock = Lock::acquire(self.log_path(name))?;
+        let content: String = entries.iter().map(ReflogEntry::to_string).collect();
+        lock.commit(content.as_bytes())
+    }
+
+    fn log_path(&self, name: &str) -> PathBuf {
+        self.git_dir.join("logs").join(name)
+    }
+
+    // Opens the reflog of `name` for an entry about it moving from `old` to
+    // `new`, if it should have one: git keeps them for HEAD, branches,
+    // remote-tracking refs and notes unless core.logAllRefUpdates says
+    // otherwise, and for any ref that has one
+    fn open_log(
+        &self,
+        name: &str,
+        old: Option<ObjectId>,
+        new: Option<ObjectId>,
+        message: &str,
+    ) -> io::Result<Option<(fs::File, ReflogEntry)>> {
+        if old.is_none() && new.is_none() {
+            return Ok(None);
+        }
+        let path = self.log_path(name);
+        let config = Config::open(&self.git_dir)?;
+        let setting = config
+            .get("core.logAllRefUpdates")
+            .map(str::to_ascii_lowercase);
+        let wanted = match setting.as_deref() {
+            Some("always") => true,
+            Some("false" | "no" | "off" | "0") => false,
+            _ => {
+                name == "HEAD"
+                    || ["refs/heads/", "refs/remotes/", "refs/notes/"]
+                        .iter()
+                        .any(|prefix| name.starts_with(prefix))
+            }
+        };
+        if !wanted && !path.is_file() {
+            return Ok(None);
+        }
+        let entry = ReflogEntry {
+            old: old.unwrap_or_default(),
+            new: new.unwrap_or_default(),
+            who: Signature::committer(&config)?,
+            // Entries are one line each
+            message: message.split_whitespace().collect::<Vec<_>>().join(" "),
+        };
+        fs::create_dir_all(path.parent().unwrap())?;
+        let log = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        Ok(Some((log, entry)))
+    }
+
+    // Takes the lock on a ref, for changes that must see it unchanged
+    // between checking and writing
+    fn lock(&self, name: &str) -> io::Result<Lock> {
+        check_name(name)?;
+        Lock::acquire(self.git_dir.join(name))
+    }
+
+    // Fails unless `name` holds what `expected` says, following it if it is
+    // symbolic
+    fn check_expected(&self, name: &str, expected: Expected) -> io::Result<()> {
+        let current = match self.read(name)? {
+            Some(RefValue::Direct(id)) => Some(id),
+            Some(RefValue::Symbolic(_)) => self.resolve(name)?,
+            None => None,
+        };
+        let ok = match expected {
+            Expected::Any => true,
+            Expected::Missing => current.is_none(),
+            Expected::Value(id) => current == Some(id),
+        };
+        if ok {
+            return Ok(());
+        }
+        let current = current.map_or("nothing".to_string(), |id| id.to_string());
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            match expected {
+                Expected::Missing => format!("ref {name} already exists at {current}"),
+                _ => format!("ref {name} is at {current}, not the expected value"),
+            },
+        ))
+    }
+
+    // Locks packed-refs and writes it to the lock without `names` and the
+    // peeled lines that may follow them, for the caller to commit
+    fn lock_packed_without(&self, names: &[&str]) -> io::Result<Lock> {
+        let path = self.git_dir.join("packed-refs");
+        let mut lock = Lock::acquire(&path)?;
+        let content = fs::read_to_string(&path)?;
+        let mut kept = String::new();
+        let mut dropping = false;
+        for line in content.lines() {
+            if line.starts_with('^') && dropping {
+                continue;
+            }
+            dropping = line
+                .split_once(' ')
+                .is_some_and(|(_, name)| names.contains(&name));
+            if !dropping {
+                kept.push_str(line);
+                kept.push('\n');
+            }
+        }
+        lock.write(kept.as_bytes())?;
+        Ok(lock)
+    }
+
+    // Drops the directories a deleted ref and its reflog leave empty, as git
+    // does, short of `refs` itself and the ones directly in it such as
+    // `refs/heads`
+    fn prune_empty_dirs(&self, name: &str) {
+        let git_dir = &self.git_dir;
+        for (root, mut dir) in [
+            (git_dir.join("refs"), git_dir.join(name)),
+            (git_dir.join("logs/refs"), self.log_path(name)),
+        ] {
+            let prunable = |dir: &Path| {
+                dir.strip_prefix(&root)
+                    .is_ok_and(|rest| rest.components().count() > 1)
+            };
+            while dir.pop() && prunable(&dir) {
+                if fs::remove_dir(&dir).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// The full name a short name like `main` or `v1.0` refers to, tried in
+    /// the same order as git.
+    pub fn dwim(&self, short: &str) -> io::Result<Option<String>> {
+        for full in [
+            short.to_string(),
+            format!("refs/{short}"),
+            format!("refs/tags/{short}"),
+            format!("refs/heads/{short}"),
+            format!("refs/remotes/{short}"),
+            format!("refs/remotes/{short}/HEAD"),
+        ] {
+            // Outside refs/ only names like HEAD and FETCH_HEAD are refs;
+            // anything else there is git's own files, such as config
+            let root_level = !full.starts_with("refs/");
+            if check_name(&full).is_err() || root_level && !is_root_ref_syntax(&full) {
+                continue;
+            }
+            if self.resolve(&full)?.is_some() {
+                return Ok(Some(full));
+            }
+        }
+        Ok(None)
+    }
+
+    fn packed(&self) -> io::Result<Vec<(String, ObjectId)>> {
+        let content = match fs::read_to_string(self.git_dir.join("packed-refs")) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut refs = vec![];
+        for line in content.lines() {
+            // The header and the peeled ids of annotated tags
+            if line.starts_with('#') || line.starts_with('^') {
+                continue;
+            }
+            let (id, name) = line
+                .split_once(' ')
+                .ok_or_else(|| invalid(format!("bad packed-refs line: {line}")))?;
+            let id = id
+                .parse()
+                .map_err(|_| invalid(format!("bad packed-refs line: {line}")))?;
+            refs.push((name.to_string(), id));
+        }
+        Ok(refs)
+    }
+}
+
+// All uppercase and underscores, as `HEAD` and `ORIG_HEAD` are
+fn is_root_ref_syntax(name: &str) -> bool {
+    name.bytes().all(|b| b.is_ascii_uppercase() || b == b'_')
+}
+
+// Enough of git's ref name rules to keep lookups inside the git dir
+fn check_name(name: &str) -> io::Result<()> {
+    let bad = name.is_empty()
+        || name.starts_with('/')
+        || name.ends_with('/')
+        || name.ends_with(".lock")
+        || name.contains("..")
+        || name.contains("//")
+        || name.contains("@{")
+        || name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c));
+    if bad {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{name} is not a valid ref name"),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const A: &str = "1111111111111111111111111111111111111111";
+    const B: &str = "2222222222222222222222222222222222222222";
+
+    fn write(dir: &TempDir, name: &str, content: &str) {
+        let path = dir.path().join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn dwim_skips_git_files_for_lowercase_names() {
+        let dir = TempDir::new();
+        write(&dir, "config", "[core]\n\tbare = false\n");
+        write(&dir, "index", "DIRC");
+        write(&dir, "HEAD", "ref: refs/heads/main\n");
+        write(&dir, "ORIG_HEAD", &format!("{A}\n"));
+        for name in ["config", "index", "main"] {
+            write(&dir, &format!("refs/heads/{name}"), &format!("{A}\n"));
+        }
+        let refs = RefStore::new(dir.path());
+
+        assert_eq!(
+            refs.dwim("config").unwrap().as_deref(),
+            Some("refs/heads/config")
+        );
+        assert_eq!(
+            refs.dwim("index").unwrap().as_deref(),
+            Some("refs/heads/index")
+        );
+        assert_eq!(refs.dwim("description").unwrap(), None);
+        assert_eq!(refs.dwim("HEAD").unwrap().as_deref(), Some("HEAD"));
+        assert_eq!(
+            refs.dwim("ORIG_HEAD").unwrap().as_deref(),
+            Some("ORIG_HEAD")
+        );
+        assert_eq!(
+            refs.dwim("heads/main").unwrap().as_deref(),
+            Some("refs/heads/main")
+        );
+    }
+
+    #[test]
+    fn transaction_moves_nothing_if_one_update_fails() {
+        let dir = TempDir::new();
+        write(&dir, "refs/heads/a", &format!("{A}\n"));
+        write(&dir, "refs/remotes/o/b", &format!("{A}\n"));
+        let packed = format!("# pack-refs with: peeled fully-peeled sorted \n{A} refs/tags/t\n");
+        write(&dir, "packed-refs", &packed);
+        // The reflog of refs/remotes/o/b cannot be created
+        write(&dir, "logs/refs/remotes", "");
+        let refs = RefStore::new(dir.path());
+
+        let b = RefValue::Direct(B.parse().unwrap());
+        let mut transaction = refs.transaction();
+        transaction.update("refs/heads/a", b.clone(), Expected::Any);
+        transaction.update("refs/remotes/o/b", b, Expected::Any);
+        transaction.delete("refs/tags/t", Expected::Any);
+        assert!(transaction.commit("move").is_err());
+
+        let a = Some(RefValue::Direct(A.parse().unwrap()));
+        assert_eq!(refs.read("refs/heads/a").unwrap(), a);
+        assert_eq!(refs.read("refs/remotes/o/b").unwrap(), a);
+        assert_eq!(refs.read("refs/tags/t").unwrap(), a);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("packed-refs")).unwrap(),
+            packed
+        );
+        let log = fs::read(dir.path().join("logs/refs/heads/a")).unwrap_or_default();
+        assert!(log.is_empty());
+        assert!(!dir.path().join("refs/heads/a.lock").exists());
+        assert!(!dir.path().join("packed-refs.lock").exists());
+    }
+
+    #[test]
+    fn updates_fail_while_someone_else_holds_the_lock() {
+        let dir = TempDir::new();
+        write(&dir, "refs/heads/a", &format!("{A}\n"));
+        write(&dir, "refs/heads/a.lock", "");
+        let refs = RefStore::new(dir.path());
+
+        let b = RefValue::Direct(B.parse().unwrap());
+        let e = refs
+            .update("refs/heads/a", &b, Expected::Any, "move")
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        let e = refs.delete("refs/heads/a", Expected::Any).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+
+        assert_eq!(
+            refs.read("refs/heads/a").unwrap(),
+            Some(RefValue::Direct(A.parse().unwrap()))
+        );
+        // The lock is someone else's to remove
+        assert!(dir.path().join("refs/heads/a.lock").exists());
+        assert!(!dir.path().join("logs/refs/heads/a").exists());
+    }
+
+    #[test]
+    fn symbolic_refs_that_loop_or_nest_too_deeply_do_not_resolve() {
+        let dir = TempDir::new();
+        write(&dir, "HEAD", "ref: refs/heads/x\n");
+        write(&dir, "refs/heads/x", "ref: refs/heads/y\n");
+        write(&dir, "refs/heads/y", "ref: HEAD\n");
+        let refs = RefStore::new(dir.path());
+        let e = refs.resolve("HEAD").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "symbolic ref cycle: HEAD -> refs/heads/x -> refs/heads/y -> HEAD"
+        );
+        write(&dir, "refs/heads/self", "ref: refs/heads/self\n");
+        assert!(refs.resolve("refs/heads/self").is_err());
+
+        // Five levels of symbolic refs are followed, six are not
+        write(&dir, "refs/heads/r0", &format!("{A}\n"));
+        for n in 1..=6 {
+            write(
+                &dir,
+                &format!("refs/heads/r{n}"),
+                &format!("ref: refs/heads/r{}\n", n - 1),
+            );
+        }
+        assert_eq!(
+            refs.resolve("refs/heads/r5").unwrap(),
+            Some(A.parse().unwrap())
+        );
+        let e = refs.resolve("refs/heads/r6").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "symbolic ref refs/heads/r6 is nested too deeply"
+        );
+    }
+
+    #[test]
+    fn loose_refs_shadow_packed_ones() {
+        let dir = TempDir::new();
+        let packed = format!(
+            "# pack-refs with: peeled fully-peeled sorted \n\
+             {A} refs/heads/a\n{A} refs/heads/b\n{A} refs/tags/t\n^{B}\n"
+        );
+        write(&dir, "packed-refs", &packed);
+        write(&dir, "refs/heads/a", &format!("{B}\n"));
+        let refs = RefStore::new(dir.path());
+
+        let a = RefValue::Direct(A.parse().unwrap());
+        let b = RefValue::Direct(B.parse().unwrap());
+        assert_eq!(refs.read("refs/heads/a").unwrap(), Some(b.clone()));
+        assert_eq!(refs.read("refs/heads/b").unwrap(), Some(a.clone()));
+        assert_eq!(
+            refs.list("refs/").unwrap(),
+            [
+                ("refs/heads/a".to_string(), b),
+                ("refs/heads/b".to_string(), a.clone()),
+                ("refs/tags/t".to_string(), a),
+            ]
+        );
+
+        // Deleting a ref removes it from both places, along with the peeled
+        // line of a packed tag
+        refs.delete("refs/heads/a", Expected::Any).unwrap();
+        refs.delete("refs/tags/t", Expected::Any).unwrap();
+        assert_eq!(refs.read("refs/heads/a").unwrap(), None);
+        assert_eq!(refs.read("refs/tags/t").unwrap(), None);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("packed-refs")).unwrap(),
+            format!("# pack-refs with: peeled fully-peeled sorted \n{A} refs/heads/b\n")
+        );
+    }
+
+    #[test]
+    fn deleting_a_ref_prunes_the_directories_it_leaves_empty() {
+        let dir = TempDir::new();
+        let refs = RefStore::new(dir.path());
+        let a = RefValue::Direct(A.parse().unwrap());
+        for name in [
+            "refs/heads/topic/deep/x",
+            "refs/heads/topic/y",
+            "refs/tags/v1",
+        ] {
+            refs.update(name, &a, Expected::Missing, "create").unwrap();
+        }
+        assert!(dir.path().join("logs/refs/heads/topic/deep/x").is_file());
+
+        refs.delete("refs/heads/topic/deep/x", Expected::Any)
+            .unwrap();
+        assert!(!dir.path().join("refs/heads/topic/deep").exists());
+        assert!(!dir.path().join("logs/refs/heads/topic/deep").exists());
+        assert!(dir.path().join("refs/heads/topic/y").is_file());
+
+        refs.delete("refs/heads/topic/y", Expected::Any).unwrap();
+        refs.delete("refs/tags/v1", Expected::Any).unwrap();
+        assert!(!dir.path().join("refs/heads/topic").exists());
+        assert!(!dir.path().join("logs/refs/heads/topic").exists());
+        for kept in ["refs/heads", "refs/tags", "logs/refs/heads"] {
+            assert!(dir.path().join(kept).is_dir(), "{kept} was pruned");
+        }
+    }
+
+    #[test]
+    fn prepared_transactions_hold_their_locks_until_closed() {
+        let dir = TempDir::new();
+        write(&dir, "refs/heads/a", &format!("{A}\n"));
+        let refs = RefStore::new(dir.path());
+        let a = Some(RefValue::Direct(A.parse().unwrap()));
+        let b = RefValue::Direct(B.parse().unwrap());
+
+        let mut transaction = refs.transaction();
+        transaction.update("refs/heads/a", b.clone(), Expected::Any);
+        let prepared = transaction.prepare("move").unwrap();
+        assert!(dir.path().join("refs/heads/a.lock").exists());
+        assert_eq!(refs.read("refs/heads/a").unwrap(), a);
+        drop(prepared);
+        assert!(!dir.path().join("refs/heads/a.lock").exists());
+        assert_eq!(refs.read("refs/heads/a").unwrap(), a);
+
+        let mut transaction = refs.transaction();
+        transaction.update("refs/heads/a", b.clone(), Expected::Any);
+        let prepared = transaction.prepare("move").unwrap();
+        let e = refs
+            .update("refs/heads/a", &b, Expected::Any, "race")
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        prepared.commit().unwrap();
+        assert_eq!(refs.read("refs/heads/a").unwrap(), Some(b));
+        assert!(!dir.path().join("refs/heads/a.lock").exists());
+        let log = refs.reflog("refs/heads/a").unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].message, "move");
+    }
+}
@@ -0,0 +1,86 @@
+use std::fmt;
+use std::io;
+
+use crate::ident::Signature;
+use crate::object::invalid;
+use crate::object::ObjectId;
+
+/// One line of `logs/<ref>`: the ref moved from `old` to `new`, by whom and
+/// why. A missing ref on either side is written as the all-zero id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflogEntry {
+    pub old: ObjectId,
+    pub new: ObjectId,
+    pub who: Signature,
+    pub message: String,
+}
+
+impl ReflogEntry {
+    pub fn parse(line: &str) -> io::Result<Self> {
+        let bad = || invalid(format!("bad reflog line: {line}"));
+        let (old, rest) = line.split_once(' ').ok_or_else(bad)?;
+        let (new, rest) = rest.split_once(' ').ok_or_else(bad)?;
+        let (who, message) = rest.split_once('\t').unwrap_or((rest, ""));
+        Ok(ReflogEntry {
+            old: old.parse().map_err(|_| bad())?,
+            new: new.parse().map_err(|_| bad())?,
+            who: Signature::parse(who)?,
+            message: message.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for ReflogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.old, self.new, self.who)?;
+        if !self.message.is_empty() {
+            write!(f, "\t{}", self.message)?;
+        }
+        writeln!(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_parse_and_print_as_git_writes_them() {
+        let line = "0000000000000000000000000000000000000000 \
+                    9c8aaa55820822e67b4743c838458f7693a8f541 \
+                    A U Thor <a@b> 1792270930 -0130\tcommit (initial): m";
+        let entry = ReflogEntry::parse(line).unwrap();
+        assert_eq!(entry.old, ObjectId::default());
+        assert_eq!(
+            entry.new.to_string(),
+            "9c8aaa55820822e67b4743c838458f7693a8f541"
+        );
+        assert_eq!(entry.who.name, "A U Thor");
+        assert_eq!(entry.who.time, 1792270930);
+        assert_eq!(entry.who.offset, -90);
+        assert_eq!(entry.message, "commit (initial): m");
+        assert_eq!(entry.to_string(), format!("{line}\n"));
+
+        // Without a message there is no tab either
+        let line = &line[..line.find('\t').unwrap()];
+        let entry = ReflogEntry::parse(line).unwrap();
+        assert_eq!(entry.message, "");
+        assert_eq!(entry.to_string(), format!("{line}\n"));
+    }
+
+    #[test]
+    fn malformed_entries_are_errors() {
+        let id = "9c8aaa55820822e67b4743c838458f7693a8f541";
+        for line in [
+            "".to_string(),
+            id.to_string(),
+            format!("{id} {id}"),
+            format!("xyz {id} a <a@b> 0 +0000\tm"),
+            format!("{id} {id} a <a@b> 0\tm"),
+            format!("{id} {id} a a@b 0 +0000\tm"),
+        ] {
+            let e = ReflogEntry::parse(&line).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{line}");
+        }
+    }
+}
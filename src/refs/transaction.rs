@@ -0,0 +1,203 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+
+use crate::lock::Lock;
+
+use super::check_name;
+use super::Expected;
+use super::RefStore;
+use super::RefValue;
+use super::ReflogEntry;
+
+enum Change {
+    Update(RefValue),
+    Delete,
+    // Only checks the ref, holding its lock until the rest is done
+    Verify,
+}
+
+struct RefUpdate {
+    name: String,
+    change: Change,
+    expected: Expected,
+}
+
+/// Changes to refs that either all happen or, if any ref is locked by
+/// someone else or not holding what was expected, none do. Refs are named as
+/// themselves; callers follow symbolic refs first if that is what they want.
+pub struct Transaction<'a> {
+    refs: &'a RefStore,
+    updates: Vec<RefUpdate>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(super) fn new(refs: &'a RefStore) -> Self {
+        Transaction {
+            refs,
+            updates: vec![],
+        }
+    }
+
+    pub fn update(&mut self, name: &str, new: RefValue, expected: Expected) {
+        self.push(name, Change::Update(new), expected);
+    }
+
+    pub fn delete(&mut self, name: &str, expected: Expected) {
+        self.push(name, Change::Delete, expected);
+    }
+
+    pub fn verify(&mut self, name: &str, expected: Expected) {
+        self.push(name, Change::Verify, expected);
+    }
+
+    fn push(&mut self, name: &str, change: Change, expected: Expected) {
+        self.updates.push(RefUpdate {
+            name: name.to_string(),
+            change,
+            expected,
+        });
+    }
+
+    /// Prepares the transaction and makes the changes, recording `message`
+    /// in the reflogs of refs that move.
+    pub fn commit(self, message: &str) -> io::Result<()> {
+        self.prepare(message)?.commit()
+    }
+
+    /// Locks every ref, checks each holds what was expected and writes the
+    /// new values out, leaving only the changes themselves for `commit`.
+    /// Dropping the result instead aborts the transaction.
+    pub fn prepare(self, message: &str) -> io::Result<Prepared<'a>> {
+        let refs = self.refs;
+        let mut updates = self.updates;
+        // Name order means two transactions never each hold a lock the other
+        // is about to ask for
+        updates.sort_by(|a, b| a.name.cmp(&b.name));
+        if let Some(pair) = updates.windows(2).find(|pair| pair[0].name == pair[1].name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("multiple updates for ref '{}' not allowed", pair[0].name),
+            ));
+        }
+
+        let mut locks = vec![];
+        for update in &updates {
+            if let Change::Update(RefValue::Symbolic(target)) = &update.change {
+                check_name(target)?;
+            }
+            locks.push(refs.lock(&update.name)?);
+        }
+        for update in &updates {
+            refs.check_expected(&update.name, update.expected)?;
+        }
+
+        // Everything that can fail, short of the disk giving out, happens
+        // before the first ref moves
+        let head = refs.read("HEAD")?;
+        let mut logs = vec![];
+        for (update, lock) in updates.iter().zip(&mut locks) {
+            let name = &update.name;
+            let Change::Update(new) = &update.change else {
+                continue;
+            };
+            let old_id = refs.resolve(name)?;
+            let new_id = match new {
+                RefValue::Direct(id) => Some(*id),
+                RefValue::Symbolic(target) => refs.resolve(target)?,
+            };
+            // Like git, repointing a symbolic ref is only logged when there
+            // is a reason to give
+            if matches!(new, RefValue::Direct(_)) || !message.is_empty() {
+                logs.extend(refs.open_log(name, old_id, new_id, message)?);
+            }
+            // A branch moving moves HEAD too when HEAD is on it
+            let on_branch = head == Some(RefValue::Symbolic(name.clone()));
+            if on_branch && matches!(new, RefValue::Direct(_)) {
+                logs.extend(refs.open_log("HEAD", old_id, new_id, message)?);
+            }
+            lock.write(new.to_file().as_bytes())?;
+        }
+        let packed = refs.packed()?;
+        let unpacked: Vec<&str> = updates
+            .iter()
+            .filter(|update| matches!(update.change, Change::Delete))
+            .map(|update| update.name.as_str())
+            .filter(|name| packed.iter().any(|(packed, _)| packed == name))
+            .collect();
+        let packed_lock = if unpacked.is_empty() {
+            None
+        } else {
+            Some(refs.lock_packed_without(&unpacked)?)
+        };
+        Ok(Prepared {
+            refs,
+            updates,
+            locks,
+            packed_lock,
+            logs,
+        })
+    }
+}
+
+/// A transaction holding the locks on its refs, with nothing left that can
+/// fail short of the disk giving out.
+pub struct Prepared<'a> {
+    refs: &'a RefStore,
+    updates: Vec<RefUpdate>,
+    // One for each update, in the same order
+    locks: Vec<Lock>,
+    packed_lock: Option<Lock>,
+    logs: Vec<(fs::File, ReflogEntry)>,
+}
+
+impl Prepared<'_> {
+    /// Moves every ref, then tidies up after deleted ones and appends the
+    /// reflog entries.
+    pub fn commit(self) -> io::Result<()> {
+        let Prepared {
+            refs,
+            updates,
+            locks,
+            packed_lock,
+            logs,
+        } = self;
+
+        // Once one ref has moved the rest go ahead whatever happens, so a
+        // failure leaves as few of them behind as it can
+        let mut result = Ok(());
+        for (update, lock) in updates.iter().zip(locks) {
+            let done = match update.change {
+                Change::Update(_) => lock.finish(),
+                Change::Delete => lock.delete(),
+                Change::Verify => {
+                    drop(lock);
+                    Ok(())
+                }
+            };
+            result = result.and(done);
+        }
+        result?;
+
+        // The ones that moved keep their new values even if tidying up after
+        // them fails
+        let mut result = match packed_lock {
+            Some(lock) => lock.finish(),
+            None => Ok(()),
+        };
+        for update in &updates {
+            if let Change::Delete = update.change {
+                let removed = match fs::remove_file(refs.log_path(&update.name)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                    _ => Ok(()),
+                };
+                result = result.and(removed);
+                refs.prune_empty_dirs(&update.name);
+            }
+        }
+        for (mut log, entry) in logs {
+            result = result.and(log.write_all(entry.to_string().as_bytes()));
+        }
+        result
+    }
+}
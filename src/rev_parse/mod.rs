@@ -0,0 +1,581 @@
+use std::io;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::ident;
+use crate::index::Index;
+use crate::object::Object;
+use crate::object::ObjectId;
+use crate::object::ObjectType;
+use crate::odb::ObjectDatabase;
+use crate::refs::RefStore;
+use crate::refs::RefValue;
+
+// Shortest abbreviation git accepts for an object id
+const MIN_ABBREV: usize = 4;
+
+/// Resolves a revision such as `HEAD~3`, `main^2`, `v1.0^{tree}`, `a1b2c3d`,
+/// `main@{upstream}`, `HEAD@{2}`, `main@{yesterday}`, `HEAD:src/main.rs` or
+/// `:src/main.rs` to an object id.
+pub fn rev_parse(git_dir: &Path, odb: &dyn ObjectDatabase, spec: &str) -> io::Result<ObjectId> {
+    let refs = RefStore::new(git_dir);
+    if let Some(path) = spec.strip_prefix(':') {
+        return lookup_index(git_dir, path).map_err(|e| unknown(spec, &e.to_string()));
+    }
+    if let Some((rev, path)) = split_path(spec) {
+        let tree = peel(odb, rev_parse(git_dir, odb, rev)?, ObjectType::Tree)?;
+        return lookup_path(odb, tree, path).map_err(|e| unknown(spec, &e.to_string()));
+    }
+
+    // Ref names can't contain any of these, so the name ends at the first one
+    let base_end = spec.find(['~', '^']).unwrap_or(spec.len());
+    let base_end = spec[..base_end].find("@{").unwrap_or(base_end);
+    let (base, mut rest) = spec.split_at(base_end);
+
+    let mut id = if let Some(suffix) = rest.strip_prefix("@{") {
+        let (reflog, after) = suffix
+            .split_once('}')
+            .ok_or_else(|| unknown(spec, "unterminated @{"))?;
+        rest = after;
+        match reflog.to_ascii_lowercase().as_str() {
+            "upstream" | "u" => {
+                let upstream = upstream(git_dir, &refs, base)?;
+                refs.resolve(&upstream)?
+                    .ok_or_else(|| unknown(spec, &format!("{upstream} does not exist")))?
+            }
+            selector => {
+                at_reflog(&refs, base, selector).map_err(|e| unknown(spec, &e.to_string()))?
+            }
+        }
+    } else {
+        resolve_base(&refs, odb, base)?.ok_or_else(|| unknown(spec, "no such ref or object"))?
+    };
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("^{") {
+            let (kind, after) = after
+                .split_once('}')
+                .ok_or_else(|| unknown(spec, "unterminated ^{"))?;
+            id = match kind {
+                "" => peel_tags(odb, id)?,
+                "object" => id,
+                kind => peel(odb, id, kind.parse()?)?,
+            };
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('^') {
+            let (n, after) = split_number(after);
+            id = match n.unwrap_or(1) {
+                0 => peel(odb, id, ObjectType::Commit)?,
+                n => *parents(odb, id)?
+                    .get(n - 1)
+                    .ok_or_else(|| unknown(spec, &format!("commit has no parent {n}")))?,
+            };
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('~') {
+            let (n, after) = split_number(after);
+            for _ in 0..n.unwrap_or(1) {
+                id = *parents(odb, id)?
+                    .first()
+                    .ok_or_else(|| unknown(spec, "ran out of first parents"))?;
+            }
+            rest = after;
+        } else {
+            return Err(unknown(spec, &format!("can't make sense of {rest}")));
+        }
+    }
+    Ok(id)
+}
+
+/// Follows tags, and commits on the way to a tree, until reaching `kind`.
+pub fn peel(odb: &dyn ObjectDatabase, id: ObjectId, kind: ObjectType) -> io::Result<ObjectId> {
+    let mut current = id;
+    loop {
+        let object = odb.read_object(&current)?;
+        if object.kind() == kind {
+            return Ok(current);
+        }
+        current = match object {
+            Object::Tag(tag) => tag.object,
+            Object::Commit(commit) if kind == ObjectType::Tree => commit.tree,
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{id} leads to a {}, not a {kind}", other.kind()),
+                ))
+            }
+        };
+    }
+}
+
+/// Every object id in a database, sorted, for looking ids up by prefix and
+/// finding the shortest prefixes that tell them apart. Listing a database
+/// walks every loose object and pack index, so callers build this once and
+/// reuse it.
+pub struct ObjectIds {
+    ids: Vec<ObjectId>,
+}
+
+impl ObjectIds {
+    pub fn new(odb: &dyn ObjectDatabase) -> io::Result<Self> {
+        let mut ids: Vec<ObjectId> = odb.iter()?.collect();
+        ids.sort();
+        ids.dedup();
+        Ok(ObjectIds { ids })
+    }
+
+    /// The shortest prefix of `id`, at least `min_len` digits long, that no
+    /// other object shares.
+    pub fn abbreviate(&self, id: ObjectId, min_len: usize) -> String {
+        let hex = id.to_hex();
+        let at = self.ids.partition_point(|other| *other < id);
+        let after = match self.ids.get(at) {
+            Some(other) if *other == id => at + 1,
+            _ => at,
+        };
+        // Only the ids either side of it in sorted order can share the
+        // longest prefix with it
+        let longest_common = [at.checked_sub(1), Some(after)]
+            .into_iter()
+            .flatten()
+            .filter_map(|n| self.ids.get(n))
+            .map(|other| {
+                hex.bytes()
+                    .zip(other.to_hex().bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            })
+            .max()
+            .unwrap_or(0);
+        let len = min_len.max(longest_common + 1);
+        id.abbrev(len.clamp(MIN_ABBREV, hex.len()))
+    }
+
+    // The ids starting with the lowercase hex digits in `prefix`
+    fn with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = ObjectId> + 'a {
+        // The smallest id with the prefix is the prefix followed by zeros
+        let lowest: ObjectId = format!("{prefix:0<40}").parse().unwrap_or_default();
+        let start = self.ids.partition_point(|id| *id < lowest);
+        self.ids[start..]
+            .iter()
+            .copied()
+            .take_while(move |id| id.to_hex().starts_with(prefix))
+    }
+}
+
+fn peel_tags(odb: &dyn ObjectDatabase, id: ObjectId) -> io::Result<ObjectId> {
+    let mut id = id;
+    while let Object::Tag(tag) = odb.read_object(&id)? {
+        id = tag.object;
+    }
+    Ok(id)
+}
+
+fn parents(odb: &dyn ObjectDatabase, id: ObjectId) -> io::Result<Vec<ObjectId>> {
+    match odb.read_object(&peel(odb, id, ObjectType::Commit)?)? {
+        Object::Commit(commit) => Ok(commit.parents),
+        _ => unreachable!(),
+    }
+}
+
+// A full id, a ref, or else an abbreviated id
+fn resolve_base(
+    refs: &RefStore,
+    odb: &dyn ObjectDatabase,
+    base: &str,
+) -> io::Result<Option<ObjectId>> {
+    if let Ok(id) = base.parse() {
+        return Ok(Some(id));
+    }
+    let base = if base.is_empty() || base == "@" {
+        "HEAD"
+    } else {
+        base
+    };
+    if let Some(full) = refs.dwim(base)? {
+        return refs.resolve(&full);
+    }
+    if base.len() < MIN_ABBREV || !base.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Ok(None);
+    }
+    let prefix = base.to_ascii_lowercase();
+    let ids = ObjectIds::new(odb)?;
+    let mut matches = ids.with_prefix(&prefix);
+    let Some(id) = matches.next() else {
+        return Ok(None);
+    };
+    if matches.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("short object id {base} is ambiguous"),
+        ));
+    }
+    Ok(Some(id))
+}
+
+// Where `base` was `selector` ago: the Nth previous entry of its reflog, or
+// the newest entry no later than a date. No base means the current branch.
+fn at_reflog(refs: &RefStore, base: &str, selector: &str) -> io::Result<ObjectId> {
+    let name = match base {
+        "" => match refs.read("HEAD")? {
+            Some(RefValue::Symbolic(branch)) => branch,
+            _ => "HEAD".to_string(),
+        },
+        base => refs
+            .dwim(base)?
+            .ok_or_else(|| unknown(base, "no such ref"))?,
+    };
+    let shown = name.strip_prefix("refs/heads/").unwrap_or(&name);
+    let entries = refs.reflog(&name)?;
+    let Some(oldest) = entries.first() else {
+        return Err(unknown(shown, "log is empty"));
+    };
+
+    if let Ok(n) = selector.parse::<usize>() {
+        return entries
+            .iter()
+            .rev()
+            .nth(n)
+            .map(|entry| entry.new)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("log for '{shown}' only has {} entries", entries.len()),
+                )
+            });
+    }
+    let time = ident::approxidate(selector, ident::now()).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("bad reflog selector {selector}"),
+        )
+    })?;
+    if let Some(entry) = entries.iter().rev().find(|entry| entry.who.time <= time) {
+        return Ok(entry.new);
+    }
+    eprintln!(
+        "warning: log for '{shown}' only goes back to {}",
+        oldest.who.time
+    );
+    // Before the ref was created, the best answer is its first value
+    Ok(if oldest.old == ObjectId::default() {
+        oldest.new
+    } else {
+        oldest.old
+    })
+}
+
+// The remote-tracking ref a branch merges from, per its branch.<name>.*
+// settings. An empty branch means the one HEAD is on.
+fn upstream(git_dir: &Path, refs: &RefStore, branch: &str) -> io::Result<String> {
+    let branch = match branch {
+        "" | "@" | "HEAD" => match refs.read("HEAD")? {
+            Some(RefValue::Symbolic(target)) => target,
+            _ => return Err(unknown(branch, "HEAD is not on a branch")),
+        },
+        branch => refs
+            .dwim(branch)?
+            .filter(|full| full.starts_with("refs/heads/"))
+            .ok_or_else(|| unknown(branch, "not a branch"))?,
+    };
+    let short = branch.strip_prefix("refs/heads/").unwrap_or(&branch);
+
+    let config = Config::open(git_dir)?;
+    let no_upstream = || unknown(short, "no upstream configured");
+    let remote = config
+        .get(&format!("branch.{short}.remote"))
+        .ok_or_else(no_upstream)?;
+    let merge = config
+        .get(&format!("branch.{short}.merge"))
+        .ok_or_else(no_upstream)?;
+    if remote == "." {
+        return Ok(merge.to_string());
+    }
+    let merged = merge.strip_prefix("refs/heads/").unwrap_or(merge);
+    Ok(format!("refs/remotes/{remote}/{merged}"))
+}
+
+fn lookup_path(odb: &dyn ObjectDatabase, tree: ObjectId, path: &str) -> io::Result<ObjectId> {
+    let mut id = tree;
+    for part in path.split('/').filter(|part| !part.is_empty()) {
+        let tree = match odb.read_object(&id)? {
+            Object::Tree(tree) => tree,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{path} does not exist"),
+                ))
+            }
+        };
+        id = tree
+            .entries
+            .iter()
+            .find(|entry| entry.name == part.as_bytes())
+            .map(|entry| entry.oid)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("{path} does not exist"))
+            })?;
+    }
+    Ok(id)
+}
+
+// `<rev>:<path>`, where a colon inside braces, as in `main@{12:00}`, belongs
+// to the revision
+fn split_path(spec: &str) -> Option<(&str, &str)> {
+    let mut depth = 0;
+    for (at, c) in spec.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth > 0 => depth -= 1,
+            ':' if depth == 0 => return Some((&spec[..at], &spec[at + 1..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+// The digits at the start of `s`, if any, and what follows them
+fn split_number(s: &str) -> (Option<usize>, &str) {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    (s[..end].parse().ok(), &s[end..])
+}
+
+// `path` or `N:path` as staged in the index, at stage N or 0
+fn lookup_index(git_dir: &Path, path: &str) -> io::Result<ObjectId> {
+    let (stage, path) = match path.split_once(':') {
+        Some((stage @ ("0" | "1" | "2" | "3"), path)) => (stage.parse().unwrap(), path),
+        _ => (0, path),
+    };
+    let index = Index::open(git_dir)?;
+    if let Some(entry) = index.entry(path.as_bytes(), stage) {
+        return Ok(entry.oid);
+    }
+    let staged = index
+        .entries()
+        .iter()
+        .any(|entry| entry.path == path.as_bytes());
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        if staged {
+            format!("path '{path}' is in the index, but not at stage {stage}")
+        } else {
+            format!("path '{path}' does not exist in the index")
+        },
+    ))
+}
+
+fn unknown(spec: &str, why: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("unknown revision {spec}: {why}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::index::IndexEntry;
+    use crate::index::Stat;
+    use crate::object::RawObject;
+    use crate::odb::LooseObjectDb;
+    use crate::refs::Expected;
+    use crate::test_util::TempDir;
+
+    // Two blobs whose ids both start with 59b7
+    const CLASHING: [&str; 2] = ["blob 96\n", "blob 262\n"];
+
+    struct Repo {
+        dir: TempDir,
+        odb: LooseObjectDb,
+        blob: ObjectId,
+        inner: ObjectId,
+        tree: ObjectId,
+        // main is merge, whose parents are third and side; third's line goes
+        // back through second to root, and side's parent is root
+        commits: [ObjectId; 5],
+        tag: ObjectId,
+    }
+
+    impl Repo {
+        fn new() -> Repo {
+            let dir = TempDir::new();
+            let odb = LooseObjectDb::new(dir.path());
+            let write =
+                |kind, data: &[u8]| odb.write(&RawObject::new(kind, data.to_vec())).unwrap();
+
+            let blob = write(ObjectType::Blob, b"hello\n");
+            let inner = write(ObjectType::Blob, b"inner\n");
+            let mut subtree = b"100644 inner\0".to_vec();
+            subtree.extend_from_slice(inner.as_bytes());
+            let subtree = write(ObjectType::Tree, &subtree);
+            let mut tree = b"40000 dir\0".to_vec();
+            tree.extend_from_slice(subtree.as_bytes());
+            tree.extend_from_slice(b"100644 file\0");
+            tree.extend_from_slice(blob.as_bytes());
+            let tree = write(ObjectType::Tree, &tree);
+
+            let commit = |parents: &[ObjectId], message: &str| {
+                let mut text = format!("tree {tree}\n");
+                for parent in parents {
+                    text.push_str(&format!("parent {parent}\n"));
+                }
+                text.push_str("author a <a@b> 0 +0000\ncommitter a <a@b> 0 +0000\n\n");
+                text.push_str(message);
+                write(ObjectType::Commit, text.as_bytes())
+            };
+            let root = commit(&[], "root\n");
+            let second = commit(&[root], "second\n");
+            let third = commit(&[second], "third\n");
+            let side = commit(&[root], "side\n");
+            let merge = commit(&[third, side], "merge\n");
+            let tag =
+                format!("object {merge}\ntype commit\ntag v1\ntagger a <a@b> 0 +0000\n\nv1\n");
+            let tag = write(ObjectType::Tag, tag.as_bytes());
+            for content in CLASHING {
+                write(ObjectType::Blob, content.as_bytes());
+            }
+
+            fs::write(dir.path().join("HEAD"), "ref: refs/heads/main\n").unwrap();
+            let refs = RefStore::new(dir.path());
+            for id in [root, second, third, merge] {
+                refs.update(
+                    "refs/heads/main",
+                    &RefValue::Direct(id),
+                    Expected::Any,
+                    "move",
+                )
+                .unwrap();
+            }
+            refs.update(
+                "refs/tags/v1",
+                &RefValue::Direct(tag),
+                Expected::Missing,
+                "",
+            )
+            .unwrap();
+
+            let mut index = Index::new(2);
+            index.add(IndexEntry::new(
+                b"file".to_vec(),
+                0o100644,
+                blob,
+                Stat::default(),
+            ));
+            fs::write(dir.path().join("index"), index.serialize()).unwrap();
+
+            Repo {
+                dir,
+                odb,
+                blob,
+                inner,
+                tree,
+                commits: [root, second, third, side, merge],
+                tag,
+            }
+        }
+
+        fn parse(&self, spec: &str) -> io::Result<ObjectId> {
+            rev_parse(self.dir.path(), &self.odb, spec)
+        }
+
+        fn id(&self, spec: &str) -> ObjectId {
+            self.parse(spec).unwrap()
+        }
+    }
+
+    #[test]
+    fn follows_parents() {
+        let repo = Repo::new();
+        let [root, second, third, side, merge] = repo.commits;
+        assert_eq!(repo.id("main"), merge);
+        assert_eq!(repo.id("main~"), third);
+        assert_eq!(repo.id("main~2"), second);
+        assert_eq!(repo.id("HEAD~3"), root);
+        assert_eq!(repo.id("main^"), third);
+        assert_eq!(repo.id("main^2"), side);
+        assert_eq!(repo.id("main^2~"), root);
+        assert_eq!(repo.id("@^^"), second);
+        assert_eq!(repo.id("main^0"), merge);
+        assert!(repo.parse("main^3").is_err());
+        assert!(repo.parse("main~4").is_err());
+    }
+
+    #[test]
+    fn peels_to_a_type() {
+        let repo = Repo::new();
+        let merge = repo.commits[4];
+        assert_eq!(repo.id("v1"), repo.tag);
+        assert_eq!(repo.id("v1^{}"), merge);
+        assert_eq!(repo.id("v1^{commit}"), merge);
+        assert_eq!(repo.id("v1^{tag}"), repo.tag);
+        assert_eq!(repo.id("v1^{object}"), repo.tag);
+        assert_eq!(repo.id("v1^{tree}"), repo.tree);
+        assert_eq!(repo.id("v1^0"), merge);
+        assert_eq!(repo.id("v1~"), repo.commits[2]);
+        assert_eq!(repo.id("main^{}"), merge);
+        assert!(repo.parse("main^{blob}").is_err());
+        assert!(repo.parse("main^{tree").is_err());
+    }
+
+    #[test]
+    fn reads_reflogs() {
+        let repo = Repo::new();
+        let [_, second, third, _, merge] = repo.commits;
+        assert_eq!(repo.id("main@{0}"), merge);
+        assert_eq!(repo.id("main@{1}"), third);
+        assert_eq!(repo.id("@{2}"), second);
+        assert_eq!(repo.id("HEAD@{1}"), third);
+        assert_eq!(repo.id("main@{1}^"), second);
+        let e = repo.parse("main@{4}").unwrap_err();
+        assert!(e.to_string().contains("only has 4 entries"), "{e}");
+    }
+
+    #[test]
+    fn looks_up_paths() {
+        let repo = Repo::new();
+        assert_eq!(repo.id(":file"), repo.blob);
+        assert_eq!(repo.id(":0:file"), repo.blob);
+        assert_eq!(repo.id("main:file"), repo.blob);
+        assert_eq!(repo.id("main:dir/inner"), repo.inner);
+        assert_eq!(repo.id("v1:"), repo.tree);
+        assert_eq!(repo.id("main@{1}:file"), repo.blob);
+        assert!(repo.parse(":missing").is_err());
+        assert!(repo.parse(":2:file").is_err());
+        assert!(repo.parse("main:dir/missing").is_err());
+        assert!(repo.parse("main:file/below").is_err());
+    }
+
+    #[test]
+    fn resolves_short_ids() {
+        let repo = Repo::new();
+        let root = repo.commits[0];
+        assert_eq!(repo.id(&root.abbrev(7)), root);
+        assert_eq!(repo.id(&root.abbrev(7).to_uppercase()), root);
+        assert_eq!(repo.id(&format!("{}~0", root.abbrev(10))), root);
+        assert!(repo.parse(&root.to_hex()[..3]).is_err());
+
+        let e = repo.parse("59b7").unwrap_err();
+        assert!(e.to_string().contains("ambiguous"), "{e}");
+        let clashing = CLASHING
+            .map(|content| RawObject::new(ObjectType::Blob, content.as_bytes().to_vec()).hash());
+        assert_eq!(repo.id("59b76"), clashing[0]);
+        assert_eq!(repo.id("59b74"), clashing[1]);
+        assert!(repo.parse("59b75").is_err());
+    }
+
+    #[test]
+    fn abbreviates_past_shared_prefixes() {
+        let repo = Repo::new();
+        let ids = ObjectIds::new(&repo.odb).unwrap();
+        let clashing = CLASHING
+            .map(|content| RawObject::new(ObjectType::Blob, content.as_bytes().to_vec()).hash());
+        assert_eq!(ids.abbreviate(clashing[0], 4), "59b76");
+        assert_eq!(ids.abbreviate(clashing[1], 4), "59b74");
+        assert_eq!(ids.abbreviate(clashing[0], 7), clashing[0].abbrev(7));
+        assert_eq!(ids.abbreviate(repo.blob, 0), repo.blob.abbrev(4));
+        // Ids not in the database are told apart from the ones that are
+        let missing = "59b7600000000000000000000000000000000000".parse().unwrap();
+        assert_eq!(ids.abbreviate(missing, 4), "59b760");
+    }
+}